@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use ground_covered::{config::Config, App};
+
+// Usage: import_archive <export.zip> <athlete_id> [--setting value ...]
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+
+    let (Some(archive_path), Some(athlete_id)) = (args.next(), args.next()) else {
+        eprintln!("Usage: import_archive <export.zip> <athlete_id> [--setting value ...]");
+        std::process::exit(1);
+    };
+
+    let Ok(athlete_id) = athlete_id.parse::<i64>() else {
+        eprintln!("Invalid athlete id '{}'", athlete_id);
+        std::process::exit(1);
+    };
+
+    match Config::load(args) {
+        Ok(config) => Config::init_global(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Imported activities are matched with the ones synced from Strava so the athlete has to be registered
+    let Some(app) = App::with_athlete(athlete_id).await else {
+        eprintln!(
+            "Athlete {} is not registered, connect it through /oauth/authorize first",
+            athlete_id
+        );
+        std::process::exit(1);
+    };
+
+    match app.import_strava_archive(Path::new(&archive_path)).await {
+        Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        Err(err) => {
+            eprintln!("Import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
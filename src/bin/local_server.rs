@@ -1,13 +1,24 @@
+// Rocket's FromForm derive allows private_in_public, which rustc no longer knows
+#![allow(renamed_and_removed_lints)]
+
+use ground_covered::config::Config;
 use ground_covered::data_types::common::DocumentId;
-use ground_covered::App;
+use ground_covered::data_types::strava::athlete::AthleteId;
+use ground_covered::data_types::strava::webhook::{AspectType, ObjectType, WebhookEvent};
+use ground_covered::export::kml::KmlWriter;
+use ground_covered::import::ImportResult;
+use ground_covered::{logln, App, QueryError};
 use mongodb::bson::{self};
-use rocket::http::{ContentType, Status};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
+use rocket::response::Redirect;
 
 #[macro_use]
 extern crate rocket;
 
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
+use rocket::request::{self, FromRequest};
 use rocket::{Request, Response};
 
 pub struct Cors;
@@ -21,11 +32,22 @@ impl Fairing for Cors {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new(
-            "Access-Control-Allow-Origin",
-            "http://localhost:5173",
-        ));
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // Only one origin can be returned, echo the request's one if it is allowed
+        let allowed_origins = &Config::global().server.allowed_origins;
+        let origin = request
+            .headers()
+            .get_one("Origin")
+            .filter(|origin| allowed_origins.iter().any(|allowed| allowed == origin))
+            .or(allowed_origins.first().map(|origin| origin.as_str()));
+
+        if let Some(origin) = origin {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Origin",
+                origin.to_string(),
+            ));
+            response.set_header(Header::new("Vary", "Origin"));
+        }
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
             "POST, PATCH, PUT, DELETE, HEAD, OPTIONS, GET",
@@ -36,28 +58,262 @@ impl Fairing for Cors {
 }
 
 #[options("/<_..>")]
-fn all_options() {
-    /* Intentionally left empty */
+fn all_options() -> Status {
+    // Only the CORS headers matter
+    Status::Ok
 }
 
 #[get("/activities/<act_id>")]
-async fn activities(act_id: &str) -> (Status, (ContentType, String)) {
+async fn activities(act_id: &str) -> (Status, (ContentType, Vec<u8>)) {
+    // Exports are requested as files, e.g. /activities/123.tcx
+    if let Some((act_id, extension)) = act_id.split_once('.') {
+        let Ok(act_id) = act_id.parse::<i64>() else {
+            return (Status::NotFound, (ContentType::Text, Vec::new()));
+        };
+
+        let app = App::anonym_athlete().await;
+        let export = match extension {
+            "tcx" => app
+                .export_activity_tcx(act_id)
+                .await
+                .map(|tcx| (ContentType::new("application", "vnd.garmin.tcx+xml"), tcx)),
+            "gpx" => app
+                .export_activity_gpx(act_id)
+                .await
+                .map(|gpx| (gpx_content_type(), gpx)),
+            "kml" => app
+                .export_activity_kml(act_id)
+                .await
+                .map(|kml| (kml_content_type(), kml)),
+            _ => None,
+        };
+
+        return match export {
+            Some((content_type, export)) => (Status::Ok, (content_type, export.into_bytes())),
+            None => (Status::NotFound, (ContentType::Text, Vec::new())),
+        };
+    }
+
     if let Ok(act_id) = act_id.parse::<i64>() {
         let app = App::anonym_athlete().await;
         if let Some(activity) = app.get_activity(act_id).await {
             return (
                 Status::Ok,
-                (ContentType::JSON, serde_json::to_string(&activity).unwrap()),
+                (ContentType::JSON, serde_json::to_vec(&activity).unwrap()),
             );
         }
     }
 
+    (Status::NotFound, (ContentType::Text, Vec::new()))
+}
+
+#[get("/routes/<route_file>")]
+async fn route_export(route_file: &str) -> (Status, (ContentType, Vec<u8>)) {
+    let Some((route_id, extension)) = route_file.split_once('.') else {
+        return (Status::NotFound, (ContentType::Text, Vec::new()));
+    };
+
+    let Ok(route_id) = route_id.parse::<i64>() else {
+        return (Status::NotFound, (ContentType::Text, Vec::new()));
+    };
+
+    let app = App::anonym_athlete().await;
+    let export = match extension {
+        "gpx" => app
+            .export_route_gpx(route_id)
+            .await
+            .map(|gpx| (gpx_content_type(), gpx.into_bytes())),
+        "geojson" => app
+            .export_route_geojson(route_id)
+            .await
+            .map(|geojson| (geojson_content_type(), geojson.to_string().into_bytes())),
+        "kml" => app
+            .export_route_kml(route_id)
+            .await
+            .map(|kml| (kml_content_type(), kml.into_bytes())),
+        "kmz" => app
+            .export_route_kml(route_id)
+            .await
+            .map(|kml| (kmz_content_type(), KmlWriter::kmz(&kml))),
+        _ => None,
+    };
+
+    match export {
+        Some(export) => (Status::Ok, export),
+        None => (Status::NotFound, (ContentType::Text, Vec::new())),
+    }
+}
+
+// All of the athlete's routes in one file, for GIS tools
+#[get("/athletes/<athlete_id>/routes.geojson")]
+async fn athlete_routes_geojson(athlete_id: i64) -> (Status, (ContentType, String)) {
+    let app = App::anonym_athlete().await;
+    let geojson = app.export_athlete_routes_geojson(athlete_id).await;
+
+    (Status::Ok, (geojson_content_type(), geojson.to_string()))
+}
+
+// The route library for Google Earth
+#[get("/athletes/<athlete_id>/routes.kmz")]
+async fn athlete_routes_kmz(athlete_id: i64) -> (Status, (ContentType, Vec<u8>)) {
+    let app = App::anonym_athlete().await;
+    let kmz = app.export_athlete_routes_kmz(athlete_id).await;
+
+    (Status::Ok, (kmz_content_type(), kmz))
+}
+
+fn geojson_content_type() -> ContentType {
+    ContentType::new("application", "geo+json")
+}
+
+fn gpx_content_type() -> ContentType {
+    ContentType::new("application", "gpx+xml")
+}
+
+fn kml_content_type() -> ContentType {
+    ContentType::new("application", "vnd.google-earth.kml+xml")
+}
+
+fn kmz_content_type() -> ContentType {
+    ContentType::new("application", "vnd.google-earth.kmz")
+}
+
+#[get("/activities/<act_id>/splits")]
+async fn activity_splits(act_id: i64) -> (Status, (ContentType, String)) {
+    let app = App::anonym_athlete().await;
+
+    if let Some(splits) = app.get_activity_splits(act_id).await {
+        return (
+            Status::Ok,
+            (ContentType::JSON, serde_json::to_string(&splits).unwrap()),
+        );
+    }
+
+    (Status::NotFound, (ContentType::Text, String::new()))
+}
+
+// Activity recorded on a device which does not upload to Strava, uploads are done by an admin for the athlete
+#[post(
+    "/athletes/<athlete_id>/activities/gpx?<type>&<filename>",
+    data = "<gpx>"
+)]
+async fn upload_gpx(
+    _admin: AdminToken,
+    athlete_id: i64,
+    r#type: Option<&str>,
+    filename: Option<&str>,
+    gpx: Data<'_>,
+) -> (Status, (ContentType, String)) {
+    let Some(app) = App::with_athlete(athlete_id).await else {
+        return (Status::NotFound, (ContentType::Text, String::new()));
+    };
+
+    let content = match gpx.open(32.mebibytes()).into_string().await {
+        Ok(content) if content.is_complete() => content.into_inner(),
+        Ok(_) => return (Status::PayloadTooLarge, (ContentType::Text, String::new())),
+        Err(err) => return (Status::BadRequest, (ContentType::Text, err.to_string())),
+    };
+
+    let result = app.import_gpx(&content, r#type, filename).await;
+
+    imported_response(app, result)
+}
+
+// Same as upload_gpx for files from Garmin, Wahoo and other head units
+#[post(
+    "/athletes/<athlete_id>/activities/fit?<type>&<filename>",
+    data = "<fit>"
+)]
+async fn upload_fit(
+    _admin: AdminToken,
+    athlete_id: i64,
+    r#type: Option<&str>,
+    filename: Option<&str>,
+    fit: Data<'_>,
+) -> (Status, (ContentType, String)) {
+    let Some(app) = App::with_athlete(athlete_id).await else {
+        return (Status::NotFound, (ContentType::Text, String::new()));
+    };
+
+    let content = match fit.open(32.mebibytes()).into_bytes().await {
+        Ok(content) if content.is_complete() => content.into_inner(),
+        Ok(_) => return (Status::PayloadTooLarge, (ContentType::Text, String::new())),
+        Err(err) => return (Status::BadRequest, (ContentType::Text, err.to_string())),
+    };
+
+    let result = app.import_fit(&content, r#type, filename).await;
+
+    imported_response(app, result)
+}
+
+// Same as upload_gpx for files exported by training platforms
+#[post(
+    "/athletes/<athlete_id>/activities/tcx?<type>&<filename>",
+    data = "<tcx>"
+)]
+async fn upload_tcx(
+    _admin: AdminToken,
+    athlete_id: i64,
+    r#type: Option<&str>,
+    filename: Option<&str>,
+    tcx: Data<'_>,
+) -> (Status, (ContentType, String)) {
+    let Some(app) = App::with_athlete(athlete_id).await else {
+        return (Status::NotFound, (ContentType::Text, String::new()));
+    };
+
+    let content = match tcx.open(32.mebibytes()).into_string().await {
+        Ok(content) if content.is_complete() => content.into_inner(),
+        Ok(_) => return (Status::PayloadTooLarge, (ContentType::Text, String::new())),
+        Err(err) => return (Status::BadRequest, (ContentType::Text, err.to_string())),
+    };
+
+    let result = app.import_tcx(&content, r#type, filename).await;
+
+    imported_response(app, result)
+}
+
+fn imported_response(
+    app: App,
+    result: ImportResult<DocumentId>,
+) -> (Status, (ContentType, String)) {
+    match result {
+        Ok(act_id) => {
+            // Route matching takes a while, the activity is already stored
+            rocket::tokio::spawn(async move { app.update_routes().await });
+
+            (
+                Status::Ok,
+                (
+                    ContentType::JSON,
+                    serde_json::json!({ "activity_id": act_id }).to_string(),
+                ),
+            )
+        }
+        Err(err) => (
+            Status::UnprocessableEntity,
+            (ContentType::Text, err.to_string()),
+        ),
+    }
+}
+
+#[get("/segments/<seg_id>?<athlete_id>")]
+async fn segments(seg_id: i64, athlete_id: i64) -> (Status, (ContentType, String)) {
+    let app = App::anonym_athlete().await;
+
+    if let Some(history) = app.get_segment_history(athlete_id, seg_id).await {
+        return (
+            Status::Ok,
+            (ContentType::JSON, serde_json::to_string(&history).unwrap()),
+        );
+    }
+
     (Status::NotFound, (ContentType::Text, String::new()))
 }
 
-fn parse_query_to_bson(query: &String) -> Vec<bson::Document> {
+fn parse_query_to_bson(query: &str) -> Vec<bson::Document> {
     let json: Vec<serde_json::Map<String, serde_json::Value>> =
-        serde_json::from_str(query.as_str()).unwrap();
+        serde_json::from_str(query).unwrap();
 
     let mut bsons: Vec<bson::Document> = Vec::new();
     for key in &json {
@@ -69,37 +325,38 @@ fn parse_query_to_bson(query: &String) -> Vec<bson::Document> {
     bsons
 }
 
+// Queries the memory backend can't run are the client's error
+fn query_response<T: serde::Serialize>(
+    result: Result<T, QueryError>,
+) -> (Status, (ContentType, String)) {
+    match result {
+        Ok(documents) => (
+            Status::Ok,
+            (
+                ContentType::JSON,
+                serde_json::to_string(&documents).unwrap(),
+            ),
+        ),
+        Err(err) => (Status::BadRequest, (ContentType::Text, err.to_string())),
+    }
+}
+
 #[post("/query_activities", data = "<query>")]
 async fn query_activities(query: String) -> (Status, (ContentType, String)) {
     let app = App::anonym_athlete().await;
-    let activities = app.query_activities(parse_query_to_bson(&query)).await;
-    (
-        Status::Ok,
-        (
-            ContentType::JSON,
-            serde_json::to_string(&activities).unwrap(),
-        ),
-    )
+    query_response(app.query_activities(parse_query_to_bson(&query)).await)
 }
 
 #[post("/query_efforts", data = "<query>")]
 async fn query_efforts(query: String) -> (Status, (ContentType, String)) {
     let app = App::anonym_athlete().await;
-    let efforts = app.query_activities(parse_query_to_bson(&query)).await;
-    (
-        Status::Ok,
-        (ContentType::JSON, serde_json::to_string(&efforts).unwrap()),
-    )
+    query_response(app.query_activities(parse_query_to_bson(&query)).await)
 }
 
 #[post("/query_routes", data = "<query>")]
 async fn query_routes(query: String) -> (Status, (ContentType, String)) {
     let app = App::anonym_athlete().await;
-    let efforts = app.query_routes(parse_query_to_bson(&query)).await;
-    (
-        Status::Ok,
-        (ContentType::JSON, serde_json::to_string(&efforts).unwrap()),
-    )
+    query_response(app.query_routes(parse_query_to_bson(&query)).await)
 }
 
 #[post("/query_statistics")]
@@ -112,72 +369,268 @@ async fn query_statistics() -> (Status, (ContentType, String)) {
     )
 }
 
-#[post("/on_activity_updated", data = "<query>")]
-async fn on_activity_updated(query: String) -> (Status, (ContentType, String)) {
-    let json: serde_json::Map<String, serde_json::Value> =
-        serde_json::from_str(query.as_str()).unwrap();
+#[derive(FromForm)]
+struct SubscriptionValidation<'r> {
+    mode: &'r str,
+    challenge: &'r str,
+    verify_token: &'r str,
+}
 
-    let mut is_activity_creation = false;
-    let mut is_activity_deletion = false;
+// Handshake done by Strava when creating the push subscription
+#[get("/on_activity_updated?<hub>")]
+async fn validate_subscription(hub: SubscriptionValidation<'_>) -> (Status, (ContentType, String)) {
+    if hub.mode != "subscribe"
+        || Config::global().strava.verify_token.as_deref() != Some(hub.verify_token)
+    {
+        return (Status::Forbidden, (ContentType::Text, String::new()));
+    }
 
-    let mut athlete_id: Option<DocumentId> = None;
-    let mut act_id: Option<DocumentId> = None;
+    (
+        Status::Ok,
+        (
+            ContentType::JSON,
+            serde_json::json!({ "hub.challenge": hub.challenge }).to_string(),
+        ),
+    )
+}
 
-    for key in &json {
-        match key.0.trim() {
-            "create" => {
-                is_activity_creation = true;
-                act_id = key.1.to_string().parse::<i64>().ok();
+// Events are processed in the background, after Strava got its answer
+struct WebhookProcessor;
+
+impl WebhookProcessor {
+    const CC: &str = "Webhook";
+
+    async fn process(event: WebhookEvent) {
+        if event.object_type == ObjectType::Athlete {
+            let deauthorized = event.updates.get("authorized").is_some_and(|authorized| {
+                authorized.as_str() == Some("false") || authorized.as_bool() == Some(false)
+            });
+
+            if event.aspect_type == AspectType::Update && deauthorized {
+                WebhookProcessor::on_deauthorized(event.owner_id).await;
             }
-            "delete" => {
-                is_activity_deletion = true;
-                act_id = key.1.to_string().parse::<i64>().ok();
+
+            return;
+        }
+
+        if let Some(app) = App::with_athlete(event.owner_id).await {
+            let act_id = event.object_id;
+
+            match event.aspect_type {
+                AspectType::Create => {
+                    if let Err(err) = app.on_new_activity(act_id).await {
+                        logln!("Failed to process new activity {}: {}", act_id, err);
+                    }
+                }
+                AspectType::Update => {
+                    if let Err(err) = app.on_update_activity(act_id).await {
+                        logln!("Failed to process activity update {}: {}", act_id, err);
+                    }
+                }
+                AspectType::Delete => app.on_delete_activity(act_id).await,
             }
-            "athlete_id" => {
-                athlete_id = key.1.to_string().parse::<i64>().ok();
+        }
+    }
+
+    // Athlete revoked our access, all their data has to go once Strava confirms it
+    async fn on_deauthorized(athlete_id: AthleteId) {
+        let Some(app) = App::with_athlete(athlete_id).await else {
+            return;
+        };
+
+        match app.is_strava_access_revoked().await {
+            Ok(true) => {
+                app.purge_athlete(athlete_id).await;
             }
-            _ => {}
+            Ok(false) => logln!(
+                "Athlete {} still has access, deauthorization ignored",
+                athlete_id
+            ),
+            Err(err) => logln!(
+                "Deauthorization of athlete {} not confirmed: {}",
+                athlete_id,
+                err
+            ),
         }
     }
+}
 
-    if let Some(athlete_id) = athlete_id {
-        if let Some(act_id) = act_id {                        
-            if let Some(app) = App::with_athlete(athlete_id).await {
-                if is_activity_creation {
-                    app.on_new_activity(act_id).await;
-                }
+#[post("/on_activity_updated", data = "<event>")]
+async fn on_activity_updated(event: String) -> (Status, (ContentType, String)) {
+    let event: WebhookEvent = match serde_json::from_str(event.as_str()) {
+        Ok(event) => event,
+        Err(_) => return (Status::BadRequest, (ContentType::Text, String::new())),
+    };
 
-                if is_activity_deletion {
-                    app.on_delete_activity(act_id).await;
-                }
+    // Anyone can post here, only events of our subscription are trusted
+    if Config::global().strava.subscription_id != Some(event.subscription_id) {
+        return (Status::Forbidden, (ContentType::Text, String::new()));
+    }
+
+    // Strava expects an answer within 2 seconds so processing is done in the background
+    rocket::tokio::spawn(WebhookProcessor::process(event));
+
+    (Status::Ok, (ContentType::Text, "OK".to_string()))
+}
+
+pub struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let expected_token = Config::global().server.admin_token.as_deref();
+
+        match (request.headers().get_one("X-Admin-Token"), expected_token) {
+            (Some(token), Some(expected_token)) if token == expected_token => {
+                request::Outcome::Success(AdminToken)
             }
+            _ => request::Outcome::Failure((Status::Unauthorized, ())),
         }
     }
+}
+
+#[delete("/admin/athletes/<athlete_id>")]
+async fn purge_athlete(_admin: AdminToken, athlete_id: i64) -> (Status, (ContentType, String)) {
+    let report = App::anonym_athlete().await.purge_athlete(athlete_id).await;
 
     (
         Status::Ok,
-        (ContentType::Text, "OK".to_string()),
+        (ContentType::JSON, serde_json::to_string(&report).unwrap()),
     )
 }
 
+// Holds the state of the OAuth flow started by this browser
+const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+// Starts onboarding of a new athlete by sending them to Strava's authorization page
+#[get("/oauth/authorize")]
+fn oauth_authorize(cookies: &CookieJar<'_>) -> Result<Redirect, (Status, String)> {
+    let state = App::new_oauth_state();
+
+    let url = App::strava_authorize_url(&Config::global().strava.redirect_uri, &state)
+        .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    // Lax, the callback is a cross-site redirect from Strava
+    cookies.add(
+        Cookie::build(OAUTH_STATE_COOKIE, state)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish(),
+    );
+
+    Ok(Redirect::to(url))
+}
+
+#[get("/oauth/callback?<code>&<scope>&<state>&<error>")]
+async fn oauth_callback(
+    cookies: &CookieJar<'_>,
+    code: Option<&str>,
+    scope: Option<&str>,
+    state: Option<&str>,
+    error: Option<&str>,
+) -> (Status, (ContentType, String)) {
+    let expected_state = cookies
+        .get(OAUTH_STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove(Cookie::named(OAUTH_STATE_COOKIE));
+
+    // Otherwise anyone could make a victim's browser complete the flow with their own code
+    if expected_state.is_none() || expected_state.as_deref() != state {
+        return (
+            Status::Forbidden,
+            (ContentType::Text, "invalid OAuth state".to_string()),
+        );
+    }
+
+    if let Some(error) = error {
+        return (Status::Forbidden, (ContentType::Text, error.to_string()));
+    }
+
+    let (Some(code), Some(scope)) = (code, scope) else {
+        return (Status::BadRequest, (ContentType::Text, String::new()));
+    };
+
+    // Without activity access there is nothing to sync
+    if !scope
+        .split(',')
+        .any(|scope| scope == "activity:read" || scope == "activity:read_all")
+    {
+        return (
+            Status::BadRequest,
+            (
+                ContentType::Text,
+                "activity:read scope required".to_string(),
+            ),
+        );
+    }
+
+    match App::register_athlete(code).await {
+        Ok(app) => {
+            let athlete_id = app.athlete_id();
+
+            // Initial backfill of the athlete's history, takes a while
+            rocket::tokio::spawn(async move { app.start_data_pipeline().await });
+
+            (
+                Status::Ok,
+                (
+                    ContentType::JSON,
+                    serde_json::json!({ "athlete_id": athlete_id }).to_string(),
+                ),
+            )
+        }
+        Err(err) => (Status::BadGateway, (ContentType::Text, err.to_string())),
+    }
+}
+
 #[launch]
 fn rocket() -> _ {
+    let config = match Config::load(std::env::args().skip(1)) {
+        Ok(config) => Config::init_global(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     rocket::build()
         .configure(
             rocket::Config::figment()
-                .merge(("port", 8080))
-                .merge(("address", "0.0.0.0")),
+                .merge(("port", config.server.port))
+                .merge(("address", config.server.address.as_str())),
         )
         .attach(Cors)
+        .attach(rocket::fairing::AdHoc::on_liftoff(
+            "Activity directories watcher",
+            |_| {
+                Box::pin(async {
+                    rocket::tokio::spawn(App::watch_activity_directories());
+                })
+            },
+        ))
         .mount(
             "/",
             routes![
                 activities,
+                activity_splits,
+                upload_gpx,
+                upload_fit,
+                upload_tcx,
+                segments,
+                route_export,
+                athlete_routes_geojson,
+                athlete_routes_kmz,
                 query_routes,
                 query_activities,
                 query_efforts,
                 query_statistics,
+                validate_subscription,
                 on_activity_updated,
+                oauth_authorize,
+                oauth_callback,
+                purge_athlete,
                 all_options
             ],
         )
@@ -0,0 +1,663 @@
+use std::{collections::HashMap, fmt::Display, path::Path, time::Duration};
+
+use once_cell::sync::OnceCell;
+use serde_derive::Deserialize;
+
+use crate::{
+    data_types::strava::sync_job::MAX_SYNC_PAGE_SIZE,
+    database::DatabaseBackend,
+    strava::{cassette::CassetteMode, http::STRAVA_BASE_URL, retry::Endpoint},
+};
+
+static GLOBAL_CONFIG: OnceCell<Config> = OnceCell::new();
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// Every setting is addressed by "section.field", the same name is used in the file and on the command line
+// environment variables override the file, command line overrides both
+const SETTINGS: &[(&str, &str)] = &[
+    ("strava.client_id", "STRAVA_CLIENT_ID"),
+    ("strava.client_secret", "STRAVA_CLIENT_SECRET"),
+    ("strava.api_url", "STRAVA_API_URL"),
+    ("strava.request_timeout_secs", "STRAVA_REQUEST_TIMEOUT_SECS"),
+    (
+        "strava.token_refresh_margin_secs",
+        "STRAVA_TOKEN_REFRESH_MARGIN_SECS",
+    ),
+    ("strava.verify_token", "STRAVA_VERIFY_TOKEN"),
+    ("strava.subscription_id", "STRAVA_SUBSCRIPTION_ID"),
+    ("strava.redirect_uri", "STRAVA_REDIRECT_URI"),
+    ("strava.cassette", "STRAVA_CASSETTE"),
+    ("strava.cassette_mode", "STRAVA_CASSETTE_MODE"),
+    ("database.backend", "DATABASE_BACKEND"),
+    ("database.url", "MONGO_DB_URL"),
+    ("database.strava_db", "STRAVA_DB_NAME"),
+    ("database.gc_db", "GC_DB_NAME"),
+    ("server.address", "SERVER_ADDRESS"),
+    ("server.port", "SERVER_PORT"),
+    ("server.allowed_origins", "ALLOWED_ORIGINS"),
+    ("server.admin_token", "ADMIN_TOKEN"),
+    (
+        "pipeline.rate_limit_short_reserve",
+        "PIPELINE_RATE_LIMIT_SHORT_RESERVE",
+    ),
+    (
+        "pipeline.rate_limit_daily_reserve",
+        "PIPELINE_RATE_LIMIT_DAILY_RESERVE",
+    ),
+    ("pipeline.max_retries", "PIPELINE_MAX_RETRIES"),
+    (
+        "pipeline.retry_base_delay_ms",
+        "PIPELINE_RETRY_BASE_DELAY_MS",
+    ),
+    (
+        "pipeline.retry_max_delay_secs",
+        "PIPELINE_RETRY_MAX_DELAY_SECS",
+    ),
+    ("pipeline.backfill_streams", "PIPELINE_BACKFILL_STREAMS"),
+    ("pipeline.sync_page_size", "PIPELINE_SYNC_PAGE_SIZE"),
+    (
+        "pipeline.download_concurrency",
+        "PIPELINE_DOWNLOAD_CONCURRENCY",
+    ),
+    ("sources.strava", "SOURCES_STRAVA"),
+    ("sources.directories", "SOURCES_DIRECTORIES"),
+    ("sources.directory_poll_secs", "SOURCES_DIRECTORY_POLL_SECS"),
+];
+
+// Per endpoint retry settings "strava.retry.<endpoint>.<field>", env STRAVA_RETRY_<ENDPOINT>_<FIELD>
+const RETRY_FIELDS: &[&str] = &["max_retries", "base_delay_ms", "max_delay_secs"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    // File given explicitly could not be read
+    File(String),
+    // File content is not valid TOML or has fields of the wrong type
+    Parse(String),
+    UnknownSetting(String),
+    InvalidValue { setting: String, value: String },
+    // Settings without a value which have no sensible default
+    Missing(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::File(err) => write!(f, "unable to read config file: {}", err),
+            ConfigError::Parse(err) => write!(f, "invalid config file: {}", err),
+            ConfigError::UnknownSetting(setting) => write!(f, "unknown setting '{}'", setting),
+            ConfigError::InvalidValue { setting, value } => {
+                write!(f, "invalid value '{}' for setting '{}'", value, setting)
+            }
+            ConfigError::Missing(settings) => {
+                write!(f, "missing required settings: ")?;
+
+                for (index, setting) in settings.iter().enumerate() {
+                    let env_var = SETTINGS
+                        .iter()
+                        .find(|(name, _)| name == setting)
+                        .map_or("", |(_, env_var)| env_var);
+
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{} (env {})", setting, env_var)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StravaConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub api_url: String,
+    pub request_timeout_secs: u64,
+    // Tokens are refreshed this long before they expire
+    pub token_refresh_margin_secs: u64,
+    // Token Strava echoes back when validating the webhook subscription
+    pub verify_token: Option<String>,
+    // Id of our push subscription, events carrying another one are ignored
+    pub subscription_id: Option<i64>,
+    pub redirect_uri: String,
+    // File Strava requests are recorded to or replayed from, used to run syncs offline in tests
+    pub cassette: Option<String>,
+    pub cassette_mode: CassetteMode,
+    // Keyed by endpoint name, fields left unset use the pipeline retry settings
+    pub retry: HashMap<String, EndpointRetryConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct EndpointRetryConfig {
+    pub max_retries: Option<u32>,
+    pub base_delay_ms: Option<u64>,
+    pub max_delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    // The memory backend loses everything on exit, for tests and trying the server out
+    pub backend: DatabaseBackend,
+    pub url: String,
+    pub strava_db: String,
+    pub gc_db: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub allowed_origins: Vec<String>,
+    pub admin_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PipelineConfig {
+    pub rate_limit_short_reserve: u32,
+    pub rate_limit_daily_reserve: u32,
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_secs: u64,
+    // Re-downloads the streams of activities synced before all stream types were stored
+    pub backfill_streams: bool,
+    // Activities listed per request, Strava allows up to 200
+    pub sync_page_size: usize,
+    // Activity details and streams downloaded in parallel, the rate limit budget still applies
+    pub download_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SourcesConfig {
+    // Activities synced from the Strava API
+    pub strava: bool,
+    // Folders of GPX, FIT and TCX files with a sub folder per athlete id
+    pub directories: Vec<String>,
+    // How often the folders are checked for new files
+    pub directory_poll_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub strava: StravaConfig,
+    pub database: DatabaseConfig,
+    pub server: ServerConfig,
+    pub pipeline: PipelineConfig,
+    pub sources: SourcesConfig,
+}
+
+impl Default for StravaConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            api_url: STRAVA_BASE_URL.to_string(),
+            request_timeout_secs: 30,
+            token_refresh_margin_secs: 300,
+            verify_token: None,
+            subscription_id: None,
+            redirect_uri: "http://localhost:8080/oauth/callback".to_string(),
+            cassette: None,
+            cassette_mode: CassetteMode::Replay,
+            retry: HashMap::new(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            backend: DatabaseBackend::Mongodb,
+            url: "mongodb://localhost:27017".to_string(),
+            strava_db: "strava_db".to_string(),
+            gc_db: "gc_db".to_string(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: 8080,
+            allowed_origins: vec!["http://localhost:5173".to_string()],
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit_short_reserve: 2,
+            rate_limit_daily_reserve: 10,
+            max_retries: 3,
+            retry_base_delay_ms: 500,
+            retry_max_delay_secs: 60,
+            backfill_streams: false,
+            sync_page_size: 30,
+            download_concurrency: 4,
+        }
+    }
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self {
+        Self {
+            strava: true,
+            directories: Vec::new(),
+            directory_poll_secs: 60,
+        }
+    }
+}
+
+impl PipelineConfig {
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_ms)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_max_delay_secs)
+    }
+}
+
+impl Config {
+    // Layers, lowest priority first: defaults, config file, environment variables, command line
+    // Command line accepts '--config <path>' and '--<section.field> <value>' (or '=value')
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let overrides = Config::parse_args(args)?;
+
+        let config_path = overrides
+            .iter()
+            .find(|(setting, _)| setting == "config")
+            .map(|(_, path)| path.clone())
+            .or(std::env::var("GC_CONFIG").ok());
+
+        let mut config = match config_path {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            // Default file is optional
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Default::default(),
+        };
+
+        for (setting, env_var) in Config::settings() {
+            if let Ok(value) = std::env::var(env_var) {
+                config.set(&setting, &value)?;
+            }
+        }
+
+        for (setting, value) in &overrides {
+            if setting != "config" {
+                config.set(setting, value)?;
+            }
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::File(format!("{}: {}", path.display(), err)))?;
+
+        let table: toml::Table =
+            toml::from_str(&content).map_err(|err| ConfigError::Parse(err.to_string()))?;
+
+        // Misspelled settings would otherwise be silently replaced by their defaults
+        let mut names = Vec::new();
+        Config::setting_names("", &table, &mut names);
+
+        for setting in names {
+            if !Config::settings().any(|(name, _)| name == setting) {
+                return Err(ConfigError::UnknownSetting(setting));
+            }
+        }
+
+        table
+            .try_into()
+            .map_err(|err: toml::de::Error| ConfigError::Parse(err.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut missing: Vec<String> = Vec::new();
+
+        let mut require = |setting: &str, value: &str| {
+            if value.trim().is_empty() {
+                missing.push(setting.to_string());
+            }
+        };
+
+        // Directory only setups never talk to Strava
+        if self.sources.strava {
+            require("strava.client_id", &self.strava.client_id);
+            require("strava.client_secret", &self.strava.client_secret);
+            require("strava.api_url", &self.strava.api_url);
+        }
+
+        if self.database.backend == DatabaseBackend::Mongodb {
+            require("database.url", &self.database.url);
+            require("database.strava_db", &self.database.strava_db);
+            require("database.gc_db", &self.database.gc_db);
+        }
+
+        require("server.address", &self.server.address);
+
+        if !missing.is_empty() {
+            return Err(ConfigError::Missing(missing));
+        }
+
+        if self.pipeline.sync_page_size == 0 || self.pipeline.sync_page_size > MAX_SYNC_PAGE_SIZE {
+            return Err(ConfigError::InvalidValue {
+                setting: "pipeline.sync_page_size".to_string(),
+                value: self.pipeline.sync_page_size.to_string(),
+            });
+        }
+
+        if self.pipeline.download_concurrency == 0 {
+            return Err(ConfigError::InvalidValue {
+                setting: "pipeline.download_concurrency".to_string(),
+                value: "0".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(setting: &str, value: &str) -> Result<T, ConfigError> {
+            value
+                .trim()
+                .parse::<T>()
+                .map_err(|_| ConfigError::InvalidValue {
+                    setting: setting.to_string(),
+                    value: value.to_string(),
+                })
+        }
+
+        let optional = |value: &str| -> Option<String> {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            }
+        };
+
+        match setting {
+            "strava.client_id" => self.strava.client_id = value.to_string(),
+            "strava.client_secret" => self.strava.client_secret = value.to_string(),
+            "strava.api_url" => self.strava.api_url = value.to_string(),
+            "strava.request_timeout_secs" => {
+                self.strava.request_timeout_secs = parse(setting, value)?
+            }
+            "strava.token_refresh_margin_secs" => {
+                self.strava.token_refresh_margin_secs = parse(setting, value)?
+            }
+            "strava.verify_token" => self.strava.verify_token = optional(value),
+            "strava.subscription_id" => {
+                self.strava.subscription_id = match optional(value) {
+                    Some(value) => Some(parse(setting, &value)?),
+                    None => None,
+                }
+            }
+            "strava.redirect_uri" => self.strava.redirect_uri = value.to_string(),
+            "strava.cassette" => self.strava.cassette = optional(value),
+            "strava.cassette_mode" => self.strava.cassette_mode = parse(setting, value)?,
+            "database.backend" => self.database.backend = parse(setting, value)?,
+            "database.url" => self.database.url = value.to_string(),
+            "database.strava_db" => self.database.strava_db = value.to_string(),
+            "database.gc_db" => self.database.gc_db = value.to_string(),
+            "server.address" => self.server.address = value.to_string(),
+            "server.port" => self.server.port = parse(setting, value)?,
+            "server.allowed_origins" => {
+                self.server.allowed_origins = value
+                    .split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect()
+            }
+            "server.admin_token" => self.server.admin_token = optional(value),
+            "pipeline.rate_limit_short_reserve" => {
+                self.pipeline.rate_limit_short_reserve = parse(setting, value)?
+            }
+            "pipeline.rate_limit_daily_reserve" => {
+                self.pipeline.rate_limit_daily_reserve = parse(setting, value)?
+            }
+            "pipeline.max_retries" => self.pipeline.max_retries = parse(setting, value)?,
+            "pipeline.retry_base_delay_ms" => {
+                self.pipeline.retry_base_delay_ms = parse(setting, value)?
+            }
+            "pipeline.retry_max_delay_secs" => {
+                self.pipeline.retry_max_delay_secs = parse(setting, value)?
+            }
+            "pipeline.backfill_streams" => self.pipeline.backfill_streams = parse(setting, value)?,
+            "pipeline.sync_page_size" => self.pipeline.sync_page_size = parse(setting, value)?,
+            "pipeline.download_concurrency" => {
+                self.pipeline.download_concurrency = parse(setting, value)?
+            }
+            "sources.strava" => self.sources.strava = parse(setting, value)?,
+            "sources.directories" => {
+                self.sources.directories = value
+                    .split(',')
+                    .map(|directory| directory.trim().to_string())
+                    .filter(|directory| !directory.is_empty())
+                    .collect()
+            }
+            "sources.directory_poll_secs" => {
+                self.sources.directory_poll_secs = parse(setting, value)?
+            }
+            _ => {
+                let unknown = || ConfigError::UnknownSetting(setting.to_string());
+
+                let (endpoint, field) = setting
+                    .strip_prefix("strava.retry.")
+                    .and_then(|setting| setting.split_once('.'))
+                    .ok_or_else(unknown)?;
+
+                if !Endpoint::ALL.iter().any(|known| known.name() == endpoint) {
+                    return Err(unknown());
+                }
+
+                let retry = self.strava.retry.entry(endpoint.to_string()).or_default();
+
+                match field {
+                    "max_retries" => retry.max_retries = Some(parse(setting, value)?),
+                    "base_delay_ms" => retry.base_delay_ms = Some(parse(setting, value)?),
+                    "max_delay_secs" => retry.max_delay_secs = Some(parse(setting, value)?),
+                    _ => return Err(unknown()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every setting with its environment variable, the per endpoint retry ones included
+    fn settings() -> impl Iterator<Item = (String, String)> {
+        let retry_settings = Endpoint::ALL.iter().flat_map(|endpoint| {
+            RETRY_FIELDS.iter().map(move |field| {
+                (
+                    format!("strava.retry.{}.{}", endpoint.name(), field),
+                    format!("STRAVA_RETRY_{}_{}", endpoint.name(), field).to_uppercase(),
+                )
+            })
+        });
+
+        SETTINGS
+            .iter()
+            .map(|(setting, env_var)| (setting.to_string(), env_var.to_string()))
+            .chain(retry_settings)
+    }
+
+    // Dotted names of the values in the file, nested tables like [strava.retry.activity] included
+    fn setting_names(prefix: &str, table: &toml::Table, names: &mut Vec<String>) {
+        for (key, value) in table {
+            let name = if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+
+            match value.as_table() {
+                Some(table) => Config::setting_names(&name, table, names),
+                None => names.push(name),
+            }
+        }
+    }
+
+    // Makes the config available to the whole crate, only the first call has an effect
+    pub fn init_global(config: Config) -> &'static Config {
+        GLOBAL_CONFIG.get_or_init(|| config)
+    }
+
+    // Falls back to loading from file and environment when the binary did not initialize it
+    pub fn global() -> &'static Config {
+        GLOBAL_CONFIG.get_or_init(|| match Config::load(Vec::new()) {
+            Ok(config) => config,
+            Err(err) => panic!("Invalid configuration: {}", err),
+        })
+    }
+
+    // Memory database and no retry delays, every test using the global config has to call it first
+    #[cfg(test)]
+    pub fn init_test_global() -> &'static Config {
+        let mut config: Config = Default::default();
+
+        config.strava.client_id = "1".to_string();
+        config.strava.client_secret = "secret".to_string();
+        config.database.backend = DatabaseBackend::Memory;
+        config.pipeline.max_retries = 0;
+
+        Config::init_global(config)
+    }
+
+    fn parse_args<I: IntoIterator<Item = String>>(
+        args: I,
+    ) -> Result<Vec<(String, String)>, ConfigError> {
+        let mut overrides: Vec<(String, String)> = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(setting) = arg.strip_prefix("--") else {
+                return Err(ConfigError::UnknownSetting(arg));
+            };
+
+            if let Some((setting, value)) = setting.split_once('=') {
+                overrides.push((setting.to_string(), value.to_string()));
+                continue;
+            }
+
+            match args.next() {
+                Some(value) => overrides.push((setting.to_string(), value)),
+                None => {
+                    return Err(ConfigError::InvalidValue {
+                        setting: setting.to_string(),
+                        value: String::new(),
+                    })
+                }
+            }
+        }
+
+        Ok(overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // Environment variables are shared by the whole test process
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const FILE: &str = r#"
+        [strava]
+        client_id = "1"
+        client_secret = "secret"
+
+        [database]
+        backend = "memory"
+
+        [server]
+        address = "127.0.0.1"
+        port = 9000
+    "#;
+
+    // Loads 'content' as the config file with the given environment and command line
+    fn load(content: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let _env_guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        let path = std::env::temp_dir().join(format!("gc_config_{}.toml", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+
+        for (env_var, value) in env {
+            std::env::set_var(env_var, value);
+        }
+
+        let config_arg = format!("--config={}", path.display());
+        let args = std::iter::once(config_arg).chain(args.iter().map(|arg| arg.to_string()));
+        let result = Config::load(args);
+
+        for (env_var, _) in env {
+            std::env::remove_var(env_var);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        result
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = load(FILE, &[("SERVER_PORT", "9100")], &[]).unwrap();
+
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.server.address, "127.0.0.1");
+        assert_eq!(config.database.backend, DatabaseBackend::Memory);
+    }
+
+    #[test]
+    fn command_line_overrides_the_environment() {
+        let env = [("SERVER_PORT", "9100"), ("SERVER_ADDRESS", "10.0.0.1")];
+
+        let config = load(FILE, &env, &["--server.port", "9200"]).unwrap();
+        assert_eq!(config.server.port, 9200);
+        assert_eq!(config.server.address, "10.0.0.1");
+
+        let config = load(FILE, &env, &["--server.address=0.0.0.0"]).unwrap();
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.server.address, "0.0.0.0");
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        let misspelled = FILE.replace("port = 9000", "prot = 9000");
+        assert_eq!(
+            load(&misspelled, &[], &[]).unwrap_err(),
+            ConfigError::UnknownSetting("server.prot".to_string())
+        );
+
+        assert_eq!(
+            load(FILE, &[], &["--server.prot", "9000"]).unwrap_err(),
+            ConfigError::UnknownSetting("server.prot".to_string())
+        );
+
+        assert_eq!(
+            load(FILE, &[], &["server.port=9000"]).unwrap_err(),
+            ConfigError::UnknownSetting("server.port=9000".to_string())
+        );
+    }
+}
@@ -44,4 +44,20 @@ impl Identifiable for Route {
     fn as_i64(&self) -> DocumentId {
         self._id as DocumentId
     }
-}
\ No newline at end of file
+}
+
+impl Route {
+    // Routes left without their master activity get the first remaining one,
+    // the empty type marks them for the route processor
+    pub fn remove_activities(&mut self, act_ids: &[DocumentId]) {
+        self.activities.retain(|act_id| !act_ids.contains(act_id));
+
+        if act_ids.contains(&self.master_activity_id) {
+            if let Some(first_act_id) = self.activities.first() {
+                self.master_activity_id = *first_act_id;
+            }
+
+            self.r#type = "".to_string();
+        }
+    }
+}
@@ -34,9 +34,52 @@ pub struct Effort {
     pub distance_from_start: Option<f32>,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Lap {
+    pub id: DocumentId,
+    pub name: String,
+    pub lap_index: i32,
+
+    pub distance: f32,
+    pub moving_time: i32,
+    pub elapsed_time: i32,
+    pub average_speed: f32,
+    pub max_speed: f32,
+    #[serde(default)]
+    pub total_elevation_gain: f32,
+    pub average_heartrate: Option<f32>,
+    pub average_cadence: Option<f32>,
+    pub average_watts: Option<f32>,
+
+    pub start_index: i32,
+    pub end_index: i32,
+    pub start_date_local: String,
+
+    // Added later by post processor
+    pub start_index_poly: Option<i32>,
+    pub end_index_poly: Option<i32>,
+}
+
+// splits_metric are 1km long, splits_standard 1 mile
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Split {
+    pub split: i32,
+    pub distance: f32,
+    pub moving_time: i32,
+    pub elapsed_time: i32,
+    pub average_speed: f32,
+    #[serde(default)]
+    pub elevation_difference: f32,
+    pub average_grade_adjusted_speed: Option<f32>,
+    pub average_heartrate: Option<f32>,
+    pub pace_zone: Option<i32>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Activity {
     pub _id: f64,
+    #[serde(default)]
+    pub name: String,
     pub distance: f32,
     pub average_speed: f32,
     pub segment_efforts: Vec<Effort>,
@@ -50,8 +93,37 @@ pub struct Activity {
     pub description: Option<String>,
     pub location_city: Option<String>,
     pub location_country: String,
+    // UTC, start_date_local is the same time in the activity's timezone
+    #[serde(default)]
+    pub start_date: Option<String>,
     pub start_date_local: String,
-    pub start_date_local_date: Option<DateTime>
+    pub start_date_local_date: Option<DateTime>,
+
+    #[serde(default)]
+    pub private: bool,
+
+    #[serde(default)]
+    pub laps: Vec<Lap>,
+    #[serde(default)]
+    pub splits_metric: Vec<Split>,
+    #[serde(default)]
+    pub splits_standard: Vec<Split>,
+
+    // Name of the ActivitySource it was synced from, None for activities stored before sources existed
+    #[serde(default)]
+    pub source: Option<String>,
+
+    // Set by sources whose activities change after they were synced, like edited files
+    #[serde(default)]
+    pub revision: Option<i64>,
+}
+
+impl Activity {
+    pub fn source_name(&self) -> &str {
+        self.source
+            .as_deref()
+            .unwrap_or(crate::sources::strava::STRAVA_SOURCE)
+    }
 }
 
 impl crate::data_types::common::Identifiable for Activity {
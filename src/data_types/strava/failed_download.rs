@@ -0,0 +1,40 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data_types::common::DocumentId;
+
+use super::athlete::AthleteId;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum DownloadKind {
+    // Activity details could not be downloaded
+    Activity,
+    // Activity is stored but its telemetry streams are missing
+    Telemetry,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FailedDownload {
+    pub _id: DocumentId,
+    pub athlete_id: AthleteId,
+    pub kind: DownloadKind,
+    pub error: String,
+    pub attempts: i32,
+    pub last_attempt_ts: i64,
+    // None for downloads which failed before sources existed, they were all from Strava
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl FailedDownload {
+    pub fn source_name(&self) -> &str {
+        self.source
+            .as_deref()
+            .unwrap_or(crate::sources::strava::STRAVA_SOURCE)
+    }
+}
+
+impl crate::data_types::common::Identifiable for FailedDownload {
+    fn as_i64(&self) -> i64 {
+        self._id
+    }
+}
@@ -1,5 +1,8 @@
 pub mod common;
 pub mod athlete;
 pub mod activity;
+pub mod failed_download;
 pub mod segment;
-pub mod telemetry;
\ No newline at end of file
+pub mod sync_job;
+pub mod telemetry;
+pub mod webhook;
\ No newline at end of file
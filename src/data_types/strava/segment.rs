@@ -1,10 +1,14 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::data_types::common::DocumentId;
 
-use super::common::Map;
+use super::{
+    activity::Effort,
+    common::Map,
+    telemetry::{F32data, LatLngData},
+};
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Xoms {
     #[serde(deserialize_with = "null_to_default")]
     pub kom: String,
@@ -12,13 +16,48 @@ pub struct Xoms {
     pub qom: String
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+// Geometry downloaded with get_segment_telemetry
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SegmentStreams {
+    #[serde(default)]
+    pub latlng: LatLngData,
+    #[serde(default)]
+    pub distance: F32data,
+    #[serde(default)]
+    pub altitude: F32data,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Segment {
     pub _id: DocumentId,
     pub distance: f32,
     pub name: String,
+    #[serde(default)]
     pub xoms: Xoms,
     pub map: Map,
+
+    #[serde(default)]
+    pub average_grade: f32,
+    #[serde(default)]
+    pub maximum_grade: f32,
+    pub city: Option<String>,
+    pub country: Option<String>,
+
+    #[serde(default)]
+    pub streams: SegmentStreams,
+}
+
+// Segment together with all the efforts an athlete did on it
+#[derive(Debug, Serialize, Clone)]
+pub struct SegmentHistory {
+    pub segment: Segment,
+    pub efforts: Vec<Effort>,
+}
+
+impl crate::data_types::common::Identifiable for Segment {
+    fn as_i64(&self) -> i64 {
+        self._id
+    }
 }
 
 fn null_to_default<'de, D, T>(de: D) -> Result<T, D::Error>
@@ -0,0 +1,137 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data_types::common::DocumentId;
+
+use super::athlete::AthleteId;
+
+// Strava refuses larger pages
+pub const MAX_SYNC_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum SyncJobKind {
+    // Everything recorded before the athlete connected
+    Backfill,
+    // Activities recorded since the last completed sync
+    Incremental,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum SyncJobState {
+    Running,
+    // Stopped by the rate limit or an authorization error, the next run resumes it
+    Paused,
+    Completed,
+}
+
+// Steps an activity goes through, in order
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ActivitySyncStatus {
+    Listed,
+    DetailFetched,
+    TelemetryFetched,
+    PostProcessed,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ActivitySyncEntry {
+    pub act_id: DocumentId,
+    pub start_ts: i64,
+    // Listed by the source, a stored activity with another revision is synced again
+    #[serde(default)]
+    pub revision: Option<i64>,
+    pub status: ActivitySyncStatus,
+    // Set when the activity was skipped, it is then retried with the failed downloads
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SyncJobError {
+    pub act_id: Option<DocumentId>,
+    pub error: String,
+    pub ts: i64,
+}
+
+// One job per athlete, saved after every step so a crashed or paused sync resumes where it stopped
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SyncJob {
+    pub _id: AthleteId,
+    pub kind: SyncJobKind,
+    pub state: SyncJobState,
+    pub after_ts: i64,
+    pub before_ts: i64,
+    pub page_size: usize,
+    // Number of the page being processed
+    pub page: usize,
+    // Start and id of the most recent activity listed, the next page is requested after them
+    pub cursor_ts: i64,
+    #[serde(default = "SyncJob::first_cursor_id")]
+    pub cursor_id: DocumentId,
+    // False once Strava returned a page which was not full
+    pub has_more: bool,
+    // Activities of the current page
+    pub activities: Vec<ActivitySyncEntry>,
+    pub processed_count: u32,
+    pub errors: Vec<SyncJobError>,
+    pub created_ts: i64,
+    pub updated_ts: i64,
+}
+
+impl SyncJob {
+    pub fn new(
+        athlete_id: AthleteId,
+        kind: SyncJobKind,
+        after_ts: i64,
+        before_ts: i64,
+        page_size: usize,
+        now_ts: i64,
+    ) -> Self {
+        Self {
+            _id: athlete_id,
+            kind,
+            state: SyncJobState::Running,
+            after_ts,
+            before_ts,
+            page_size: page_size.clamp(1, MAX_SYNC_PAGE_SIZE),
+            page: 0,
+            cursor_ts: after_ts,
+            cursor_id: SyncJob::first_cursor_id(),
+            has_more: true,
+            activities: Vec::new(),
+            processed_count: 0,
+            errors: Vec::new(),
+            created_ts: now_ts,
+            updated_ts: now_ts,
+        }
+    }
+
+    // Below every id, synthetic ids are negative
+    fn first_cursor_id() -> DocumentId {
+        DocumentId::MIN
+    }
+
+    // Activities of the current page still to be synced, skipped ones are left to the failed downloads
+    pub fn pending_activities(&self) -> Vec<usize> {
+        self.activities
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry.status != ActivitySyncStatus::PostProcessed && entry.error.is_none()
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn add_error(&mut self, act_id: Option<DocumentId>, error: String, now_ts: i64) {
+        self.errors.push(SyncJobError {
+            act_id,
+            error,
+            ts: now_ts,
+        });
+    }
+}
+
+impl crate::data_types::common::Identifiable for SyncJob {
+    fn as_i64(&self) -> i64 {
+        self._id
+    }
+}
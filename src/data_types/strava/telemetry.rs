@@ -1,17 +1,22 @@
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 pub type LatLng = [f32; 2];
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct LatLngData {
     pub data: Vec<LatLng>
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct F32data {
     pub data: Vec<f32>
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BoolData {
+    pub data: Vec<bool>
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Telemetry {
     pub _id: f64,
@@ -34,6 +39,26 @@ pub struct Telemetry {
 
     #[serde(default)] 
     pub time    : F32data,
+
+    // Only present when recorded by the device, missing in telemetry downloaded before extended_streams
+    #[serde(default)]
+    pub heartrate: Option<F32data>,
+
+    #[serde(default)]
+    pub cadence: Option<F32data>,
+
+    #[serde(default)]
+    pub watts: Option<F32data>,
+
+    #[serde(default)]
+    pub temp: Option<F32data>,
+
+    #[serde(default)]
+    pub moving: Option<BoolData>,
+
+    // Set once the streams were requested with the extended keys
+    #[serde(default)]
+    pub extended_streams: bool,
 }
 
 impl crate::data_types::common::Identifiable for Telemetry {
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+use serde_derive::Deserialize;
+
+use crate::data_types::common::DocumentId;
+
+use super::athlete::AthleteId;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectType {
+    Activity,
+    Athlete,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AspectType {
+    Create,
+    Update,
+    Delete,
+}
+
+// Event pushed by Strava to the subscription callback url
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookEvent {
+    pub object_type: ObjectType,
+    pub aspect_type: AspectType,
+    // Activity id or athlete id depending on object_type
+    pub object_id: DocumentId,
+    pub owner_id: AthleteId,
+    pub subscription_id: i64,
+    pub event_time: i64,
+
+    // Changed fields for update events e.g. {"title": "Morning Ride"} or {"authorized": "false"}
+    #[serde(default)]
+    pub updates: HashMap<String, serde_json::Value>,
+}
@@ -1,14 +1,19 @@
-use std::vec;
+use std::{sync::Arc, vec};
 
 use ::mongodb::bson::Document;
 use ::mongodb::bson::{self, doc};
 use ::mongodb::Collection;
+use async_trait::async_trait;
 use mongodb::Client;
 
-use crate::data_types::common::Identifiable;
+use crate::data_types::common::{DocumentId, Identifiable};
 use crate::data_types::gc::route::Route;
 
-use super::mongodb::MongoDatabase;
+use super::{
+    memory::{MemoryRoutes, MemoryStatistics},
+    mongodb::MongoDatabase,
+    storage::{QueryResult, RoutesStorage, StatisticsStorage},
+};
 
 pub struct Routes {
     db_conn: MongoDatabase,
@@ -30,18 +35,23 @@ impl Routes {
     fn typed_collection(&self) -> Collection<Route> {
         self.db_conn.typed_collection(Routes::COLL_NAME)
     }
+}
 
-    pub async fn get_athlete_routes(&self, ath_id: i64) -> mongodb::Cursor<Route> {
-        self.typed_collection()
-            .find(doc! {"athlete_id": ath_id}, None)
+#[async_trait]
+impl RoutesStorage for Routes {
+    async fn get(&self, route_id: DocumentId) -> Option<Route> {
+        self.db_conn
+            .get_one(&self.typed_collection(), route_id)
             .await
-            .unwrap()
     }
 
-    pub async fn get_last_route_id(
-        &self,
-        ath_id: i64,
-    ) -> Option<crate::data_types::common::DocumentId> {
+    async fn get_athlete_routes(&self, ath_id: i64) -> Vec<Route> {
+        self.db_conn
+            .find(&self.typed_collection(), doc! {"athlete_id": ath_id})
+            .await
+    }
+
+    async fn get_last_route_id(&self, ath_id: i64) -> Option<DocumentId> {
         let mut result = self
             .typed_collection()
             .aggregate(
@@ -66,29 +76,62 @@ impl Routes {
         None
     }
 
-    pub async fn clear_routes(&self) {
+    async fn clear_routes(&self) {
         self.typed_collection()
             .delete_many(doc! {}, None)
             .await
             .ok();
     }
 
-    pub async fn delete(&self, route: &Route) -> u64{
+    async fn delete(&self, route: &Route) -> u64{
+        self.db_conn
+            .delete_one(&self.typed_collection(), route._id)
+            .await
+    }
+
+    async fn delete_athlete_routes(&self, ath_id: i64) -> u64 {
         self.typed_collection()
-            .delete_one(doc! {"_id": route._id}, None)
+            .delete_many(doc! {"athlete_id": ath_id}, None)
             .await
-            .unwrap().deleted_count
+            .map_or(0, |result| result.deleted_count)
+    }
+
+    async fn remove_activities(&self, act_ids: &[DocumentId]) -> (u64, u64) {
+        let routes = self
+            .db_conn
+            .find(
+                &self.typed_collection(),
+                doc! {"activities": {"$in": act_ids}},
+            )
+            .await;
+
+        let mut updated = 0;
+        let mut deleted = 0;
+
+        // Only the routes containing the activities are updated or deleted
+        for mut route in routes {
+            route.remove_activities(act_ids);
+
+            if route.activities.is_empty() {
+                deleted += self.delete(&route).await;
+            } else {
+                self.update(&route).await;
+                updated += 1;
+            }
+        }
+
+        (updated, deleted)
     }
 
-    pub async fn update(&self, route: &Route) {
+    async fn update(&self, route: &Route) {
         self.db_conn
             .upsert_one(&self.typed_collection(), route.as_i64(), route)
             .await;
     }
 
-    pub async fn query(&self, stages: Vec<bson::Document>) -> Vec<Route> {
+    async fn query(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Route>> {
         self.db_conn
-            .query(&self.typed_collection(), stages)
+            .try_query(&self.typed_collection(), stages)
             .await
     }
 }
@@ -105,8 +148,11 @@ impl Statistics {
     fn raw_collection(&self) -> Collection<mongodb::bson::Document> {
         self.db_conn.typed_collection(Statistics::COLL_NAME)
     }
+}
 
-    pub async fn query(&self) -> Vec<Document> {
+#[async_trait]
+impl StatisticsStorage for Statistics {
+    async fn query(&self) -> Vec<Document> {
         self.db_conn
             .query(
                 &self.raw_collection(),
@@ -117,23 +163,32 @@ impl Statistics {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct GCDB {
-    pub routes: Routes,
-    pub statistics: Statistics,
+    pub routes: Arc<dyn RoutesStorage>,
+    pub statistics: Arc<dyn StatisticsStorage>,
 }
 
 impl GCDB {
-    pub async fn new(db_url: &str) -> GCDB {
+    pub async fn new(db_url: &str, db_name: &str) -> GCDB {
         let db = Client::with_uri_str(db_url)
             .await
             .unwrap()
-            .database("gc_db");
+            .database(db_name);
 
         let db_coll = MongoDatabase::new(&db);
 
         Self {
-            routes: Routes::new(&db_coll),
-            statistics: Statistics::new(&db_coll),
+            routes: Arc::new(Routes::new(&db_coll)),
+            statistics: Arc::new(Statistics::new(&db_coll)),
+        }
+    }
+
+    // Nothing is persisted, for tests and running without a MongoDB server
+    pub fn in_memory() -> GCDB {
+        Self {
+            routes: Arc::new(MemoryRoutes::default()),
+            statistics: Arc::new(MemoryStatistics::default()),
         }
     }
 }
@@ -0,0 +1,930 @@
+use std::{cmp::Ordering, collections::BTreeMap, sync::Mutex};
+
+use async_trait::async_trait;
+use mongodb::bson::{self, doc, Bson, DateTime, Document};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::data_types::{
+    common::{DocumentId, Identifiable},
+    gc::route::Route,
+    strava::{
+        activity::{Activity, Effort},
+        athlete::{AthleteData, AthleteId, AthleteTokens},
+        failed_download::FailedDownload,
+        segment::Segment,
+        sync_job::SyncJob,
+        telemetry::Telemetry,
+    },
+};
+
+use super::storage::{
+    ActivitiesStorage, AthletesStorage, FailedDownloadsStorage, QueryError, QueryResult,
+    RoutesStorage, SegmentsStorage, StatisticsStorage, SyncJobsStorage, TelemetriesStorage,
+};
+
+// Documents kept as BSON like in MongoDB, filters use the same syntax as the MongoDB collections
+#[derive(Default)]
+pub struct MemoryCollection {
+    documents: Mutex<BTreeMap<DocumentId, Document>>,
+}
+
+impl MemoryCollection {
+    fn get<T: DeserializeOwned>(&self, id: DocumentId) -> Option<T> {
+        let documents = self.documents.lock().unwrap();
+
+        documents
+            .get(&id)
+            .map(|document| bson::from_document(document.clone()).unwrap())
+    }
+
+    fn exists(&self, id: DocumentId) -> bool {
+        self.documents.lock().unwrap().contains_key(&id)
+    }
+
+    // Inserts the document if it doesn't exist, otherwise it replaces it
+    fn upsert<T: Serialize>(&self, id: DocumentId, value: &T) {
+        let document = bson::to_document(value).unwrap();
+
+        self.documents.lock().unwrap().insert(id, document);
+    }
+
+    fn upsert_json(&self, id: DocumentId, json: &mut serde_json::Value) {
+        json["_id"] = serde_json::Value::Number(id.into());
+
+        self.upsert(id, json);
+    }
+
+    fn delete(&self, id: DocumentId) -> u64 {
+        self.documents.lock().unwrap().remove(&id).map_or(0, |_| 1)
+    }
+
+    fn delete_many(&self, filter: &Document) -> u64 {
+        let mut documents = self.documents.lock().unwrap();
+        let count = documents.len();
+
+        documents.retain(|_, document| !MemoryCollection::matches(document, filter));
+
+        (count - documents.len()) as u64
+    }
+
+    fn clear(&self) {
+        self.documents.lock().unwrap().clear();
+    }
+
+    // Matching documents ordered by id
+    fn find_docs(&self, filter: &Document) -> Vec<Document> {
+        self.documents
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|document| MemoryCollection::matches(document, filter))
+            .cloned()
+            .collect()
+    }
+
+    fn find<T: DeserializeOwned>(&self, filter: &Document) -> Vec<T> {
+        self.find_docs(filter)
+            .into_iter()
+            .map(|document| bson::from_document(document).unwrap())
+            .collect()
+    }
+
+    // Results of a query which don't have the fields of T are an error, as they are for MongoDB
+    fn deserialize_all<T: DeserializeOwned>(documents: Vec<Document>) -> QueryResult<Vec<T>> {
+        documents
+            .into_iter()
+            .map(|document| {
+                bson::from_document(document).map_err(|err| QueryError::Database(err.to_string()))
+            })
+            .collect()
+    }
+
+    // Like MongoDatabase::update_field, a '$' in the field stands for the array element matched by key_path
+    fn update_field(&self, key_path: &str, key_value: Bson, field: &str, value: Bson) {
+        let filter = doc! {key_path: key_value.clone()};
+        let mut documents = self.documents.lock().unwrap();
+
+        let Some(document) = documents
+            .values_mut()
+            .find(|document| MemoryCollection::matches(document, &filter))
+        else {
+            return;
+        };
+
+        let Some((array_path, element_field)) = field.split_once(".$.") else {
+            MemoryCollection::set_path(document, field, value);
+            return;
+        };
+
+        let element_key_path = key_path
+            .strip_prefix(array_path)
+            .and_then(|path| path.strip_prefix('.'))
+            .unwrap_or(key_path);
+        let element_filter = doc! {element_key_path: key_value};
+
+        if let Some(Bson::Array(elements)) = MemoryCollection::get_path_mut(document, array_path) {
+            let element = elements.iter_mut().find(|element| match element {
+                Bson::Document(element) => MemoryCollection::matches(element, &element_filter),
+                _ => false,
+            });
+
+            if let Some(Bson::Document(element)) = element {
+                MemoryCollection::set_path(element, element_field, value);
+            }
+        }
+    }
+
+    // Runs the $match, $sort, $skip and $limit stages of an aggregation pipeline, pipelines with
+    // other stages are rejected rather than returning what MongoDB wouldn't
+    fn aggregate(&self, stages: Vec<Document>) -> QueryResult<Vec<Document>> {
+        let mut documents = self.find_docs(&Document::new());
+
+        for stage in stages {
+            let Some((name, argument)) = stage.iter().next() else {
+                continue;
+            };
+
+            match (name.as_str(), argument) {
+                ("$match", Bson::Document(filter)) => {
+                    documents.retain(|document| MemoryCollection::matches(document, filter));
+                }
+                ("$sort", Bson::Document(sort)) => {
+                    documents
+                        .sort_by(|first, second| MemoryCollection::sort_order(first, second, sort));
+                }
+                ("$skip", skip) => {
+                    let skip = MemoryCollection::as_f64(skip).unwrap_or(0.0) as usize;
+                    documents.drain(..skip.min(documents.len()));
+                }
+                ("$limit", limit) => {
+                    documents.truncate(MemoryCollection::as_f64(limit).unwrap_or(0.0) as usize);
+                }
+                _ => return Err(QueryError::UnsupportedStage(name.to_string())),
+            }
+        }
+
+        Ok(documents)
+    }
+
+    fn matches(document: &Document, filter: &Document) -> bool {
+        filter.iter().all(|(path, condition)| {
+            let values = MemoryCollection::field_values(document, path);
+
+            match condition {
+                Bson::Document(operators)
+                    if !operators.is_empty()
+                        && operators.keys().all(|key| key.starts_with('$')) =>
+                {
+                    operators.iter().all(|(operator, operand)| {
+                        MemoryCollection::matches_operator(&values, operator, operand)
+                    })
+                }
+                _ => values
+                    .iter()
+                    .any(|value| MemoryCollection::equals(value, condition)),
+            }
+        })
+    }
+
+    fn matches_operator(values: &[&Bson], operator: &str, operand: &Bson) -> bool {
+        let any = |predicate: &dyn Fn(&Bson) -> bool| values.iter().any(|value| predicate(value));
+        let compares = |expected: &[Ordering]| {
+            any(&|value| {
+                MemoryCollection::compare(value, operand)
+                    .is_some_and(|ordering| expected.contains(&ordering))
+            })
+        };
+        let in_operand = |value: &Bson| match operand {
+            Bson::Array(items) => items
+                .iter()
+                .any(|item| MemoryCollection::equals(value, item)),
+            _ => false,
+        };
+
+        match operator {
+            "$eq" => any(&|value| MemoryCollection::equals(value, operand)),
+            "$ne" => !any(&|value| MemoryCollection::equals(value, operand)),
+            "$in" => any(&in_operand),
+            "$nin" => !any(&in_operand),
+            "$gt" => compares(&[Ordering::Greater]),
+            "$gte" => compares(&[Ordering::Greater, Ordering::Equal]),
+            "$lt" => compares(&[Ordering::Less]),
+            "$lte" => compares(&[Ordering::Less, Ordering::Equal]),
+            "$exists" => values.is_empty() != operand.as_bool().unwrap_or(true),
+            "$size" => any(&|value| match value {
+                Bson::Array(items) => Some(items.len() as f64) == MemoryCollection::as_f64(operand),
+                _ => false,
+            }),
+            _ => false,
+        }
+    }
+
+    // Values at a dotted path, arrays on the way are searched element by element.
+    // An array at the end of the path yields its elements and the array itself, as in MongoDB queries
+    fn field_values<'a>(document: &'a Document, path: &str) -> Vec<&'a Bson> {
+        fn collect<'a>(value: &'a Bson, path: &[&str], values: &mut Vec<&'a Bson>) {
+            match (path.split_first(), value) {
+                (None, Bson::Array(items)) => {
+                    values.extend(items.iter());
+                    values.push(value);
+                }
+                (None, _) => values.push(value),
+                (Some((key, rest)), Bson::Document(document)) => {
+                    if let Some(value) = document.get(*key) {
+                        collect(value, rest, values);
+                    }
+                }
+                (Some(_), Bson::Array(items)) => {
+                    items.iter().for_each(|item| collect(item, path, values));
+                }
+                _ => {}
+            }
+        }
+
+        let path: Vec<&str> = path.split('.').collect();
+        let mut values = Vec::new();
+
+        if let Some(value) = document.get(path[0]) {
+            collect(value, &path[1..], &mut values);
+        }
+
+        values
+    }
+
+    fn get_path_mut<'a>(document: &'a mut Document, path: &str) -> Option<&'a mut Bson> {
+        let mut keys = path.split('.');
+        let mut value = document.get_mut(keys.next()?)?;
+
+        for key in keys {
+            value = value.as_document_mut()?.get_mut(key)?;
+        }
+
+        Some(value)
+    }
+
+    fn set_path(document: &mut Document, path: &str, value: Bson) {
+        match path.split_once('.') {
+            Some((key, rest)) => {
+                if !matches!(document.get(key), Some(Bson::Document(_))) {
+                    document.insert(key, Document::new());
+                }
+
+                if let Some(Bson::Document(child)) = document.get_mut(key) {
+                    MemoryCollection::set_path(child, rest, value);
+                }
+            }
+            None => {
+                document.insert(path, value);
+            }
+        }
+    }
+
+    // Missing fields sort first, as null does in MongoDB
+    fn sort_order(first: &Document, second: &Document, sort: &Document) -> Ordering {
+        for (path, direction) in sort {
+            let first_value = MemoryCollection::field_values(first, path).first().copied();
+            let second_value = MemoryCollection::field_values(second, path)
+                .first()
+                .copied();
+
+            let ordering = match (first_value, second_value) {
+                (Some(first_value), Some(second_value)) => {
+                    MemoryCollection::compare(first_value, second_value).unwrap_or(Ordering::Equal)
+                }
+                (first_value, second_value) => first_value.is_some().cmp(&second_value.is_some()),
+            };
+
+            let ordering = if MemoryCollection::as_f64(direction).unwrap_or(1.0) < 0.0 {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+
+    fn as_f64(value: &Bson) -> Option<f64> {
+        match value {
+            Bson::Int32(value) => Some(*value as f64),
+            Bson::Int64(value) => Some(*value as f64),
+            Bson::Double(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn as_i64(value: &Bson) -> Option<i64> {
+        match value {
+            Bson::Int32(value) => Some(*value as i64),
+            Bson::Int64(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    // Numbers are compared by value whatever their BSON type, ids are stored as both Int64 and Double
+    fn equals(first: &Bson, second: &Bson) -> bool {
+        MemoryCollection::compare(first, second).map_or(first == second, Ordering::is_eq)
+    }
+
+    // Integers are compared exactly, large ids like lap ids are not representable as f64
+    fn compare(first: &Bson, second: &Bson) -> Option<Ordering> {
+        if let (Some(first), Some(second)) = (
+            MemoryCollection::as_i64(first),
+            MemoryCollection::as_i64(second),
+        ) {
+            return Some(first.cmp(&second));
+        }
+
+        if let (Some(first), Some(second)) = (
+            MemoryCollection::as_f64(first),
+            MemoryCollection::as_f64(second),
+        ) {
+            return first.partial_cmp(&second);
+        }
+
+        match (first, second) {
+            (Bson::String(first), Bson::String(second)) => Some(first.cmp(second)),
+            (Bson::DateTime(first), Bson::DateTime(second)) => Some(first.cmp(second)),
+            (Bson::Boolean(first), Bson::Boolean(second)) => Some(first.cmp(second)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryActivities {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl ActivitiesStorage for MemoryActivities {
+    async fn delete(&self, id: i64) -> u64 {
+        self.collection.delete(id)
+    }
+
+    async fn delete_athlete_activities(&self, ath_id: i64) -> u64 {
+        self.collection.delete_many(&doc! {"athlete.id": ath_id})
+    }
+
+    async fn get(&self, id: i64) -> Option<Activity> {
+        self.collection.get(id)
+    }
+
+    async fn get_athlete_activities(&self, ath_id: i64) -> Vec<Activity> {
+        self.collection.find(&doc! {"athlete.id": ath_id})
+    }
+
+    async fn get_athlete_activities_with_ids(
+        &self,
+        ath_id: i64,
+        ids: &[DocumentId],
+    ) -> Vec<Activity> {
+        self.collection
+            .find(&doc! {"athlete.id": ath_id, "_id": {"$in": ids}})
+    }
+
+    async fn get_athlete_activity_ids(&self, ath_id: i64) -> Vec<DocumentId> {
+        self.get_athlete_activities(ath_id)
+            .await
+            .iter()
+            .map(|activity| activity.as_i64())
+            .collect()
+    }
+
+    async fn get_athlete_activity_ids_sorted_distance_asc(&self, ath_id: i64) -> Vec<DocumentId> {
+        self.query_activities(vec![
+            doc! {"$match": {"athlete.id": ath_id}},
+            doc! {"$sort": { "distance": 1 } },
+        ])
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|activity| activity.as_i64())
+        .collect()
+    }
+
+    async fn get_athlete_segment_efforts(&self, ath_id: i64, seg_id: i64) -> Vec<Effort> {
+        let mut efforts: Vec<Effort> = self
+            .collection
+            .find::<Activity>(&doc! {"athlete.id": ath_id, "segment_efforts.segment.id": seg_id})
+            .into_iter()
+            .flat_map(|activity| activity.segment_efforts)
+            .filter(|effort| effort.segment.id == seg_id)
+            .collect();
+
+        efforts.sort_by(|first, second| first.start_date_local.cmp(&second.start_date_local));
+
+        efforts
+    }
+
+    async fn exists(&self, act_id: i64) -> bool {
+        self.collection.exists(act_id)
+    }
+
+    async fn set_location_city(&self, act_id: i64, city: &Option<String>) {
+        self.collection.update_field(
+            "_id",
+            act_id.into(),
+            "location_city",
+            bson::to_bson(city).unwrap(),
+        );
+    }
+
+    async fn set_location_country(&self, act_id: i64, country: &str) {
+        self.collection
+            .update_field("_id", act_id.into(), "location_country", country.into());
+    }
+
+    async fn set_segment_start_index_poly(&self, seg_id: i64, start_index_poly: &Option<i32>) {
+        if let Some(start_index) = start_index_poly {
+            self.collection.update_field(
+                "segment_efforts.id",
+                seg_id.into(),
+                "segment_efforts.$.start_index_poly",
+                (*start_index).into(),
+            );
+        }
+    }
+
+    async fn set_segment_end_index_poly(&self, seg_id: i64, end_index_poly: &Option<i32>) {
+        if let Some(end_index) = end_index_poly {
+            self.collection.update_field(
+                "segment_efforts.id",
+                seg_id.into(),
+                "segment_efforts.$.end_index_poly",
+                (*end_index).into(),
+            );
+        }
+    }
+
+    async fn set_lap_indexes_poly(&self, lap_id: i64, start_index_poly: i32, end_index_poly: i32) {
+        self.collection.update_field(
+            "laps.id",
+            lap_id.into(),
+            "laps.$.start_index_poly",
+            start_index_poly.into(),
+        );
+
+        self.collection.update_field(
+            "laps.id",
+            lap_id.into(),
+            "laps.$.end_index_poly",
+            end_index_poly.into(),
+        );
+    }
+
+    async fn set_segment_distance_from_start(&self, seg_id: i64, distance_from_start: f32) {
+        self.collection.update_field(
+            "segment_efforts.id",
+            seg_id.into(),
+            "segment_efforts.$.distance_from_start",
+            distance_from_start.into(),
+        );
+    }
+
+    async fn set_start_date_local_date(
+        &self,
+        act_id: i64,
+        start_date_local_date: &Option<DateTime>,
+    ) {
+        if let Some(start_date_local_date) = start_date_local_date {
+            self.collection.update_field(
+                "_id",
+                act_id.into(),
+                "start_date_local_date",
+                (*start_date_local_date).into(),
+            );
+        }
+    }
+
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
+        self.collection.upsert_json(act_id, json);
+    }
+
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>) {
+        for (act_id, mut json) in documents {
+            self.collection.upsert_json(act_id, &mut json);
+        }
+    }
+
+    async fn query_activities(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Activity>> {
+        MemoryCollection::deserialize_all(self.collection.aggregate(stages)?)
+    }
+
+    async fn query_activities_docs(
+        &self,
+        stages: Vec<bson::Document>,
+    ) -> QueryResult<Vec<bson::Document>> {
+        self.collection.aggregate(stages)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTelemetries {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl TelemetriesStorage for MemoryTelemetries {
+    async fn get(&self, id: i64) -> Option<Telemetry> {
+        self.collection.get(id)
+    }
+
+    async fn exists(&self, act_id: i64) -> bool {
+        self.collection.exists(act_id)
+    }
+
+    async fn delete_athlete_telemetries(&self, ath_id: i64) -> u64 {
+        self.collection.delete_many(&doc! {"athlete.id": ath_id})
+    }
+
+    async fn get_athlete_ids_without_extended_streams(&self, ath_id: i64) -> Vec<DocumentId> {
+        let documents = self
+            .collection
+            .find_docs(&doc! {"athlete.id": ath_id, "extended_streams": {"$ne": true}});
+
+        documents
+            .iter()
+            .filter_map(|document| document.get("_id").and_then(MemoryCollection::as_f64))
+            .map(|act_id| act_id as DocumentId)
+            .collect()
+    }
+
+    async fn set_type(&self, act_id: i64, r#type: &str) {
+        self.collection
+            .update_field("_id", act_id.into(), "type", r#type.into());
+    }
+
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
+        self.collection.upsert_json(act_id, json);
+    }
+
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>) {
+        for (act_id, mut json) in documents {
+            self.collection.upsert_json(act_id, &mut json);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryAthletes {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl AthletesStorage for MemoryAthletes {
+    async fn get_athlete_data(&self, id: i64) -> Option<AthleteData> {
+        self.collection.get(id)
+    }
+
+    async fn get_athlete_ids(&self) -> Vec<AthleteId> {
+        self.collection
+            .find::<AthleteData>(&Document::new())
+            .iter()
+            .map(|athlete_data| athlete_data.as_i64())
+            .collect()
+    }
+
+    async fn delete(&self, id: i64) -> u64 {
+        self.collection.delete(id)
+    }
+
+    async fn set_athlete_data(&self, athlete_data: &AthleteData) {
+        self.collection.upsert(athlete_data.as_i64(), athlete_data);
+    }
+
+    async fn set_athlete_tokens(&self, id: i64, athlete_tokens: &AthleteTokens) {
+        self.collection.update_field(
+            "_id",
+            id.into(),
+            "tokens",
+            bson::to_bson(athlete_tokens).unwrap(),
+        );
+    }
+
+    async fn save_after_before_timestamps(&self, ath_id: i64, after_ts: i64, before_ts: i64) {
+        self.collection
+            .update_field("_id", ath_id.into(), "before_ts", before_ts.into());
+        self.collection
+            .update_field("_id", ath_id.into(), "after_ts", after_ts.into());
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryFailedDownloads {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl FailedDownloadsStorage for MemoryFailedDownloads {
+    async fn get(&self, act_id: i64) -> Option<FailedDownload> {
+        self.collection.get(act_id)
+    }
+
+    async fn get_athlete_failed_downloads(&self, ath_id: i64) -> Vec<FailedDownload> {
+        self.collection.find(&doc! {"athlete_id": ath_id})
+    }
+
+    async fn store(&self, failed_download: &FailedDownload) {
+        self.collection
+            .upsert(failed_download.as_i64(), failed_download);
+    }
+
+    async fn delete(&self, act_id: i64) -> u64 {
+        self.collection.delete(act_id)
+    }
+
+    async fn delete_athlete_failed_downloads(&self, ath_id: i64) -> u64 {
+        self.collection.delete_many(&doc! {"athlete_id": ath_id})
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySyncJobs {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl SyncJobsStorage for MemorySyncJobs {
+    async fn get(&self, ath_id: i64) -> Option<SyncJob> {
+        self.collection.get(ath_id)
+    }
+
+    async fn store(&self, sync_job: &SyncJob) {
+        self.collection.upsert(sync_job.as_i64(), sync_job);
+    }
+
+    async fn delete(&self, ath_id: i64) -> u64 {
+        self.collection.delete(ath_id)
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySegments {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl SegmentsStorage for MemorySegments {
+    async fn get(&self, seg_id: i64) -> Option<Segment> {
+        self.collection.get(seg_id)
+    }
+
+    async fn exists(&self, seg_id: i64) -> bool {
+        self.collection.exists(seg_id)
+    }
+
+    async fn store(&self, seg_id: i64, json: &mut serde_json::Value) {
+        self.collection.upsert_json(seg_id, json);
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRoutes {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl RoutesStorage for MemoryRoutes {
+    async fn get(&self, route_id: DocumentId) -> Option<Route> {
+        self.collection.get(route_id)
+    }
+
+    async fn get_athlete_routes(&self, ath_id: i64) -> Vec<Route> {
+        self.collection.find(&doc! {"athlete_id": ath_id})
+    }
+
+    async fn get_last_route_id(&self, ath_id: i64) -> Option<DocumentId> {
+        self.get_athlete_routes(ath_id)
+            .await
+            .iter()
+            .map(|route| route.as_i64())
+            .max()
+    }
+
+    async fn clear_routes(&self) {
+        self.collection.clear();
+    }
+
+    async fn delete(&self, route: &Route) -> u64 {
+        self.collection.delete(route.as_i64())
+    }
+
+    async fn delete_athlete_routes(&self, ath_id: i64) -> u64 {
+        self.collection.delete_many(&doc! {"athlete_id": ath_id})
+    }
+
+    async fn remove_activities(&self, act_ids: &[DocumentId]) -> (u64, u64) {
+        let routes: Vec<Route> = self.collection.find(&doc! {"activities": {"$in": act_ids}});
+
+        let mut updated = 0;
+        let mut deleted = 0;
+
+        // Only the routes containing the activities are updated or deleted
+        for mut route in routes {
+            route.remove_activities(act_ids);
+
+            if route.activities.is_empty() {
+                deleted += self.delete(&route).await;
+            } else {
+                self.update(&route).await;
+                updated += 1;
+            }
+        }
+
+        (updated, deleted)
+    }
+
+    async fn update(&self, route: &Route) {
+        self.collection.upsert(route.as_i64(), route);
+    }
+
+    async fn query(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Route>> {
+        MemoryCollection::deserialize_all(self.collection.aggregate(stages)?)
+    }
+}
+
+// Statistics are computed outside of this service, in memory there are none
+#[derive(Default)]
+pub struct MemoryStatistics {
+    collection: MemoryCollection,
+}
+
+#[async_trait]
+impl StatisticsStorage for MemoryStatistics {
+    async fn query(&self) -> Vec<Document> {
+        self.collection
+            .aggregate(vec![doc! { "$match": { "_id": 0 } }])
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{export::geojson::GeoJsonWriter, import::tcx::TcxParser};
+
+    const TWO_LAPS: &str = include_str!("../../fixtures/import/two_laps.tcx");
+
+    // The imported fixture with one effort over its second lap, polyline indexes not set yet
+    fn activity_with_effort(act_id: DocumentId) -> (serde_json::Value, String) {
+        let recorded = TcxParser::parse(TWO_LAPS).unwrap();
+        let polyline = recorded.track.polyline();
+        let details = recorded.activity_details(1, TWO_LAPS.as_bytes(), "TCX", None, None);
+        let mut activity = details.to_activity_json(&recorded.summary(), &polyline, 1);
+
+        activity["id"] = act_id.into();
+        activity["segment_efforts"] = json!([{
+            "id": 11,
+            "athlete": { "id": 1 },
+            "activity": { "id": act_id },
+            "name": "Back straight",
+            "segment": {
+                "id": 12,
+                "average_grade": 1.5,
+                "maximum_grade": 2.0,
+                "distance": 333.0,
+                "city": null,
+                "country": null,
+            },
+            "moving_time": 20,
+            "start_index": 4,
+            "end_index": 6,
+            "start_date_local": "2023-05-01T06:00:40Z",
+            "start_index_poly": null,
+            "end_index_poly": null,
+            "distance_from_start": null,
+        }]);
+
+        (activity, polyline)
+    }
+
+    #[tokio::test]
+    async fn exported_routes_include_efforts_with_polyline_indexes() {
+        let activities = MemoryActivities::default();
+        let (mut activity, polyline) = activity_with_effort(7);
+
+        activities.store(7, &mut activity).await;
+        activities.set_segment_start_index_poly(11, &Some(4)).await;
+        activities.set_segment_end_index_poly(11, &Some(6)).await;
+
+        let activity = activities.get(7).await.unwrap();
+        let effort = &activity.segment_efforts[0];
+        assert_eq!(
+            (effort.start_index_poly, effort.end_index_poly),
+            (Some(4), Some(6))
+        );
+
+        let route = Route {
+            _id: 3.,
+            master_activity_id: 7,
+            activities: vec![7],
+            polyline,
+            ..Default::default()
+        };
+        let geojson = GeoJsonWriter::write_route(&route, Some(&activity));
+        let efforts: Vec<&serde_json::Value> = geojson["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|feature| feature["properties"]["kind"] == "segment_effort")
+            .collect();
+
+        assert_eq!(efforts.len(), 1);
+        assert_eq!(efforts[0]["properties"]["segment_id"], 12);
+        assert_eq!(efforts[0]["properties"]["end_index_poly"], 6);
+        assert_eq!(
+            efforts[0]["geometry"]["coordinates"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn imported_laps_get_their_own_polyline_indexes() {
+        let activities = MemoryActivities::default();
+        let recorded = TcxParser::parse(TWO_LAPS).unwrap();
+        let details = recorded.activity_details(1, TWO_LAPS.as_bytes(), "TCX", None, None);
+        let mut activity = details.to_activity_json(&recorded.summary(), "", 1);
+
+        // Lap ids of synthetic activities are too large to be told apart as f64
+        activities.store(details.id, &mut activity).await;
+        activities
+            .set_lap_indexes_poly(details.laps[1].id, 4, 6)
+            .await;
+
+        let laps = activities.get(details.id).await.unwrap().laps;
+        assert_eq!(
+            laps.iter()
+                .map(|lap| (lap.start_index_poly, lap.end_index_poly))
+                .collect::<Vec<_>>(),
+            vec![(None, None), (Some(4), Some(6))]
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_filter_sort_and_limit_like_mongodb() {
+        let activities = MemoryActivities::default();
+
+        for (act_id, distance) in [(1, 500.), (2, 1500.), (3, 1000.)] {
+            let (mut activity, _) = activity_with_effort(act_id);
+            activity["distance"] = distance.into();
+            activities.store(act_id, &mut activity).await;
+        }
+
+        let queried = activities
+            .query_activities(vec![
+                doc! { "$match": { "id": { "$in": [1_i64, 2_i64, 3_i64] }, "distance": { "$gte": 1000 } } },
+                doc! { "$sort": { "distance": -1 } },
+                doc! { "$limit": 1 },
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            queried
+                .iter()
+                .map(|activity| activity.as_i64())
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_stages_are_errors() {
+        let activities = MemoryActivities::default();
+        let (mut activity, _) = activity_with_effort(1);
+        activities.store(1, &mut activity).await;
+
+        let result = activities
+            .query_activities_docs(vec![doc! { "$group": { "_id": "$type" } }])
+            .await;
+
+        assert!(matches!(result, Err(QueryError::UnsupportedStage(stage)) if stage == "$group"));
+    }
+
+    #[test]
+    fn large_integers_are_compared_exactly() {
+        // Both round to the same f64
+        let (first, second) = (
+            Bson::Int64(9_007_199_254_740_993),
+            Bson::Int64(9_007_199_254_740_992),
+        );
+
+        assert!(!MemoryCollection::equals(&first, &second));
+        assert_eq!(
+            MemoryCollection::compare(&first, &second),
+            Some(Ordering::Greater)
+        );
+        assert!(MemoryCollection::equals(&Bson::Int32(7), &Bson::Int64(7)));
+        assert!(MemoryCollection::equals(&Bson::Int64(7), &Bson::Double(7.)));
+    }
+}
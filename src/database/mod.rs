@@ -1,4 +1,31 @@
+use std::str::FromStr;
+
+use serde_derive::Deserialize;
+
 pub (crate) mod mongodb;
 
 pub mod strava_db;
-pub mod gc_db;
\ No newline at end of file
+pub mod gc_db;
+pub mod memory;
+pub mod storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    #[default]
+    Mongodb,
+    // Kept in the process and lost when it exits, no MongoDB server is needed
+    Memory,
+}
+
+impl FromStr for DatabaseBackend {
+    type Err = ();
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "mongodb" => Ok(DatabaseBackend::Mongodb),
+            "memory" => Ok(DatabaseBackend::Memory),
+            _ => Err(()),
+        }
+    }
+}
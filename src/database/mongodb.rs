@@ -1,7 +1,15 @@
-use crate::{data_types::common::DocumentId, database::mongodb::bson::Bson};
+use crate::{
+    data_types::common::DocumentId,
+    database::{
+        mongodb::bson::Bson,
+        storage::{QueryError, QueryResult},
+    },
+    logln,
+};
 use mongodb::{
     bson::{self, doc, Document},
-    options::{FindOptions, ReplaceOptions},
+    error::{BulkWriteFailure, ErrorKind},
+    options::{FindOptions, InsertManyOptions, ReplaceOptions},
     Collection, Database,
 };
 use serde::de::DeserializeOwned;
@@ -13,6 +21,9 @@ pub struct MongoDatabase {
 }
 
 impl MongoDatabase {
+    const CC: &str = "MongoDB";
+    const DUPLICATE_KEY: i32 = 11000;
+
     pub fn new(db: &Database) -> Self {
         Self {
             database: db.clone(),
@@ -42,14 +53,54 @@ impl MongoDatabase {
             .ok();
 
         if let Some(mut find_data) = find_res {
-            if find_data.advance().await.unwrap() {
-                return Some(find_data.deserialize_current().unwrap());
+            match find_data.advance().await {
+                Ok(true) => match find_data.deserialize_current() {
+                    Ok(doc) => return Some(doc),
+                    Err(err) => logln!("Unable to read document of {}, {}", collection.name(), err),
+                },
+                Ok(false) => {}
+                Err(err) => logln!("Unable to read {}, {}", collection.name(), err),
             }
         }
 
         None
     }
 
+    // Document with the id, errors are logged and reported as a missing document
+    pub async fn get_one<KT, T: DeserializeOwned + Unpin + Send + Sync>(
+        &self,
+        collection: &Collection<T>,
+        id: KT,
+    ) -> Option<T>
+    where
+        Bson: From<KT>,
+    {
+        collection
+            .find_one(doc! {"_id": id}, None)
+            .await
+            .unwrap_or_else(|err| {
+                logln!("Unable to read {}, {}", collection.name(), err);
+                None
+            })
+    }
+
+    // Returns the number of deleted documents, 0 when the database failed
+    pub async fn delete_one<KT, T>(&self, collection: &Collection<T>, id: KT) -> u64
+    where
+        Bson: From<KT>,
+    {
+        collection
+            .delete_one(doc! {"_id": id}, None)
+            .await
+            .map_or_else(
+                |err| {
+                    logln!("Unable to delete from {}, {}", collection.name(), err);
+                    0
+                },
+                |result| result.deleted_count,
+            )
+    }
+
     // Inserts doc_id if it doesn't exist, otherwise it replaces it
     pub async fn upsert_one<T: DeserializeOwned + Unpin + Send + Sync + serde::Serialize>(
         &self,
@@ -67,6 +118,77 @@ impl MongoDatabase {
             .ok();
     }
 
+    // Inserts a batch of documents in one unordered request, the ones already stored fail with a
+    // duplicate key error and are then replaced one by one, so a batch of new documents costs a
+    // single round trip and re-synced ones cost one more each
+    pub async fn upsert_many(&self, collection: &Collection<Document>, docs: Vec<Document>) {
+        let (docs, without_id): (Vec<Document>, Vec<Document>) =
+            docs.into_iter().partition(|doc| doc.contains_key("_id"));
+
+        if !without_id.is_empty() {
+            logln!(
+                "Unable to store {} documents without _id in {}",
+                without_id.len(),
+                collection.name()
+            );
+        }
+
+        if docs.is_empty() {
+            return;
+        }
+
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let existing: Vec<&Document> = match collection.insert_many(&docs, options).await {
+            Ok(_) => return,
+            Err(err) => match *err.kind {
+                ErrorKind::BulkWrite(BulkWriteFailure {
+                    write_errors: Some(ref write_errors),
+                    write_concern_error: None,
+                    ..
+                }) => write_errors
+                    .iter()
+                    .filter_map(|write_error| {
+                        if write_error.code == Self::DUPLICATE_KEY {
+                            docs.get(write_error.index)
+                        } else {
+                            logln!(
+                                "Unable to store document in {}, {}",
+                                collection.name(),
+                                write_error.message
+                            );
+                            None
+                        }
+                    })
+                    .collect(),
+                _ => {
+                    logln!(
+                        "Unable to store documents in {}, {}",
+                        collection.name(),
+                        err
+                    );
+                    return;
+                }
+            },
+        };
+
+        let upserts = existing.into_iter().map(|doc| async move {
+            collection
+                .replace_one(
+                    doc! {"_id": doc.get("_id").unwrap().clone()},
+                    doc,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map_err(|err| format!("{}: {}", doc.get("_id").unwrap(), err))
+        });
+
+        for result in futures_util::future::join_all(upserts).await {
+            if let Err(err) = result {
+                logln!("Unable to store document in {}, {}", collection.name(), err);
+            }
+        }
+    }
+
     pub async fn update_field<KT, T: DeserializeOwned + Unpin + Send + Sync, V>(
         &self,
         key_path: String,
@@ -100,13 +222,69 @@ impl MongoDatabase {
     {
         let found = collection.find_one(Some(doc! {"_id": id}), None).await;
 
-        if let Ok(search_op) = found {
-            if let Some(_) = search_op {
-                return true;
+        matches!(found, Ok(Some(_)))
+    }
+
+    // Every document matching the filter, for collections read whole like an athlete's routes
+    pub async fn find<T: DeserializeOwned + Unpin + Send + Sync>(
+        &self,
+        collection: &Collection<T>,
+        filter: Document,
+    ) -> Vec<T> {
+        let mut results: Vec<T> = Vec::new();
+
+        let mut cursor = match collection.find(filter, None).await {
+            Ok(cursor) => cursor,
+            Err(err) => {
+                logln!("Unable to read {}, {}", collection.name(), err);
+                return results;
+            }
+        };
+
+        // Documents which can't be read are skipped, a failing cursor ends the results
+        loop {
+            match cursor.advance().await {
+                Ok(true) => match cursor.deserialize_current() {
+                    Ok(doc) => results.push(doc),
+                    Err(err) => logln!("Unable to read document of {}, {}", collection.name(), err),
+                },
+                Ok(false) => break,
+                Err(err) => {
+                    logln!("Unable to read {}, {}", collection.name(), err);
+                    break;
+                }
             }
         }
 
-        return false;
+        results
+    }
+
+    // Like query, but errors of the database and documents not fitting T are returned
+    pub async fn try_query<T: DeserializeOwned + Unpin + Send + Sync>(
+        &self,
+        collection: &Collection<T>,
+        stages: Vec<bson::Document>,
+    ) -> QueryResult<Vec<T>> {
+        let database_error = |err: mongodb::error::Error| QueryError::Database(err.to_string());
+        let mut results: Vec<T> = Vec::new();
+
+        let mut aggregate_res = collection
+            .aggregate(stages, None)
+            .await
+            .map_err(database_error)?;
+
+        while aggregate_res.advance().await.map_err(database_error)? {
+            let doc = aggregate_res
+                .deserialize_current()
+                .map_err(database_error)?;
+
+            results.push(
+                bson::from_bson(bson::Bson::Document(doc))
+                    .map_err(|err| QueryError::Database(err.to_string()))?,
+            );
+        }
+
+        Ok(results)
     }
 
     // To be used with limit as it returns Vec
@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use mongodb::bson::{self, DateTime, Document};
+
+use crate::data_types::{
+    common::DocumentId,
+    gc::route::Route,
+    strava::{
+        activity::{Activity, Effort},
+        athlete::{AthleteData, AthleteId, AthleteTokens},
+        failed_download::FailedDownload,
+        segment::Segment,
+        sync_job::SyncJob,
+        telemetry::Telemetry,
+    },
+};
+
+// Aggregation pipeline which could not be run
+#[derive(Debug, Clone)]
+pub enum QueryError {
+    // Stage the in-memory backend can't run
+    UnsupportedStage(String),
+    // Rejected by the database or documents not in the expected shape
+    Database(String),
+}
+
+pub type QueryResult<T> = Result<T, QueryError>;
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::UnsupportedStage(stage) => {
+                write!(f, "{} stage is not supported in memory", stage)
+            }
+            QueryError::Database(err) => write!(f, "query failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+// Collections used by the processors, implemented on MongoDB and in memory
+
+#[async_trait]
+pub trait ActivitiesStorage: Send + Sync {
+    // DELETE
+    async fn delete(&self, id: i64) -> u64;
+    async fn delete_athlete_activities(&self, ath_id: i64) -> u64;
+
+    // GETTERS
+    async fn get(&self, id: i64) -> Option<Activity>;
+    async fn get_athlete_activities(&self, ath_id: i64) -> Vec<Activity>;
+    async fn get_athlete_activities_with_ids(
+        &self,
+        ath_id: i64,
+        ids: &[DocumentId],
+    ) -> Vec<Activity>;
+    async fn get_athlete_activity_ids(&self, ath_id: i64) -> Vec<DocumentId>;
+    async fn get_athlete_activity_ids_sorted_distance_asc(&self, ath_id: i64) -> Vec<DocumentId>;
+    // All efforts of the athlete on the segment, oldest first
+    async fn get_athlete_segment_efforts(&self, ath_id: i64, seg_id: i64) -> Vec<Effort>;
+    async fn exists(&self, act_id: i64) -> bool;
+
+    // SETTERS
+    async fn set_location_city(&self, act_id: i64, city: &Option<String>);
+    async fn set_location_country(&self, act_id: i64, country: &str);
+    async fn set_segment_start_index_poly(&self, seg_id: i64, start_index_poly: &Option<i32>);
+    async fn set_segment_end_index_poly(&self, seg_id: i64, end_index_poly: &Option<i32>);
+    async fn set_lap_indexes_poly(&self, lap_id: i64, start_index_poly: i32, end_index_poly: i32);
+    async fn set_segment_distance_from_start(&self, seg_id: i64, distance_from_start: f32);
+    async fn set_start_date_local_date(
+        &self,
+        act_id: i64,
+        start_date_local_date: &Option<DateTime>,
+    );
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value);
+    // Documents downloaded together are written in one batch
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>);
+
+    // Aggregation pipelines, the in-memory backend only runs $match, $sort, $skip and $limit
+    async fn query_activities(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Activity>>;
+    async fn query_activities_docs(
+        &self,
+        stages: Vec<bson::Document>,
+    ) -> QueryResult<Vec<bson::Document>>;
+}
+
+#[async_trait]
+pub trait TelemetriesStorage: Send + Sync {
+    async fn get(&self, id: i64) -> Option<Telemetry>;
+    async fn exists(&self, act_id: i64) -> bool;
+    async fn delete_athlete_telemetries(&self, ath_id: i64) -> u64;
+    // Telemetries downloaded before the heartrate, cadence, watts, temp and moving streams were requested
+    async fn get_athlete_ids_without_extended_streams(&self, ath_id: i64) -> Vec<DocumentId>;
+    async fn set_type(&self, act_id: i64, r#type: &str);
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value);
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>);
+}
+
+#[async_trait]
+pub trait AthletesStorage: Send + Sync {
+    async fn get_athlete_data(&self, id: i64) -> Option<AthleteData>;
+    async fn get_athlete_ids(&self) -> Vec<AthleteId>;
+    async fn delete(&self, id: i64) -> u64;
+    async fn set_athlete_data(&self, athlete_data: &AthleteData);
+    async fn set_athlete_tokens(&self, id: i64, athlete_tokens: &AthleteTokens);
+    async fn save_after_before_timestamps(&self, ath_id: i64, after_ts: i64, before_ts: i64);
+}
+
+#[async_trait]
+pub trait FailedDownloadsStorage: Send + Sync {
+    async fn get(&self, act_id: i64) -> Option<FailedDownload>;
+    async fn get_athlete_failed_downloads(&self, ath_id: i64) -> Vec<FailedDownload>;
+    async fn store(&self, failed_download: &FailedDownload);
+    async fn delete(&self, act_id: i64) -> u64;
+    async fn delete_athlete_failed_downloads(&self, ath_id: i64) -> u64;
+}
+
+#[async_trait]
+pub trait SyncJobsStorage: Send + Sync {
+    // Jobs are keyed by athlete, only the latest one is kept
+    async fn get(&self, ath_id: i64) -> Option<SyncJob>;
+    async fn store(&self, sync_job: &SyncJob);
+    async fn delete(&self, ath_id: i64) -> u64;
+}
+
+#[async_trait]
+pub trait SegmentsStorage: Send + Sync {
+    async fn get(&self, seg_id: i64) -> Option<Segment>;
+    async fn exists(&self, seg_id: i64) -> bool;
+    async fn store(&self, seg_id: i64, json: &mut serde_json::Value);
+}
+
+#[async_trait]
+pub trait RoutesStorage: Send + Sync {
+    async fn get(&self, route_id: DocumentId) -> Option<Route>;
+    async fn get_athlete_routes(&self, ath_id: i64) -> Vec<Route>;
+    async fn get_last_route_id(&self, ath_id: i64) -> Option<DocumentId>;
+    async fn clear_routes(&self);
+    async fn delete(&self, route: &Route) -> u64;
+    async fn delete_athlete_routes(&self, ath_id: i64) -> u64;
+    // Removes the activities from every route containing them, routes left empty are deleted
+    // and routes which lost their master are marked for the route processor
+    // returns (updated routes, deleted routes)
+    async fn remove_activities(&self, act_ids: &[DocumentId]) -> (u64, u64);
+    async fn update(&self, route: &Route);
+    async fn query(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Route>>;
+}
+
+#[async_trait]
+pub trait StatisticsStorage: Send + Sync {
+    async fn query(&self) -> Vec<Document>;
+}
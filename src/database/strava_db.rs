@@ -1,19 +1,35 @@
-use std::borrow::Borrow;
+use std::{borrow::Borrow, sync::Arc};
+
+use async_trait::async_trait;
 
 use crate::data_types::{
     common::{DocumentId, Identifiable},
     strava::{
-        activity::Activity,
-        athlete::{AthleteData, AthleteTokens},
+        activity::{Activity, Effort},
+        athlete::{AthleteData, AthleteId, AthleteTokens},
+        failed_download::FailedDownload,
+        segment::Segment,
+        sync_job::SyncJob,
         telemetry::Telemetry,
     },
 };
 use mongodb::{
     bson::{self, doc, DateTime},
+    options::FindOptions,
     Client, Collection,
 };
 
-use super::mongodb::MongoDatabase;
+use super::{
+    memory::{
+        MemoryActivities, MemoryAthletes, MemoryFailedDownloads, MemorySegments, MemorySyncJobs,
+        MemoryTelemetries,
+    },
+    mongodb::MongoDatabase,
+    storage::{
+        ActivitiesStorage, AthletesStorage, FailedDownloadsStorage, QueryResult, SegmentsStorage,
+        SyncJobsStorage, TelemetriesStorage,
+    },
+};
 
 pub struct ActivitiesCollection {
     db_conn: MongoDatabase,
@@ -27,6 +43,18 @@ pub struct AthletesCollection {
     db_conn: MongoDatabase,
 }
 
+pub struct FailedDownloadsCollection {
+    db_conn: MongoDatabase,
+}
+
+pub struct SegmentsCollection {
+    db_conn: MongoDatabase,
+}
+
+pub struct SyncJobsCollection {
+    db_conn: MongoDatabase,
+}
+
 impl ActivitiesCollection {
     const COLL_NAME: &str = "activities";
 
@@ -46,96 +74,94 @@ impl ActivitiesCollection {
             .typed_collection(ActivitiesCollection::COLL_NAME)
     }
 
+    async fn update<KT, V>(&self, key_path: String, key_value: KT, field: &str, value: &V)
+    where
+        V: std::clone::Clone + Into<bson::Bson>,
+        KT: std::clone::Clone + Into<bson::Bson>,
+        bson::Bson: From<KT> + From<V>,
+    {
+        self.db_conn
+            .update_field(
+                key_path,
+                &key_value,
+                &self.typed_collection(),
+                field,
+                &value,
+            )
+            .await;
+    }
+}
+
+#[async_trait]
+impl ActivitiesStorage for ActivitiesCollection {
     // DELETE
-    pub async fn delete(&self, id: i64) -> u64 {
+    async fn delete(&self, id: i64) -> u64 {
+        self.db_conn.delete_one(&self.typed_collection(), id).await
+    }
+
+    async fn delete_athlete_activities(&self, ath_id: i64) -> u64 {
         self.typed_collection()
-            .delete_one(doc! {"_id": id}, None)
+            .delete_many(doc! {"athlete.id": ath_id}, None)
             .await
-            .ok()
-            .unwrap().deleted_count
+            .map_or(0, |result| result.deleted_count)
     }
 
     // GETTERS
-    pub async fn get(&self, id: i64) -> Option<Activity> {
-        self.typed_collection()
-            .find_one(doc! {"_id": id}, None)
-            .await
-            .ok()
-            .unwrap()
+    async fn get(&self, id: i64) -> Option<Activity> {
+        self.db_conn.get_one(&self.typed_collection(), id).await
     }
 
-    pub async fn get_athlete_activities(&self, ath_id: i64) -> Option<mongodb::Cursor<Activity>> {
-        self.typed_collection()
-            .find(doc! {"athlete.id": ath_id}, None)
+    async fn get_athlete_activities(&self, ath_id: i64) -> Vec<Activity> {
+        self.db_conn
+            .find(&self.typed_collection(), doc! {"athlete.id": ath_id})
             .await
-            .ok()
     }
 
-    pub async fn get_athlete_activities_with_ids(
+    async fn get_athlete_activities_with_ids(
         &self,
         ath_id: i64,
-        ids: &Vec<DocumentId>,
-    ) -> Option<mongodb::Cursor<Activity>> {
-        self.typed_collection()
-            .find(doc! {"athlete.id": ath_id, "_id": {"$in": ids}}, None)
+        ids: &[DocumentId],
+    ) -> Vec<Activity> {
+        self.db_conn
+            .find(
+                &self.typed_collection(),
+                doc! {"athlete.id": ath_id, "_id": {"$in": ids}},
+            )
             .await
-            .ok()
     }
 
-    pub async fn get_athlete_activity_ids(&self, ath_id: i64) -> Vec<DocumentId> {
-        let mut act_ids: Vec<DocumentId> = Vec::new();
-
-        if let Some(mut cursor) = self.get_athlete_activities(ath_id).await {
-            while cursor.advance().await.unwrap() {
-                act_ids.push(cursor.deserialize_current().unwrap().as_i64());
-            }
-        }
-
-        act_ids
-    }
-
-    pub async fn get_athlete_activities_sorted_distance_asc(
-        &self,
-        ath_id: i64,
-    ) -> Option<mongodb::Cursor<mongodb::bson::Document>> {
-        self.typed_collection()
-            .aggregate(
-                vec![
-                    doc! {"$match": {"athlete.id": ath_id}},
-                    doc! {"$sort": { "distance": 1 } },
-                ],
-                None,
-            )
+    async fn get_athlete_activity_ids(&self, ath_id: i64) -> Vec<DocumentId> {
+        self.get_athlete_activities(ath_id)
             .await
-            .ok()
+            .iter()
+            .map(|activity| activity.as_i64())
+            .collect()
     }
 
-    pub async fn get_max_distance_activity_in_ids(
-        &self,
-        ids: &Vec<DocumentId>,
-    ) -> Option<Activity> {
-        self.query_activities(Vec::from([
-            doc! {"$match": {"_id": {"$in": ids}}},
-            doc! {"$sort": { "distance": -1 } },
-            doc! {"$limit": 1},
-        ]))
+    async fn get_athlete_activity_ids_sorted_distance_asc(&self, ath_id: i64) -> Vec<DocumentId> {
+        self.query_activities(vec![
+            doc! {"$match": {"athlete.id": ath_id}},
+            doc! {"$sort": { "distance": 1 } },
+        ])
         .await
-        .get(0)
-        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .map(|activity| activity.as_i64())
+        .collect()
     }
 
     // SETTERS
-    pub async fn set_location_city(&self, act_id: i64, city: &Option<String>) {
+    async fn set_location_city(&self, act_id: i64, city: &Option<String>) {
         self.update("_id".to_owned(), act_id, "location_city", &city)
             .await;
     }
 
-    pub async fn set_location_country(&self, act_id: i64, country: &String) {
+    async fn set_location_country(&self, act_id: i64, country: &str) {
         self.update("_id".to_owned(), act_id, "location_country", &country)
             .await;
     }
 
-    pub async fn set_segment_start_index_poly(&self, seg_id: i64, start_index_poly: &Option<i32>) {
+    async fn set_segment_start_index_poly(&self, seg_id: i64, start_index_poly: &Option<i32>) {
         if let Some(start_index) = start_index_poly {
             self.update(
                 "segment_efforts.id".to_owned(),
@@ -147,19 +173,37 @@ impl ActivitiesCollection {
         }
     }
 
-    pub async fn set_segment_end_index_poly(&self, seg_id: i64, start_index_poly: &Option<i32>) {
-        if let Some(start_index) = start_index_poly {
+    async fn set_segment_end_index_poly(&self, seg_id: i64, end_index_poly: &Option<i32>) {
+        if let Some(end_index) = end_index_poly {
             self.update(
                 "segment_efforts.id".to_owned(),
                 seg_id,
-                "segment_efforts.$.start_end_poly",
-                &start_index,
+                "segment_efforts.$.end_index_poly",
+                &end_index,
             )
             .await;
         }
     }
 
-    pub async fn set_segment_distance_from_start(&self, seg_id: i64, distance_from_start: f32) {
+    async fn set_lap_indexes_poly(&self, lap_id: i64, start_index_poly: i32, end_index_poly: i32) {
+        self.update(
+            "laps.id".to_owned(),
+            lap_id,
+            "laps.$.start_index_poly",
+            &start_index_poly,
+        )
+        .await;
+
+        self.update(
+            "laps.id".to_owned(),
+            lap_id,
+            "laps.$.end_index_poly",
+            &end_index_poly,
+        )
+        .await;
+    }
+
+    async fn set_segment_distance_from_start(&self, seg_id: i64, distance_from_start: f32) {
         self.update(
             "segment_efforts.id".to_owned(),
             seg_id,
@@ -169,41 +213,57 @@ impl ActivitiesCollection {
         .await;
     }
 
-    pub async fn set_start_date_local_date(
+    async fn set_start_date_local_date(
         &self,
-        seg_id: i64,
-        start_index_poly: &Option<DateTime>,
+        act_id: i64,
+        start_date_local_date: &Option<DateTime>,
     ) {
-        if let Some(start_index) = start_index_poly {
+        if let Some(start_date_local_date) = start_date_local_date {
             self.update(
                 "_id".to_owned(),
-                seg_id,
+                act_id,
                 "start_date_local_date",
-                &start_index,
+                &start_date_local_date,
             )
             .await;
         }
     }
 
-    pub async fn query_activities(&self, stages: Vec<bson::Document>) -> Vec<Activity> {
+    async fn get_athlete_segment_efforts(&self, ath_id: i64, seg_id: i64) -> Vec<Effort> {
         self.db_conn
-            .query(&self.typed_collection(), stages)
+            .query(
+                &self.db_conn.typed_collection::<Effort>(ActivitiesCollection::COLL_NAME),
+                vec![
+                    doc! {"$match": {"athlete.id": ath_id, "segment_efforts.segment.id": seg_id}},
+                    doc! {"$unwind": "$segment_efforts"},
+                    doc! {"$match": {"segment_efforts.segment.id": seg_id}},
+                    doc! {"$replaceRoot": {"newRoot": "$segment_efforts"}},
+                    doc! {"$sort": {"start_date_local": 1}},
+                ],
+            )
             .await
-            .to_owned()
     }
 
-    pub async fn query_activities_docs(&self, stages: Vec<bson::Document>) -> Vec<bson::Document> {
+    async fn query_activities(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Activity>> {
+        self.db_conn
+            .try_query(&self.typed_collection(), stages)
+            .await
+    }
+
+    async fn query_activities_docs(
+        &self,
+        stages: Vec<bson::Document>,
+    ) -> QueryResult<Vec<bson::Document>> {
         self.db_conn
-            .query(&self.raw_collection(), stages)
+            .try_query(&self.raw_collection(), stages)
             .await
-            .to_owned()
     }
 
-    pub async fn exists(&self, act_id: i64) -> bool {
+    async fn exists(&self, act_id: i64) -> bool {
         self.db_conn.exists(&self.raw_collection(), act_id).await
     }
 
-    pub async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
         json["_id"] = serde_json::Value::Number(act_id.into());
 
         self.db_conn
@@ -215,20 +275,17 @@ impl ActivitiesCollection {
             .await;
     }
 
-    pub async fn update<KT, V>(&self, key_path: String, key_value: KT, field: &str, value: &V)
-    where
-        V: std::clone::Clone + Into<bson::Bson>,
-        KT: std::clone::Clone + Into<bson::Bson>,
-        bson::Bson: From<KT> + From<V>,
-    {
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>) {
+        let documents = documents
+            .into_iter()
+            .map(|(id, mut json)| {
+                json["_id"] = serde_json::Value::Number(id.into());
+                bson::to_document(&json).unwrap()
+            })
+            .collect();
+
         self.db_conn
-            .update_field(
-                key_path,
-                &key_value,
-                &self.typed_collection(),
-                field,
-                &value,
-            )
+            .upsert_many(&self.raw_collection(), documents)
             .await;
     }
 }
@@ -251,20 +308,69 @@ impl TelemetriesCollection {
         self.db_conn
             .typed_collection(TelemetriesCollection::COLL_NAME)
     }
+}
 
-    pub async fn get(&self, id: i64) -> Option<Telemetry> {
-        self.typed_collection()
-            .find_one(doc! {"_id": id}, None)
-            .await
-            .ok()
-            .unwrap()
+#[async_trait]
+impl TelemetriesStorage for TelemetriesCollection {
+    async fn get(&self, id: i64) -> Option<Telemetry> {
+        self.db_conn.get_one(&self.typed_collection(), id).await
     }
 
-    pub async fn exists(&self, act_id: i64) -> bool {
+    async fn exists(&self, act_id: i64) -> bool {
         self.db_conn.exists(&self.raw_collection(), act_id).await
     }
 
-    pub async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
+    async fn delete_athlete_telemetries(&self, ath_id: i64) -> u64 {
+        self.raw_collection()
+            .delete_many(doc! {"athlete.id": ath_id}, None)
+            .await
+            .map_or(0, |result| result.deleted_count)
+    }
+
+    async fn get_athlete_ids_without_extended_streams(&self, ath_id: i64) -> Vec<DocumentId> {
+        let mut act_ids: Vec<DocumentId> = Vec::new();
+
+        if let Ok(mut cursor) = self
+            .raw_collection()
+            .find(
+                doc! {"athlete.id": ath_id, "extended_streams": {"$ne": true}},
+                FindOptions::builder()
+                    .projection(doc! {"_id": 1})
+                    .sort(doc! {"_id": 1})
+                    .build(),
+            )
+            .await
+        {
+            while cursor.advance().await.unwrap() {
+                let doc = cursor.deserialize_current().unwrap();
+
+                if let Some(act_id) = doc.get("_id").and_then(|id| match id {
+                    bson::Bson::Int64(id) => Some(*id),
+                    bson::Bson::Int32(id) => Some(*id as i64),
+                    bson::Bson::Double(id) => Some(*id as i64),
+                    _ => None,
+                }) {
+                    act_ids.push(act_id);
+                }
+            }
+        }
+
+        act_ids
+    }
+
+    async fn set_type(&self, act_id: i64, r#type: &str) {
+        self.db_conn
+            .update_field(
+                "_id".to_owned(),
+                act_id,
+                &self.typed_collection(),
+                "type",
+                &r#type,
+            )
+            .await;
+    }
+
+    async fn store(&self, act_id: i64, json: &mut serde_json::Value) {
         json["_id"] = serde_json::Value::Number(act_id.into());
 
         self.db_conn
@@ -275,6 +381,20 @@ impl TelemetriesCollection {
             )
             .await;
     }
+
+    async fn store_many(&self, documents: Vec<(i64, serde_json::Value)>) {
+        let documents = documents
+            .into_iter()
+            .map(|(id, mut json)| {
+                json["_id"] = serde_json::Value::Number(id.into());
+                bson::to_document(&json).unwrap()
+            })
+            .collect();
+
+        self.db_conn
+            .upsert_many(&self.raw_collection(), documents)
+            .await;
+    }
 }
 
 impl AthletesCollection {
@@ -287,16 +407,36 @@ impl AthletesCollection {
     fn typed_docs_collection(&self) -> Collection<AthleteData> {
         self.db_conn.typed_collection("athletes")
     }
+}
+
+#[async_trait]
+impl AthletesStorage for AthletesCollection {
+    async fn get_athlete_data(&self, id: i64) -> Option<AthleteData> {
+        self.db_conn
+            .get_one(&self.typed_docs_collection(), id)
+            .await
+    }
+
+    async fn get_athlete_ids(&self) -> Vec<AthleteId> {
+        let mut athlete_ids: Vec<AthleteId> = Vec::new();
 
-    pub async fn get_athlete_data(&self, id: i64) -> Option<AthleteData> {
+        if let Ok(mut cursor) = self.typed_docs_collection().find(None, None).await {
+            while cursor.advance().await.unwrap() {
+                athlete_ids.push(cursor.deserialize_current().unwrap().as_i64());
+            }
+        }
+
+        athlete_ids
+    }
+
+    async fn delete(&self, id: i64) -> u64 {
         self.typed_docs_collection()
-            .find_one(doc! {"_id": id}, None)
+            .delete_one(doc! {"_id": id}, None)
             .await
-            .ok()
-            .unwrap()
+            .map_or(0, |result| result.deleted_count)
     }
 
-    pub async fn set_athlete_data(&self, athlete_data: &AthleteData) {
+    async fn set_athlete_data(&self, athlete_data: &AthleteData) {
         self.db_conn
             .upsert_one::<AthleteData>(
                 &self.typed_docs_collection(),
@@ -306,7 +446,7 @@ impl AthletesCollection {
             .await;
     }
 
-    pub async fn set_athlete_tokens(&self, id: i64, athlete_tokens: &AthleteTokens) {
+    async fn set_athlete_tokens(&self, id: i64, athlete_tokens: &AthleteTokens) {
         self.db_conn
             .update_field(
                 "_id".to_owned(),
@@ -318,13 +458,13 @@ impl AthletesCollection {
             .await;
     }
 
-    pub async fn save_after_before_timestamps(&self, ath_id: i64, after_ts: i64, before_ts: i64) {
+    async fn save_after_before_timestamps(&self, ath_id: i64, after_ts: i64, before_ts: i64) {
         self.db_conn
             .update_field(
                 "_id".to_owned(),
                 ath_id,
                 &self.typed_docs_collection(),
-                &"before_ts",
+                "before_ts",
                 &before_ts,
             )
             .await;
@@ -334,39 +474,185 @@ impl AthletesCollection {
                 "_id".to_owned(),
                 ath_id,
                 &self.typed_docs_collection(),
-                &"after_ts",
+                "after_ts",
                 &after_ts,
             )
             .await;
     }
 }
 
-pub struct StravaDB {
-    db_conn: MongoDatabase,
+impl FailedDownloadsCollection {
+    const COLL_NAME: &str = "failed_downloads";
+
+    pub fn new(db_conn: &MongoDatabase) -> Self {
+        Self {
+            db_conn: db_conn.clone(),
+        }
+    }
+
+    fn typed_collection(&self) -> Collection<FailedDownload> {
+        self.db_conn
+            .typed_collection(FailedDownloadsCollection::COLL_NAME)
+    }
+}
+
+#[async_trait]
+impl FailedDownloadsStorage for FailedDownloadsCollection {
+    async fn get(&self, act_id: i64) -> Option<FailedDownload> {
+        self.db_conn.get_one(&self.typed_collection(), act_id).await
+    }
+
+    async fn get_athlete_failed_downloads(&self, ath_id: i64) -> Vec<FailedDownload> {
+        self.db_conn
+            .query(
+                &self.typed_collection(),
+                vec![
+                    doc! {"$match": {"athlete_id": ath_id}},
+                    doc! {"$sort": {"_id": 1}},
+                ],
+            )
+            .await
+    }
+
+    async fn store(&self, failed_download: &FailedDownload) {
+        self.db_conn
+            .upsert_one(
+                &self.typed_collection(),
+                failed_download.as_i64(),
+                failed_download,
+            )
+            .await;
+    }
+
+    async fn delete(&self, act_id: i64) -> u64 {
+        self.db_conn
+            .delete_one(&self.typed_collection(), act_id)
+            .await
+    }
+    async fn delete_athlete_failed_downloads(&self, ath_id: i64) -> u64 {
+        self.typed_collection()
+            .delete_many(doc! {"athlete_id": ath_id}, None)
+            .await
+            .map_or(0, |result| result.deleted_count)
+    }
+}
+
+impl SyncJobsCollection {
+    const COLL_NAME: &str = "sync_jobs";
 
-    pub activities: ActivitiesCollection,
-    pub telemetries: TelemetriesCollection,
-    pub athletes: AthletesCollection,
+    pub fn new(db_conn: &MongoDatabase) -> Self {
+        Self {
+            db_conn: db_conn.clone(),
+        }
+    }
+
+    fn typed_collection(&self) -> Collection<SyncJob> {
+        self.db_conn.typed_collection(SyncJobsCollection::COLL_NAME)
+    }
+}
+
+#[async_trait]
+impl SyncJobsStorage for SyncJobsCollection {
+    async fn get(&self, ath_id: i64) -> Option<SyncJob> {
+        self.db_conn.get_one(&self.typed_collection(), ath_id).await
+    }
+
+    async fn store(&self, sync_job: &SyncJob) {
+        self.db_conn
+            .upsert_one(&self.typed_collection(), sync_job.as_i64(), sync_job)
+            .await;
+    }
+
+    async fn delete(&self, ath_id: i64) -> u64 {
+        self.typed_collection()
+            .delete_one(doc! {"_id": ath_id}, None)
+            .await
+            .map_or(0, |result| result.deleted_count)
+    }
+}
+
+impl SegmentsCollection {
+    const COLL_NAME: &str = "segments";
+
+    pub fn new(db_conn: &MongoDatabase) -> Self {
+        Self {
+            db_conn: db_conn.clone(),
+        }
+    }
+
+    fn typed_collection(&self) -> Collection<Segment> {
+        self.db_conn.typed_collection(SegmentsCollection::COLL_NAME)
+    }
+
+    fn raw_collection(&self) -> Collection<mongodb::bson::Document> {
+        self.db_conn.typed_collection(SegmentsCollection::COLL_NAME)
+    }
+}
+
+#[async_trait]
+impl SegmentsStorage for SegmentsCollection {
+    async fn get(&self, seg_id: i64) -> Option<Segment> {
+        self.db_conn.get_one(&self.typed_collection(), seg_id).await
+    }
+
+    async fn exists(&self, seg_id: i64) -> bool {
+        self.db_conn.exists(&self.raw_collection(), seg_id).await
+    }
+
+    async fn store(&self, seg_id: i64, json: &mut serde_json::Value) {
+        json["_id"] = serde_json::Value::Number(seg_id.into());
+
+        self.db_conn
+            .upsert_one(
+                &self.raw_collection(),
+                seg_id,
+                bson::to_document(&json).unwrap().borrow(),
+            )
+            .await;
+    }
+}
+
+pub struct StravaDB {
+    pub activities: Arc<dyn ActivitiesStorage>,
+    pub telemetries: Arc<dyn TelemetriesStorage>,
+    pub athletes: Arc<dyn AthletesStorage>,
+    pub failed_downloads: Arc<dyn FailedDownloadsStorage>,
+    pub segments: Arc<dyn SegmentsStorage>,
+    pub sync_jobs: Arc<dyn SyncJobsStorage>,
 }
 
 impl StravaDB {
-    pub async fn new(db_url: &str) -> Self {
+    pub async fn new(db_url: &str, db_name: &str) -> Self {
         let db = Client::with_uri_str(db_url)
             .await
             .unwrap()
-            .database("strava_db");
+            .database(db_name);
 
         let db_coll = MongoDatabase::new(&db);
 
         Self {
-            db_conn: db_coll.clone(),
-            activities: ActivitiesCollection::new(&db_coll),
-            telemetries: TelemetriesCollection::new(&db_coll),
-            athletes: AthletesCollection::new(&db_coll),
+            activities: Arc::new(ActivitiesCollection::new(&db_coll)),
+            telemetries: Arc::new(TelemetriesCollection::new(&db_coll)),
+            athletes: Arc::new(AthletesCollection::new(&db_coll)),
+            failed_downloads: Arc::new(FailedDownloadsCollection::new(&db_coll)),
+            segments: Arc::new(SegmentsCollection::new(&db_coll)),
+            sync_jobs: Arc::new(SyncJobsCollection::new(&db_coll)),
+        }
+    }
+
+    // Nothing is persisted, for tests and running without a MongoDB server
+    pub fn in_memory() -> Self {
+        Self {
+            activities: Arc::new(MemoryActivities::default()),
+            telemetries: Arc::new(MemoryTelemetries::default()),
+            athletes: Arc::new(MemoryAthletes::default()),
+            failed_downloads: Arc::new(MemoryFailedDownloads::default()),
+            segments: Arc::new(MemorySegments::default()),
+            sync_jobs: Arc::new(MemorySyncJobs::default()),
         }
     }
 
-    pub fn get_athletes_collection(&self) -> AthletesCollection {
-        AthletesCollection::new(&self.db_conn)
+    pub fn get_athletes_collection(&self) -> Arc<dyn AthletesStorage> {
+        Arc::clone(&self.athletes)
     }
 }
@@ -0,0 +1,148 @@
+use geo_types::Coord;
+use serde_json::{json, Value};
+
+use crate::{
+    data_types::{
+        gc::route::{Gradient, Route},
+        strava::activity::{Activity, Effort},
+    },
+    util::geo::GeoUtils,
+};
+
+use super::gpx::GpxWriter;
+
+pub struct GeoJsonWriter;
+
+impl GeoJsonWriter {
+    // The route, its gradients and the master activity's segment efforts, all indexes point into the route's polyline
+    pub fn write_route(route: &Route, master_activity: Option<&Activity>) -> Value {
+        let poly_coords = GeoUtils::get_coords_from_poly(&route.polyline);
+        let mut features = vec![GeoJsonWriter::route_feature(route, &poly_coords)];
+
+        for (number, gradient) in route.gradients.iter().enumerate() {
+            if let Some(feature) =
+                GeoJsonWriter::gradient_feature(number + 1, gradient, &poly_coords)
+            {
+                features.push(feature);
+            }
+        }
+
+        for effort in master_activity
+            .iter()
+            .flat_map(|activity| activity.segment_efforts.iter())
+        {
+            if let Some(feature) = GeoJsonWriter::effort_feature(effort, &poly_coords) {
+                features.push(feature);
+            }
+        }
+
+        GeoJsonWriter::feature_collection(features)
+    }
+
+    // One LineString per route with flat properties, which is what GIS tools import best
+    pub fn write_routes(routes: &[Route]) -> Value {
+        GeoJsonWriter::feature_collection(
+            routes
+                .iter()
+                .map(|route| {
+                    GeoJsonWriter::route_feature(
+                        route,
+                        &GeoUtils::get_coords_from_poly(&route.polyline),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn route_feature(route: &Route, poly_coords: &[Coord]) -> Value {
+        GeoJsonWriter::line_feature(
+            poly_coords,
+            json!({
+                "kind": "route",
+                "id": route._id as i64,
+                "name": GpxWriter::route_name(route),
+                "type": route.r#type,
+                "athlete_id": route.athlete_id,
+                "master_activity_id": route.master_activity_id,
+                "activity_count": route.activities.len(),
+                "distance": route.distance,
+                "average_speed": route.average_speed,
+                "total_elevation_gain": route.total_elevation_gain,
+                "gradient_count": route.gradients.len(),
+                "location_city": route.location_city,
+                "location_country": route.location_country,
+            }),
+        )
+    }
+
+    fn gradient_feature(
+        number: usize,
+        gradient: &Gradient,
+        poly_coords: &[Coord],
+    ) -> Option<Value> {
+        let coords = poly_coords.get(gradient.start_index..=gradient.end_index)?;
+
+        Some(GeoJsonWriter::line_feature(
+            coords,
+            json!({
+                "kind": "gradient",
+                "number": number,
+                "name": GpxWriter::gradient_name(number, gradient),
+                "start_index": gradient.start_index,
+                "end_index": gradient.end_index,
+                "length": gradient.length,
+                "avg_gradient": gradient.avg_gradient,
+                "max_gradient": gradient.max_gradient,
+                "elevation_gain": gradient.elevation_gain,
+                "location_city": gradient.location_city,
+                "location_country": gradient.location_country,
+            }),
+        ))
+    }
+
+    // Efforts are left out until the post processor has set their polyline indexes
+    fn effort_feature(effort: &Effort, poly_coords: &[Coord]) -> Option<Value> {
+        let start_index = effort.start_index_poly? as usize;
+        let end_index = effort.end_index_poly? as usize;
+        let coords = poly_coords.get(start_index..=end_index)?;
+
+        Some(GeoJsonWriter::line_feature(
+            coords,
+            json!({
+                "kind": "segment_effort",
+                "id": effort.id,
+                "name": effort.name,
+                "segment_id": effort.segment.id,
+                "distance": effort.segment.distance,
+                "average_grade": effort.segment.average_grade,
+                "maximum_grade": effort.segment.maximum_grade,
+                "moving_time": effort.moving_time,
+                "start_date_local": effort.start_date_local,
+                "start_index_poly": start_index,
+                "end_index_poly": end_index,
+            }),
+        ))
+    }
+
+    // GeoJSON positions are [longitude, latitude]
+    fn line_feature(coords: &[Coord], properties: Value) -> Value {
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coords
+                    .iter()
+                    .map(|coord| [coord.x, coord.y])
+                    .collect::<Vec<[f64; 2]>>(),
+            },
+            "properties": properties,
+        })
+    }
+
+    fn feature_collection(features: Vec<Value>) -> Value {
+        json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt::Write;
+
+use chrono::Duration;
+use geo_types::Coord;
+
+use crate::{
+    data_types::{
+        gc::route::{Gradient, Route},
+        strava::{
+            activity::Activity,
+            telemetry::{F32data, Telemetry},
+        },
+    },
+    util::geo::GeoUtils,
+};
+
+use super::{activity_start_time, escape_xml, format_time};
+
+const GPX_HEADER: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+    "\n",
+    r#"<gpx version="1.1" creator="Ground Covered" xmlns="http://www.topografix.com/GPX/1/1""#,
+    r#" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">"#,
+    "\n"
+);
+
+pub struct GpxWriter;
+
+impl GpxWriter {
+    // None for activities without GPS, a track point needs a position
+    pub fn write_activity(activity: &Activity, telemetry: &Telemetry) -> Option<String> {
+        if telemetry.latlng.data.is_empty() {
+            return None;
+        }
+
+        let start_time = activity_start_time(activity);
+        let name = escape_xml(&activity.name);
+        let mut gpx = String::from(GPX_HEADER);
+
+        writeln!(
+            gpx,
+            "<metadata><name>{}</name><time>{}</time></metadata>",
+            name,
+            format_time(start_time)
+        )
+        .unwrap();
+        writeln!(
+            gpx,
+            "<trk><name>{}</name><type>{}</type><trkseg>",
+            name,
+            escape_xml(&activity.r#type)
+        )
+        .unwrap();
+
+        for index in 0..telemetry.latlng.data.len() {
+            let value = |stream: &Option<F32data>| -> Option<f32> {
+                stream
+                    .as_ref()
+                    .and_then(|stream| stream.data.get(index).cloned())
+            };
+
+            GpxWriter::write_point(&mut gpx, "trkpt", telemetry, index);
+
+            if let Some(time) = telemetry.time.data.get(index) {
+                write!(
+                    gpx,
+                    "<time>{}</time>",
+                    format_time(start_time + Duration::seconds(*time as i64))
+                )
+                .unwrap();
+            }
+
+            let heartrate = value(&telemetry.heartrate);
+            let cadence = value(&telemetry.cadence);
+            let temp = value(&telemetry.temp);
+            let watts = value(&telemetry.watts);
+
+            if heartrate.is_some() || cadence.is_some() || temp.is_some() || watts.is_some() {
+                gpx.push_str("<extensions>");
+
+                if let Some(watts) = watts {
+                    write!(gpx, "<power>{}</power>", watts.round()).unwrap();
+                }
+
+                gpx.push_str("<gpxtpx:TrackPointExtension>");
+
+                for (tag, value) in [("atemp", temp), ("hr", heartrate), ("cad", cadence)] {
+                    if let Some(value) = value {
+                        write!(gpx, "<gpxtpx:{0}>{1}</gpxtpx:{0}>", tag, value.round()).unwrap();
+                    }
+                }
+
+                gpx.push_str("</gpxtpx:TrackPointExtension></extensions>");
+            }
+
+            gpx.push_str("</trkpt>\n");
+        }
+
+        gpx.push_str("</trkseg></trk>\n</gpx>\n");
+
+        Some(gpx)
+    }
+
+    // Course for bike computers, the telemetry is the master activity's, times are left out
+    pub fn write_route(route: &Route, telemetry: &Telemetry) -> Option<String> {
+        if telemetry.latlng.data.is_empty() {
+            return None;
+        }
+
+        let name = escape_xml(&GpxWriter::route_name(route));
+        let mut gpx = String::from(GPX_HEADER);
+
+        writeln!(gpx, "<metadata><name>{}</name></metadata>", name).unwrap();
+
+        // Waypoints have to come before the track, gradient indexes point into the route's polyline
+        let poly_coords = GeoUtils::get_coords_from_poly(&route.polyline);
+
+        for (number, gradient) in route.gradients.iter().enumerate() {
+            GpxWriter::write_gradient(&mut gpx, number + 1, gradient, &poly_coords);
+        }
+
+        writeln!(
+            gpx,
+            "<trk><name>{}</name><type>{}</type><trkseg>",
+            name,
+            escape_xml(&route.r#type)
+        )
+        .unwrap();
+
+        for index in 0..telemetry.latlng.data.len() {
+            GpxWriter::write_point(&mut gpx, "trkpt", telemetry, index);
+            gpx.push_str("</trkpt>\n");
+        }
+
+        gpx.push_str("</trkseg></trk>\n</gpx>\n");
+
+        Some(gpx)
+    }
+
+    pub(crate) fn route_name(route: &Route) -> String {
+        let location = route
+            .location_city
+            .as_ref()
+            .map_or(route.location_country.clone(), |city| {
+                format!("{}, {}", city, route.location_country)
+            });
+
+        format!(
+            "{} {:.1} km {}",
+            route.r#type,
+            route.distance / 1000.,
+            location
+        )
+        .trim()
+        .to_string()
+    }
+
+    pub(crate) fn gradient_name(number: usize, gradient: &Gradient) -> String {
+        format!(
+            "{} {} ({:.1}%, {:.1} km)",
+            if gradient.avg_gradient >= 0. {
+                "Climb"
+            } else {
+                "Descent"
+            },
+            number,
+            gradient.avg_gradient,
+            gradient.length / 1000.
+        )
+    }
+
+    // Start and end of the gradient, named so they are listed together on the device
+    fn write_gradient(gpx: &mut String, number: usize, gradient: &Gradient, poly_coords: &[Coord]) {
+        let name = GpxWriter::gradient_name(number, gradient);
+        let description = format!(
+            "{:.0} m elevation gain, max {:.1}%",
+            gradient.elevation_gain, gradient.max_gradient
+        );
+
+        for (index, altitude, suffix) in [
+            (gradient.start_index, gradient.altitude.first(), "start"),
+            (gradient.end_index, gradient.altitude.last(), "end"),
+        ] {
+            let Some(coord) = poly_coords.get(index) else {
+                continue;
+            };
+
+            write!(gpx, r#"<wpt lat="{}" lon="{}">"#, coord.y, coord.x).unwrap();
+
+            if let Some(altitude) = altitude {
+                write!(gpx, "<ele>{}</ele>", altitude).unwrap();
+            }
+
+            writeln!(
+                gpx,
+                "<name>{} {}</name><desc>{}</desc></wpt>",
+                escape_xml(&name),
+                suffix,
+                description
+            )
+            .unwrap();
+        }
+    }
+
+    // Opening tag and elevation, the caller adds the rest and closes it
+    fn write_point(gpx: &mut String, tag: &str, telemetry: &Telemetry, index: usize) {
+        let [lat, lng] = telemetry.latlng.data[index];
+
+        write!(gpx, r#"<{} lat="{}" lon="{}">"#, tag, lat, lng).unwrap();
+
+        if let Some(altitude) = telemetry.altitude.data.get(index) {
+            write!(gpx, "<ele>{}</ele>", altitude).unwrap();
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io::{Cursor, Write as IoWrite},
+};
+
+use geo_types::Coord;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    data_types::{
+        gc::route::{Gradient, Route},
+        strava::{activity::Activity, telemetry::Telemetry},
+    },
+    util::geo::GeoUtils,
+};
+
+use super::{escape_xml, gpx::GpxWriter};
+
+// KML colors are aabbggrr
+const STYLES: &str = r#"<Style id="track"><LineStyle><color>ff0055ff</color><width>4</width></LineStyle><PolyStyle><color>400055ff</color></PolyStyle></Style>
+<Style id="gradient_start"><IconStyle><color>ff00c800</color><Icon><href>http://maps.google.com/mapfiles/kml/paddle/go.png</href></Icon></IconStyle></Style>
+<Style id="summit"><IconStyle><color>ff0000ff</color><Icon><href>http://maps.google.com/mapfiles/kml/shapes/flag.png</href></Icon></IconStyle></Style>
+<Style id="gradient_end"><IconStyle><color>ffff8800</color><Icon><href>http://maps.google.com/mapfiles/kml/paddle/stop.png</href></Icon></IconStyle></Style>
+"#;
+
+pub struct KmlWriter;
+
+impl KmlWriter {
+    // None for activities without GPS
+    pub fn write_activity(activity: &Activity, telemetry: &Telemetry) -> Option<String> {
+        if telemetry.latlng.data.is_empty() {
+            return None;
+        }
+
+        let mut kml = String::new();
+        KmlWriter::write_track(&mut kml, &activity.name, telemetry, "");
+
+        Some(KmlWriter::document(&activity.name, &kml))
+    }
+
+    // Without telemetry the route is drawn from its polyline, clamped to the ground
+    pub fn write_route(route: &Route, telemetry: Option<&Telemetry>) -> String {
+        let name = GpxWriter::route_name(route);
+
+        KmlWriter::document(&name, &KmlWriter::route_folder(route, telemetry))
+    }
+
+    // Routes and the telemetry of their master activity, grouped in a folder per type
+    pub fn write_routes(name: &str, routes: &[(Route, Option<Telemetry>)]) -> String {
+        let mut routes_by_type: BTreeMap<&str, Vec<&(Route, Option<Telemetry>)>> = BTreeMap::new();
+
+        for route in routes {
+            routes_by_type
+                .entry(route.0.r#type.as_str())
+                .or_default()
+                .push(route);
+        }
+
+        let mut kml = String::new();
+
+        for (r#type, routes) in routes_by_type {
+            writeln!(kml, "<Folder><name>{}</name>", escape_xml(r#type)).unwrap();
+
+            for (route, telemetry) in routes {
+                kml.push_str(&KmlWriter::route_folder(route, telemetry.as_ref()));
+            }
+
+            kml.push_str("</Folder>\n");
+        }
+
+        KmlWriter::document(name, &kml)
+    }
+
+    // Zip with the document as doc.kml, which is what Google Earth opens
+    pub fn kmz(kml: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        // Writing to memory can't fail
+        zip.start_file(
+            "doc.kml",
+            FileOptions::default().compression_method(CompressionMethod::Deflated),
+        )
+        .unwrap();
+        zip.write_all(kml.as_bytes()).unwrap();
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn document(name: &str, content: &str) -> String {
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                "\n",
+                r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#,
+                "\n<Document><name>{}</name>\n{}{}</Document>\n</kml>\n"
+            ),
+            escape_xml(name),
+            STYLES,
+            content
+        )
+    }
+
+    fn route_folder(route: &Route, telemetry: Option<&Telemetry>) -> String {
+        let name = GpxWriter::route_name(route);
+        let description = format!(
+            "{:.1} km, {:.0} m elevation gain, {} activities",
+            route.distance / 1000.,
+            route.total_elevation_gain,
+            route.activities.len()
+        );
+
+        let mut kml = String::new();
+        writeln!(kml, "<Folder><name>{}</name>", escape_xml(&name)).unwrap();
+
+        match telemetry {
+            Some(telemetry) if !telemetry.latlng.data.is_empty() => {
+                KmlWriter::write_track(&mut kml, &name, telemetry, &description)
+            }
+            _ => KmlWriter::write_polyline(&mut kml, &name, &route.polyline, &description),
+        }
+
+        // Gradient indexes point into the route's polyline
+        let poly_coords = GeoUtils::get_coords_from_poly(&route.polyline);
+
+        for (number, gradient) in route.gradients.iter().enumerate() {
+            KmlWriter::write_gradient(&mut kml, number + 1, gradient, &poly_coords);
+        }
+
+        kml.push_str("</Folder>\n");
+
+        kml
+    }
+
+    // Altitudes are absolute, clamped at sea level since barometric glitches would draw the track underground
+    fn write_track(kml: &mut String, name: &str, telemetry: &Telemetry, description: &str) {
+        writeln!(
+            kml,
+            "<Placemark><name>{}</name><description>{}</description><styleUrl>#track</styleUrl>",
+            escape_xml(name),
+            escape_xml(description)
+        )
+        .unwrap();
+        kml.push_str(
+            "<LineString><extrude>1</extrude><tessellate>1</tessellate><altitudeMode>absolute</altitudeMode><coordinates>\n",
+        );
+
+        for (index, [lat, lng]) in telemetry.latlng.data.iter().enumerate() {
+            let altitude = telemetry
+                .altitude
+                .data
+                .get(index)
+                .cloned()
+                .unwrap_or(0.)
+                .max(0.);
+
+            writeln!(kml, "{},{},{}", lng, lat, altitude).unwrap();
+        }
+
+        kml.push_str("</coordinates></LineString></Placemark>\n");
+    }
+
+    fn write_polyline(kml: &mut String, name: &str, polyline: &str, description: &str) {
+        writeln!(
+            kml,
+            "<Placemark><name>{}</name><description>{}</description><styleUrl>#track</styleUrl>",
+            escape_xml(name),
+            escape_xml(description)
+        )
+        .unwrap();
+        kml.push_str(
+            "<LineString><tessellate>1</tessellate><altitudeMode>clampToGround</altitudeMode><coordinates>\n",
+        );
+
+        for coord in GeoUtils::get_coords_from_poly(polyline) {
+            writeln!(kml, "{},{}", coord.x, coord.y).unwrap();
+        }
+
+        kml.push_str("</coordinates></LineString></Placemark>\n");
+    }
+
+    // Placemarks at the start and at the top of a climb, or at both ends of a descent
+    fn write_gradient(kml: &mut String, number: usize, gradient: &Gradient, poly_coords: &[Coord]) {
+        let name = GpxWriter::gradient_name(number, gradient);
+        let description = format!(
+            "Length {:.2} km, average {:.1}%, max {:.1}%, {:.0} m elevation gain",
+            gradient.length / 1000.,
+            gradient.avg_gradient,
+            gradient.max_gradient,
+            gradient.elevation_gain
+        );
+        let end_style = if gradient.avg_gradient >= 0. {
+            ("summit", "summit")
+        } else {
+            ("end", "gradient_end")
+        };
+
+        for (index, altitude, (suffix, style)) in [
+            (
+                gradient.start_index,
+                gradient.altitude.first(),
+                ("start", "gradient_start"),
+            ),
+            (gradient.end_index, gradient.altitude.last(), end_style),
+        ] {
+            let Some(coord) = poly_coords.get(index) else {
+                continue;
+            };
+
+            writeln!(
+                kml,
+                "<Placemark><name>{} {}</name><description>{}</description><styleUrl>#{}</styleUrl><Point><altitudeMode>clampToGround</altitudeMode><coordinates>{},{},{}</coordinates></Point></Placemark>",
+                escape_xml(&name),
+                suffix,
+                escape_xml(&description),
+                style,
+                coord.x,
+                coord.y,
+                altitude.cloned().unwrap_or(0).max(0)
+            )
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use chrono::{DateTime, Utc};
+
+use crate::data_types::strava::activity::Activity;
+
+pub mod geojson;
+pub mod gpx;
+pub mod kml;
+pub mod tcx;
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Activities synced before start_date was stored only have the local time, which is then used as is
+pub(crate) fn activity_start_time(activity: &Activity) -> DateTime<Utc> {
+    activity
+        .start_date
+        .as_deref()
+        .unwrap_or(&activity.start_date_local)
+        .parse::<DateTime<Utc>>()
+        .unwrap_or_default()
+}
+
+pub(crate) fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
@@ -0,0 +1,323 @@
+use std::{fmt::Write, ops::Range};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::data_types::strava::{
+    activity::{Activity, Lap},
+    telemetry::{F32data, Telemetry},
+};
+
+use super::{activity_start_time, escape_xml, format_time};
+
+pub struct TcxWriter;
+
+impl TcxWriter {
+    // Trackpoints are split between the activity's laps, activities without laps are written as a single one
+    pub fn write(activity: &Activity, telemetry: &Telemetry) -> String {
+        let start_time = activity_start_time(activity);
+        let samples = telemetry.time.data.len();
+
+        // Strava's laps share their boundary sample, each lap here ends where the next one starts
+        let mut lap_starts: Vec<usize> = activity
+            .laps
+            .iter()
+            .map(|lap| (lap.start_index.max(0) as usize).min(samples))
+            .collect();
+
+        if lap_starts.is_empty() {
+            lap_starts.push(0);
+        }
+
+        lap_starts[0] = 0;
+
+        let mut tcx = String::new();
+
+        tcx.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        tcx.push('\n');
+        tcx.push_str(concat!(
+            r#"<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2""#,
+            r#" xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">"#
+        ));
+        tcx.push_str("\n<Activities>\n");
+        writeln!(
+            tcx,
+            r#"<Activity Sport="{}">"#,
+            TcxWriter::tcx_sport(&activity.r#type)
+        )
+        .unwrap();
+        writeln!(tcx, "<Id>{}</Id>", format_time(start_time)).unwrap();
+
+        for (index, from) in lap_starts.iter().enumerate() {
+            let to = lap_starts
+                .get(index + 1)
+                .cloned()
+                .unwrap_or(samples)
+                .max(*from);
+
+            TcxWriter::write_lap(
+                &mut tcx,
+                activity.laps.get(index),
+                activity,
+                telemetry,
+                start_time,
+                *from..to,
+            );
+        }
+
+        writeln!(tcx, "<Notes>{}</Notes>", escape_xml(&activity.name)).unwrap();
+        tcx.push_str("</Activity>\n</Activities>\n</TrainingCenterDatabase>\n");
+
+        tcx
+    }
+
+    fn write_lap(
+        tcx: &mut String,
+        lap: Option<&Lap>,
+        activity: &Activity,
+        telemetry: &Telemetry,
+        start_time: DateTime<Utc>,
+        range: Range<usize>,
+    ) {
+        let stream = |stream: &Option<F32data>| {
+            stream
+                .as_ref()
+                .map(|stream| stream.data.get(range.clone()).unwrap_or_default().to_vec())
+                .filter(|values| !values.is_empty())
+        };
+        let average = |values: &Option<Vec<f32>>| -> Option<f32> {
+            let values: Vec<f32> = values
+                .iter()
+                .flatten()
+                .cloned()
+                .filter(|value| *value > 0.)
+                .collect();
+
+            (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+        };
+
+        let heartrate = stream(&telemetry.heartrate);
+        let cadence = stream(&telemetry.cadence);
+        let lap_start = range
+            .clone()
+            .next()
+            .and_then(|index| telemetry.time.data.get(index))
+            .map_or(start_time, |offset| {
+                start_time + Duration::seconds(*offset as i64)
+            });
+
+        let (elapsed_time, distance) = match lap {
+            Some(lap) => (lap.elapsed_time as f32, lap.distance),
+            None => (activity.elapsed_time as f32, activity.distance),
+        };
+        let max_speed = lap
+            .map(|lap| lap.max_speed)
+            .filter(|max_speed| *max_speed > 0.)
+            .unwrap_or_else(|| {
+                telemetry
+                    .velocity_smooth
+                    .data
+                    .get(range.clone())
+                    .unwrap_or_default()
+                    .iter()
+                    .cloned()
+                    .fold(0., f32::max)
+            });
+
+        writeln!(tcx, r#"<Lap StartTime="{}">"#, format_time(lap_start)).unwrap();
+        writeln!(tcx, "<TotalTimeSeconds>{}</TotalTimeSeconds>", elapsed_time).unwrap();
+        writeln!(tcx, "<DistanceMeters>{}</DistanceMeters>", distance).unwrap();
+        writeln!(tcx, "<MaximumSpeed>{}</MaximumSpeed>", max_speed).unwrap();
+        tcx.push_str("<Calories>0</Calories>\n");
+
+        if let Some(average_heartrate) = lap
+            .and_then(|lap| lap.average_heartrate)
+            .or(average(&heartrate))
+        {
+            writeln!(
+                tcx,
+                "<AverageHeartRateBpm><Value>{}</Value></AverageHeartRateBpm>",
+                average_heartrate.round()
+            )
+            .unwrap();
+            writeln!(
+                tcx,
+                "<MaximumHeartRateBpm><Value>{}</Value></MaximumHeartRateBpm>",
+                heartrate
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .fold(0., f32::max)
+                    .round()
+            )
+            .unwrap();
+        }
+
+        tcx.push_str("<Intensity>Active</Intensity>\n");
+
+        if let Some(average_cadence) = lap
+            .and_then(|lap| lap.average_cadence)
+            .or(average(&cadence))
+        {
+            writeln!(tcx, "<Cadence>{}</Cadence>", average_cadence.round()).unwrap();
+        }
+
+        tcx.push_str("<TriggerMethod>Manual</TriggerMethod>\n<Track>\n");
+
+        for index in range {
+            TcxWriter::write_trackpoint(tcx, telemetry, start_time, index);
+        }
+
+        tcx.push_str("</Track>\n</Lap>\n");
+    }
+
+    fn write_trackpoint(
+        tcx: &mut String,
+        telemetry: &Telemetry,
+        start_time: DateTime<Utc>,
+        index: usize,
+    ) {
+        let value = |stream: &Option<F32data>| {
+            stream
+                .as_ref()
+                .and_then(|stream| stream.data.get(index).cloned())
+        };
+
+        let time = start_time + Duration::seconds(telemetry.time.data[index] as i64);
+
+        tcx.push_str("<Trackpoint>\n");
+        writeln!(tcx, "<Time>{}</Time>", format_time(time)).unwrap();
+
+        if let Some([lat, lng]) = telemetry.latlng.data.get(index) {
+            writeln!(
+                tcx,
+                "<Position><LatitudeDegrees>{}</LatitudeDegrees><LongitudeDegrees>{}</LongitudeDegrees></Position>",
+                lat, lng
+            )
+            .unwrap();
+        }
+
+        if let Some(altitude) = telemetry.altitude.data.get(index) {
+            writeln!(tcx, "<AltitudeMeters>{}</AltitudeMeters>", altitude).unwrap();
+        }
+
+        if let Some(distance) = telemetry.distance.data.get(index) {
+            writeln!(tcx, "<DistanceMeters>{}</DistanceMeters>", distance).unwrap();
+        }
+
+        if let Some(heartrate) = value(&telemetry.heartrate) {
+            writeln!(
+                tcx,
+                "<HeartRateBpm><Value>{}</Value></HeartRateBpm>",
+                heartrate.round()
+            )
+            .unwrap();
+        }
+
+        if let Some(cadence) = value(&telemetry.cadence) {
+            writeln!(tcx, "<Cadence>{}</Cadence>", cadence.round()).unwrap();
+        }
+
+        let speed = telemetry.velocity_smooth.data.get(index);
+        let watts = value(&telemetry.watts);
+
+        if speed.is_some() || watts.is_some() {
+            tcx.push_str("<Extensions><ns3:TPX>");
+
+            if let Some(speed) = speed {
+                write!(tcx, "<ns3:Speed>{}</ns3:Speed>", speed).unwrap();
+            }
+
+            if let Some(watts) = watts {
+                write!(tcx, "<ns3:Watts>{}</ns3:Watts>", watts.round()).unwrap();
+            }
+
+            tcx.push_str("</ns3:TPX></Extensions>\n");
+        }
+
+        tcx.push_str("</Trackpoint>\n");
+    }
+
+    // TCX only knows running and biking
+    fn tcx_sport(r#type: &str) -> &'static str {
+        match r#type {
+            "Run" | "TrailRun" | "VirtualRun" | "Walk" | "Hike" => "Running",
+            r#type if r#type.ends_with("Ride") || r#type == "Velomobile" => "Biking",
+            _ => "Other",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::{tcx::TcxParser, track::RecordedActivity};
+
+    const TWO_LAPS: &str = include_str!("../../fixtures/import/two_laps.tcx");
+
+    // Builds the documents the importer would store for the file
+    fn import(content: &str) -> (RecordedActivity, Activity, Telemetry) {
+        let recorded = TcxParser::parse(content).unwrap();
+        let details = recorded.activity_details(1, content.as_bytes(), "TCX", None, None);
+
+        let mut activity =
+            details.to_activity_json(&recorded.summary(), &recorded.track.polyline(), 1);
+        activity["_id"] = details.id.into();
+
+        let mut telemetry = recorded.track.to_telemetry_json(1, &details.r#type);
+        telemetry["_id"] = details.id.into();
+
+        (
+            recorded,
+            serde_json::from_value(activity).unwrap(),
+            serde_json::from_value(telemetry).unwrap(),
+        )
+    }
+
+    #[test]
+    fn exported_activities_import_with_the_same_streams_and_laps() {
+        let (_, activity, telemetry) = import(TWO_LAPS);
+        let exported = TcxWriter::write(&activity, &telemetry);
+        let (recorded, reimported, retelemetry) = import(&exported);
+
+        assert_eq!(recorded.track.points.len(), 7);
+        assert_eq!(reimported.name, activity.name);
+        assert_eq!(reimported.r#type, activity.r#type);
+        assert_eq!(reimported.start_date, activity.start_date);
+
+        assert_eq!(retelemetry.time.data, telemetry.time.data);
+        assert_eq!(retelemetry.latlng.data, telemetry.latlng.data);
+        assert_eq!(retelemetry.altitude.data, telemetry.altitude.data);
+        assert_eq!(retelemetry.distance.data, telemetry.distance.data);
+        assert_eq!(
+            retelemetry.heartrate.map(|stream| stream.data),
+            telemetry.heartrate.map(|stream| stream.data)
+        );
+        assert_eq!(
+            retelemetry.watts.map(|stream| stream.data),
+            telemetry.watts.map(|stream| stream.data)
+        );
+
+        assert_eq!(reimported.laps.len(), activity.laps.len());
+
+        for (lap, relap) in activity.laps.iter().zip(&reimported.laps) {
+            assert_eq!(
+                (relap.start_index, relap.end_index),
+                (lap.start_index, lap.end_index)
+            );
+            assert_eq!(relap.start_date_local, lap.start_date_local);
+            assert_eq!(relap.elapsed_time, lap.elapsed_time);
+            assert_eq!(relap.distance, lap.distance);
+        }
+    }
+
+    #[test]
+    fn activities_without_laps_are_written_as_one() {
+        let (_, mut activity, telemetry) = import(TWO_LAPS);
+        activity.laps.clear();
+
+        let (recorded, _, retelemetry) = import(&TcxWriter::write(&activity, &telemetry));
+
+        assert_eq!(recorded.laps.len(), 1);
+        assert_eq!(retelemetry.time.data, telemetry.time.data);
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::data_types::{
+    common::DocumentId,
+    strava::{activity::Lap, athlete::AthleteId},
+};
+
+use super::track::TrackSummary;
+
+// Ids of activities which never reached Strava are negative so they can't collide with Strava's
+const SYNTHETIC_ID_MASK: u64 = (1 << 52) - 1;
+
+// Fields of an imported activity which don't come from its streams
+#[derive(Debug, Clone, Default)]
+pub struct ActivityDetails {
+    pub id: DocumentId,
+    pub name: String,
+    // Legacy type, routes group activities by it
+    pub r#type: String,
+    // Specific type, like MountainBikeRide for a Ride
+    pub sport_type: String,
+    pub description: Option<String>,
+    pub start_date: Option<DateTime<Utc>>,
+    // File the activity was created from
+    pub external_id: Option<String>,
+    pub device_name: Option<String>,
+    pub laps: Vec<Lap>,
+}
+
+impl ActivityDetails {
+    // Activity document with the fields StravaDBSync gets from the API detail endpoint
+    pub fn to_activity_json(
+        &self,
+        summary: &TrackSummary,
+        polyline: &str,
+        athlete_id: AthleteId,
+    ) -> Value {
+        let start_date = self
+            .start_date
+            .unwrap_or_default()
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "type": self.r#type,
+            "sport_type": self.sport_type,
+            "description": self.description,
+            "distance": summary.distance,
+            "elapsed_time": summary.elapsed_time,
+            "moving_time": summary.moving_time,
+            "total_elevation_gain": summary.total_elevation_gain,
+            "average_speed": summary.average_speed,
+            "max_speed": summary.max_speed,
+            "has_heartrate": summary.average_heartrate.is_some(),
+            "average_heartrate": summary.average_heartrate,
+            "max_heartrate": summary.max_heartrate,
+            "average_cadence": summary.average_cadence,
+            "device_watts": summary.average_watts.is_some(),
+            "average_watts": summary.average_watts,
+            "start_date": start_date,
+            // Files have no timezone information
+            "start_date_local": start_date,
+            "athlete": { "id": athlete_id },
+            "athlete_count": 1,
+            "map": { "polyline": polyline, "summary_polyline": polyline },
+            "segment_efforts": [],
+            "laps": self.laps,
+            "splits_metric": [],
+            "splits_standard": [],
+            "location_city": null,
+            "location_country": "",
+            "private": false,
+            "external_id": self.external_id,
+            "device_name": self.device_name,
+        })
+    }
+
+    // Strava's sport types are more specific than the legacy types synced activities are grouped by
+    pub fn legacy_type(sport_type: &str) -> String {
+        let r#type = match sport_type {
+            "MountainBikeRide" | "GravelRide" | "VirtualRide" => "Ride",
+            "EMountainBikeRide" => "EBikeRide",
+            "TrailRun" | "VirtualRun" => "Run",
+            _ => sport_type,
+        };
+
+        r#type.to_string()
+    }
+
+    // Laps' ids are derived from the activity's so they change with it
+    pub fn set_id(&mut self, id: DocumentId) {
+        for lap in &mut self.laps {
+            lap.id = id * 1000 + (lap.id - self.id * 1000);
+        }
+
+        self.id = id;
+    }
+
+    // Id of an activity which never reached Strava, derived from its file so uploading it again updates the same activity.
+    // The athlete is part of the key, the same file uploaded by two athletes is two activities.
+    // FNV-1a, stable between runs and versions unlike the std hasher
+    pub fn synthetic_id(athlete_id: AthleteId, content: &[u8]) -> DocumentId {
+        let hash = athlete_id
+            .to_le_bytes()
+            .iter()
+            .chain(content)
+            .fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            });
+
+        // Activity ids are also stored as f64 so they have to fit the mantissa
+        -(((hash & SYNTHETIC_ID_MASK) as i64).max(1))
+    }
+}
@@ -0,0 +1,187 @@
+use std::{fs::File, io::Read, path::Path};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use zip::ZipArchive;
+
+use crate::data_types::{common::DocumentId, strava::athlete::AthleteId};
+
+use super::{
+    activity::ActivityDetails, fit::FitParser, gpx::GpxParser, tcx::TcxParser, track::Track,
+    ImportError, ImportResult,
+};
+
+const ACTIVITIES_CSV: &str = "activities.csv";
+
+// Formats used for "Activity Date" by the different export versions, always UTC
+const ACTIVITY_DATE_FORMATS: &[&str] = &["%b %d, %Y, %I:%M:%S %p", "%Y-%m-%d %H:%M:%S"];
+
+// One row of activities.csv
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveActivity {
+    pub id: DocumentId,
+    pub name: String,
+    pub r#type: String,
+    pub description: Option<String>,
+    pub start_date: Option<DateTime<Utc>>,
+    pub elapsed_time: Option<i32>,
+    pub moving_time: Option<i32>,
+    // Meters
+    pub distance: Option<f32>,
+    pub total_elevation_gain: Option<f32>,
+    pub max_speed: Option<f32>,
+    pub average_speed: Option<f32>,
+    // Path of the recorded file inside the archive, empty for manual activities
+    pub filename: Option<String>,
+}
+
+// Strava's "download your data" zip
+pub struct StravaArchive {
+    zip: ZipArchive<File>,
+}
+
+impl StravaArchive {
+    pub fn open(path: &Path) -> ImportResult<Self> {
+        Ok(Self {
+            zip: ZipArchive::new(File::open(path)?)?,
+        })
+    }
+
+    pub fn activities(&mut self) -> ImportResult<Vec<ArchiveActivity>> {
+        let csv_content = self.read_entry(ACTIVITIES_CSV)?;
+        let mut reader = csv::Reader::from_reader(csv_content.as_slice());
+        let headers = reader.headers()?.clone();
+
+        let mut activities: Vec<ArchiveActivity> = Vec::new();
+
+        for record in reader.records() {
+            let record = record?;
+
+            // Some columns appear twice, the later one has the unrounded value in base units
+            let column = |name: &str| -> Option<String> {
+                headers
+                    .iter()
+                    .zip(record.iter())
+                    .filter(|(header, value)| *header == name && !value.trim().is_empty())
+                    .map(|(_, value)| value.trim().to_string())
+                    .last()
+            };
+            let number = |name: &str| -> Option<f32> {
+                column(name).and_then(|value| value.replace(',', "").parse::<f32>().ok())
+            };
+
+            let Some(id) = column("Activity ID").and_then(|id| id.parse::<DocumentId>().ok())
+            else {
+                continue;
+            };
+
+            // Exports with a single Distance column have it in km
+            let distance_columns = headers
+                .iter()
+                .filter(|header| *header == "Distance")
+                .count();
+            let distance = number("Distance").map(|distance| {
+                if distance_columns > 1 {
+                    distance
+                } else {
+                    distance * 1000.
+                }
+            });
+
+            activities.push(ArchiveActivity {
+                id,
+                name: column("Activity Name").unwrap_or_default(),
+                r#type: column("Activity Type").unwrap_or_default(),
+                description: column("Activity Description"),
+                start_date: column("Activity Date")
+                    .and_then(|date| StravaArchive::parse_activity_date(&date)),
+                elapsed_time: number("Elapsed Time").map(|time| time as i32),
+                moving_time: number("Moving Time").map(|time| time as i32),
+                distance,
+                total_elevation_gain: number("Elevation Gain"),
+                max_speed: number("Max Speed"),
+                average_speed: number("Average Speed"),
+                filename: column("Filename"),
+            });
+        }
+
+        Ok(activities)
+    }
+
+    // Decompresses and parses the recorded file of an activity
+    pub fn read_track(&mut self, filename: &str) -> ImportResult<Track> {
+        let mut content = self.read_entry(filename)?;
+        let mut name = filename.to_lowercase();
+
+        if let Some(uncompressed_name) = name.strip_suffix(".gz") {
+            let mut uncompressed: Vec<u8> = Vec::new();
+            GzDecoder::new(content.as_slice()).read_to_end(&mut uncompressed)?;
+
+            content = uncompressed;
+            name = uncompressed_name.to_string();
+        }
+
+        match Path::new(&name).extension().and_then(|ext| ext.to_str()) {
+            Some("gpx") => GpxParser::parse(&String::from_utf8_lossy(&content)),
+            Some("fit") => FitParser::parse(&content).map(|activity| activity.track),
+            // Strava's exported TCX files start with whitespace before the XML declaration
+            Some("tcx") => TcxParser::parse(String::from_utf8_lossy(&content).trim())
+                .map(|activity| activity.track),
+            _ => Err(ImportError::UnsupportedFormat(filename.to_string())),
+        }
+    }
+
+    // Values from activities.csv take precedence over the ones computed from the track
+    pub fn build_activity_json(
+        activity: &ArchiveActivity,
+        track: Option<&Track>,
+        athlete_id: AthleteId,
+    ) -> Value {
+        let mut summary = track.map(|track| track.summary()).unwrap_or_default();
+        let polyline = track.map(|track| track.polyline()).unwrap_or_default();
+
+        summary.distance = activity.distance.unwrap_or(summary.distance);
+        summary.elapsed_time = activity.elapsed_time.unwrap_or(summary.elapsed_time);
+        summary.moving_time = activity.moving_time.unwrap_or(summary.moving_time);
+        summary.total_elevation_gain = activity
+            .total_elevation_gain
+            .unwrap_or(summary.total_elevation_gain);
+        summary.average_speed = activity.average_speed.unwrap_or(summary.average_speed);
+        summary.max_speed = activity.max_speed.unwrap_or(summary.max_speed);
+
+        ActivityDetails {
+            id: activity.id,
+            name: activity.name.clone(),
+            r#type: activity.r#type.clone(),
+            sport_type: activity.r#type.clone(),
+            description: activity.description.clone(),
+            start_date: activity
+                .start_date
+                .or(track.and_then(|track| track.start_time())),
+            external_id: activity.filename.clone(),
+            ..Default::default()
+        }
+        .to_activity_json(&summary, &polyline, athlete_id)
+    }
+
+    fn read_entry(&mut self, name: &str) -> ImportResult<Vec<u8>> {
+        let mut entry = self
+            .zip
+            .by_name(name)
+            .map_err(|err| ImportError::Archive(format!("{}: {}", name, err)))?;
+
+        let mut content: Vec<u8> = Vec::new();
+        entry.read_to_end(&mut content)?;
+
+        Ok(content)
+    }
+
+    fn parse_activity_date(date: &str) -> Option<DateTime<Utc>> {
+        ACTIVITY_DATE_FORMATS.iter().find_map(|format| {
+            NaiveDateTime::parse_from_str(date, format)
+                .ok()
+                .map(|date| DateTime::from_utc(date, Utc))
+        })
+    }
+}
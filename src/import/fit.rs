@@ -0,0 +1,658 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::{
+    track::{RecordedActivity, Totals, TrackPoint},
+    ImportError, ImportResult,
+};
+
+// Seconds between the unix epoch and the FIT epoch, 1989-12-31T00:00:00Z
+const FIT_EPOCH_OFFSET: i64 = 631065600;
+// Positions are stored as semicircles, 2^31 of them make 180 degrees
+const SEMICIRCLES_TO_DEGREES: f64 = 180. / 2147483648.;
+
+// Global message numbers
+const MESG_FILE_ID: u16 = 0;
+const MESG_SPORT: u16 = 12;
+const MESG_SESSION: u16 = 18;
+const MESG_LAP: u16 = 19;
+const MESG_RECORD: u16 = 20;
+const MESG_DEVICE_INFO: u16 = 23;
+const MESG_FIELD_DESCRIPTION: u16 = 206;
+
+// Field number of the timestamp in every message which has one
+const FIELD_TIMESTAMP: u8 = 253;
+
+// Field numbers of the totals lap and session messages have in common
+struct SummaryFields {
+    average_speed: u8,
+    max_speed: u8,
+    average_heartrate: u8,
+    max_heartrate: u8,
+    average_cadence: u8,
+    average_watts: u8,
+    total_ascent: u8,
+    enhanced_average_speed: u8,
+    enhanced_max_speed: u8,
+}
+
+const LAP_FIELDS: SummaryFields = SummaryFields {
+    average_speed: 13,
+    max_speed: 14,
+    average_heartrate: 15,
+    max_heartrate: 16,
+    average_cadence: 17,
+    average_watts: 19,
+    total_ascent: 21,
+    enhanced_average_speed: 110,
+    enhanced_max_speed: 111,
+};
+
+const SESSION_FIELDS: SummaryFields = SummaryFields {
+    average_speed: 14,
+    max_speed: 15,
+    average_heartrate: 16,
+    max_heartrate: 17,
+    average_cadence: 18,
+    average_watts: 20,
+    total_ascent: 22,
+    enhanced_average_speed: 124,
+    enhanced_max_speed: 125,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+    base_type: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeveloperFieldDefinition {
+    developer_index: u8,
+    number: u8,
+    size: usize,
+}
+
+#[derive(Debug, Clone)]
+struct MessageDefinition {
+    global_number: u16,
+    big_endian: bool,
+    fields: Vec<FieldDefinition>,
+    developer_fields: Vec<DeveloperFieldDefinition>,
+}
+
+// Developer field as described by a field_description message
+#[derive(Debug, Clone)]
+struct DeveloperField {
+    name: String,
+    base_type: u8,
+}
+
+#[derive(Debug, Clone)]
+enum FieldValue {
+    Number(f64),
+    Text(String),
+}
+
+// Data message with invalid values left out, array fields keep their first element
+#[derive(Debug, Default)]
+struct Message {
+    global_number: u16,
+    fields: HashMap<u8, FieldValue>,
+    // By lowercase name, fields without a description are skipped
+    developer_fields: HashMap<String, f64>,
+}
+
+impl Message {
+    fn number(&self, field: u8) -> Option<f64> {
+        match self.fields.get(&field) {
+            Some(FieldValue::Number(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn text(&self, field: u8) -> Option<String> {
+        match self.fields.get(&field) {
+            Some(FieldValue::Text(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    // Profile values are integers with a scale and an offset, value = raw / scale - offset
+    fn scaled(&self, field: u8, scale: f64, offset: f64) -> Option<f32> {
+        self.number(field)
+            .map(|value| (value / scale - offset) as f32)
+    }
+
+    fn time(&self, field: u8) -> Option<DateTime<Utc>> {
+        self.number(field).and_then(|value| {
+            Utc.timestamp_opt(value as i64 + FIT_EPOCH_OFFSET, 0)
+                .single()
+        })
+    }
+
+    fn degrees(&self, field: u8) -> Option<f64> {
+        self.number(field)
+            .map(|semicircles| semicircles * SEMICIRCLES_TO_DEGREES)
+    }
+}
+
+pub struct FitParser;
+
+impl FitParser {
+    // Decodes the messages of an activity file, files cut short by a device crash are read up to the last full message
+    pub fn parse(content: &[u8]) -> ImportResult<RecordedActivity> {
+        let header_size = content.first().cloned().unwrap_or(0) as usize;
+
+        if header_size < 12 || content.len() < header_size || &content[8..12] != b".FIT" {
+            return Err(ImportError::Parse("missing FIT header".to_string()));
+        }
+
+        let data_size =
+            u32::from_le_bytes([content[4], content[5], content[6], content[7]]) as usize;
+        let end = (header_size + data_size).min(content.len());
+
+        let mut position = header_size;
+        let mut definitions: HashMap<u8, MessageDefinition> = HashMap::new();
+        let mut developer_fields: HashMap<(u8, u8), DeveloperField> = HashMap::new();
+        let mut last_timestamp: Option<f64> = None;
+
+        let mut activity: RecordedActivity = Default::default();
+        let mut positions: Vec<Option<(f64, f64)>> = Vec::new();
+        let mut manufacturer: Option<String> = None;
+        let mut product_name: Option<String> = None;
+        let mut sport: Option<(u8, u8)> = None;
+
+        while position < end {
+            let header = content[position];
+            position += 1;
+
+            // Compressed timestamp header, the time is an offset from the last full timestamp
+            let (local_number, time_offset) = if header & 0x80 != 0 {
+                ((header >> 5) & 0x03, Some(header & 0x1F))
+            } else {
+                (header & 0x0F, None)
+            };
+
+            if time_offset.is_none() && header & 0x40 != 0 {
+                let Some((definition, size)) =
+                    FitParser::read_definition(&content[position..end], header & 0x20 != 0)
+                else {
+                    break;
+                };
+
+                definitions.insert(local_number, definition);
+                position += size;
+                continue;
+            }
+
+            // Without its definition the length of the message is unknown, nothing after it can be read
+            let Some(definition) = definitions.get(&local_number) else {
+                break;
+            };
+
+            let Some((mut message, size)) =
+                FitParser::read_message(&content[position..end], definition, &developer_fields)
+            else {
+                break;
+            };
+            position += size;
+
+            if let Some(time_offset) = time_offset {
+                let last = last_timestamp.unwrap_or(0.) as u32;
+                let mut timestamp = (last & !0x1F) + time_offset as u32;
+
+                if (time_offset as u32) < (last & 0x1F) {
+                    timestamp = timestamp.wrapping_add(0x20);
+                }
+
+                message
+                    .fields
+                    .insert(FIELD_TIMESTAMP, FieldValue::Number(timestamp as f64));
+            }
+
+            if let Some(timestamp) = message.number(FIELD_TIMESTAMP) {
+                last_timestamp = Some(timestamp);
+            }
+
+            match message.global_number {
+                MESG_FILE_ID => {
+                    manufacturer = message
+                        .number(1)
+                        .and_then(|manufacturer| FitParser::manufacturer_name(manufacturer as u16))
+                        .map(|name| name.to_string());
+                    product_name = product_name.or(message.text(8));
+                }
+                // Device index 0 is the device which created the file
+                MESG_DEVICE_INFO if message.number(0).unwrap_or(0.) == 0. => {
+                    product_name = product_name.or(message.text(27));
+                }
+                MESG_SPORT => {
+                    sport = sport.or(message
+                        .number(0)
+                        .map(|sport| (sport as u8, message.number(1).unwrap_or(0.) as u8)));
+                }
+                // Multisport files have a session per sport, the first one names the activity
+                MESG_SESSION if activity.session.is_none() => {
+                    activity.session = Some(FitParser::totals(&message, &SESSION_FIELDS));
+
+                    if let Some(session_sport) = message.number(5) {
+                        sport = Some((session_sport as u8, message.number(6).unwrap_or(0.) as u8));
+                    }
+                }
+                MESG_LAP => activity.laps.push(FitParser::totals(&message, &LAP_FIELDS)),
+                MESG_RECORD => {
+                    let (point, latlng) = FitParser::record_point(&message);
+
+                    activity.track.points.push(point);
+                    positions.push(latlng);
+                }
+                MESG_FIELD_DESCRIPTION => {
+                    if let (Some(developer_index), Some(number), Some(base_type), Some(name)) = (
+                        message.number(0),
+                        message.number(1),
+                        message.number(2),
+                        message.text(3),
+                    ) {
+                        developer_fields.insert(
+                            (developer_index as u8, number as u8),
+                            DeveloperField {
+                                name: name.to_lowercase(),
+                                base_type: base_type as u8,
+                            },
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if activity.track.points.is_empty() {
+            return Err(ImportError::Parse("no record messages".to_string()));
+        }
+
+        activity.track.fill_positions(positions);
+
+        activity.track.sport = Some(FitParser::strava_type(sport));
+        activity.device_name = match (manufacturer, product_name) {
+            (Some(manufacturer), Some(product_name))
+                if !product_name.starts_with(&manufacturer) =>
+            {
+                Some(format!("{} {}", manufacturer, product_name))
+            }
+            (manufacturer, product_name) => product_name.or(manufacturer),
+        };
+
+        Ok(activity)
+    }
+
+    fn totals(message: &Message, fields: &SummaryFields) -> Totals {
+        Totals {
+            start_time: message.time(2),
+            end_time: message.time(FIELD_TIMESTAMP),
+            elapsed_time: message.scaled(7, 1000., 0.),
+            timer_time: message.scaled(8, 1000., 0.),
+            distance: message.scaled(9, 100., 0.),
+            average_speed: message
+                .scaled(fields.enhanced_average_speed, 1000., 0.)
+                .or(message.scaled(fields.average_speed, 1000., 0.)),
+            max_speed: message
+                .scaled(fields.enhanced_max_speed, 1000., 0.)
+                .or(message.scaled(fields.max_speed, 1000., 0.)),
+            total_ascent: message.scaled(fields.total_ascent, 1., 0.),
+            average_heartrate: message.scaled(fields.average_heartrate, 1., 0.),
+            max_heartrate: message.scaled(fields.max_heartrate, 1., 0.),
+            average_cadence: message.scaled(fields.average_cadence, 1., 0.),
+            average_watts: message.scaled(fields.average_watts, 1., 0.),
+        }
+    }
+
+    // From FIT's sport and sub sport enums
+    fn strava_type(sport: Option<(u8, u8)>) -> String {
+        let r#type = match sport.unwrap_or((2, 0)) {
+            (1, 3) => "TrailRun",
+            (1, 58) => "VirtualRun",
+            (1, _) => "Run",
+            (2, 8) => "MountainBikeRide",
+            (2, 46) => "GravelRide",
+            (2, 58) => "VirtualRide",
+            (4, _) | (10, _) => "Workout",
+            (5, _) => "Swim",
+            (11, _) => "Walk",
+            (12, _) => "NordicSki",
+            (13, _) => "AlpineSki",
+            (15, _) => "Rowing",
+            (17, _) => "Hike",
+            _ => "Ride",
+        };
+
+        r#type.to_string()
+    }
+
+    fn manufacturer_name(manufacturer: u16) -> Option<&'static str> {
+        match manufacturer {
+            1 => Some("Garmin"),
+            23 => Some("Suunto"),
+            32 => Some("Wahoo"),
+            123 => Some("Polar"),
+            260 => Some("Zwift"),
+            289 => Some("Hammerhead"),
+            294 => Some("COROS"),
+            _ => None,
+        }
+    }
+
+    fn record_point(message: &Message) -> (TrackPoint, Option<(f64, f64)>) {
+        let developer_field = |names: &[&str]| -> Option<f32> {
+            names
+                .iter()
+                .find_map(|name| message.developer_fields.get(*name))
+                .map(|value| *value as f32)
+        };
+
+        let latlng = match (message.degrees(0), message.degrees(1)) {
+            (Some(lat), Some(lng)) => Some((lat, lng)),
+            _ => None,
+        };
+
+        let point = TrackPoint {
+            time: message.time(FIELD_TIMESTAMP),
+            altitude: message.scaled(78, 5., 500.).or(message.scaled(2, 5., 500.)),
+            distance: message.scaled(5, 100., 0.),
+            // Sensors paired through a Connect IQ app only show up as developer fields
+            heartrate: message.scaled(3, 1., 0.).or(developer_field(&[
+                "heart_rate",
+                "heartrate",
+                "hr",
+            ])),
+            cadence: message.scaled(4, 1., 0.).or(developer_field(&["cadence"])),
+            watts: message
+                .scaled(7, 1., 0.)
+                .or(developer_field(&["power", "watts"])),
+            temp: message
+                .scaled(13, 1., 0.)
+                .or(developer_field(&["temperature", "temp"])),
+            ..Default::default()
+        };
+
+        (point, latlng)
+    }
+
+    fn read_definition(
+        content: &[u8],
+        has_developer_fields: bool,
+    ) -> Option<(MessageDefinition, usize)> {
+        // Reserved byte, architecture, global message number and number of fields
+        let header = content.get(..5)?;
+        let big_endian = header[1] == 1;
+        let global_number = if big_endian {
+            u16::from_be_bytes([header[2], header[3]])
+        } else {
+            u16::from_le_bytes([header[2], header[3]])
+        };
+
+        let mut position = 5;
+        let mut fields: Vec<FieldDefinition> = Vec::new();
+
+        for _ in 0..header[4] {
+            let field = content.get(position..position + 3)?;
+            fields.push(FieldDefinition {
+                number: field[0],
+                size: field[1] as usize,
+                base_type: field[2],
+            });
+            position += 3;
+        }
+
+        let mut developer_fields: Vec<DeveloperFieldDefinition> = Vec::new();
+
+        if has_developer_fields {
+            let count = *content.get(position)?;
+            position += 1;
+
+            for _ in 0..count {
+                let field = content.get(position..position + 3)?;
+                developer_fields.push(DeveloperFieldDefinition {
+                    number: field[0],
+                    size: field[1] as usize,
+                    developer_index: field[2],
+                });
+                position += 3;
+            }
+        }
+
+        Some((
+            MessageDefinition {
+                global_number,
+                big_endian,
+                fields,
+                developer_fields,
+            },
+            position,
+        ))
+    }
+
+    fn read_message(
+        content: &[u8],
+        definition: &MessageDefinition,
+        developer_fields: &HashMap<(u8, u8), DeveloperField>,
+    ) -> Option<(Message, usize)> {
+        let mut message = Message {
+            global_number: definition.global_number,
+            ..Default::default()
+        };
+        let mut position = 0;
+
+        for field in &definition.fields {
+            let bytes = content.get(position..position + field.size)?;
+            position += field.size;
+
+            if let Some(value) =
+                FitParser::read_value(bytes, field.base_type, definition.big_endian)
+            {
+                message.fields.insert(field.number, value);
+            }
+        }
+
+        for field in &definition.developer_fields {
+            let bytes = content.get(position..position + field.size)?;
+            position += field.size;
+
+            let Some(description) = developer_fields.get(&(field.developer_index, field.number))
+            else {
+                continue;
+            };
+
+            if let Some(FieldValue::Number(value)) =
+                FitParser::read_value(bytes, description.base_type, definition.big_endian)
+            {
+                message
+                    .developer_fields
+                    .insert(description.name.clone(), value);
+            }
+        }
+
+        Some((message, position))
+    }
+
+    // First element of a field, None for the base type's invalid value
+    fn read_value(bytes: &[u8], base_type: u8, big_endian: bool) -> Option<FieldValue> {
+        let base_type = base_type & 0x1F;
+
+        if base_type == 7 {
+            let text = bytes.split(|byte| *byte == 0).next().unwrap_or_default();
+            let text = String::from_utf8_lossy(text).trim().to_string();
+
+            return (!text.is_empty()).then_some(FieldValue::Text(text));
+        }
+
+        // Size, signed and invalid value of each numeric base type
+        let (size, signed, invalid): (usize, bool, u64) = match base_type {
+            0 | 2 | 13 => (1, false, 0xFF),
+            1 => (1, true, 0x7F),
+            3 => (2, true, 0x7FFF),
+            4 => (2, false, 0xFFFF),
+            5 => (4, true, 0x7FFFFFFF),
+            6 => (4, false, 0xFFFFFFFF),
+            8 => (4, false, 0xFFFFFFFF),
+            9 => (8, false, u64::MAX),
+            10 => (1, false, 0),
+            11 => (2, false, 0),
+            12 => (4, false, 0),
+            14 => (8, true, 0x7FFFFFFFFFFFFFFF),
+            15 => (8, false, u64::MAX),
+            16 => (8, false, 0),
+            _ => return None,
+        };
+
+        let element = bytes.get(..size)?;
+        let raw = if big_endian {
+            element
+                .iter()
+                .fold(0_u64, |raw, byte| raw << 8 | *byte as u64)
+        } else {
+            element
+                .iter()
+                .rev()
+                .fold(0_u64, |raw, byte| raw << 8 | *byte as u64)
+        };
+
+        if raw == invalid {
+            return None;
+        }
+
+        let value = match base_type {
+            8 => f32::from_bits(raw as u32) as f64,
+            9 => f64::from_bits(raw),
+            _ if signed => {
+                let shift = 64 - size * 8;
+                ((raw << shift) as i64 >> shift) as f64
+            }
+            _ => raw as f64,
+        };
+
+        value.is_finite().then_some(FieldValue::Number(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 60 records with a developer power field and a compressed timestamp, two big endian laps
+    // and a gravel ride session, recorded by a Wahoo ELEMNT BOLT
+    const GRAVEL_RIDE: &[u8] = include_bytes!("../../fixtures/import/gravel_ride.fit");
+
+    fn fit_time(offset: u32) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(1700000000 + offset as i64, 0).single()
+    }
+
+    #[test]
+    fn parses_records_laps_and_session() {
+        let activity = FitParser::parse(GRAVEL_RIDE).unwrap();
+
+        assert_eq!(activity.track.sport.as_deref(), Some("GravelRide"));
+
+        let details = activity.activity_details(1, GRAVEL_RIDE, "FIT", None, None);
+        assert_eq!(details.r#type, "Ride");
+        assert_eq!(details.sport_type, "GravelRide");
+        assert_eq!(activity.device_name.as_deref(), Some("Wahoo ELEMNT BOLT"));
+        assert_eq!(activity.track.points.len(), 60);
+
+        let point = &activity.track.points[2];
+        assert_eq!(point.time, fit_time(2));
+        assert_eq!(point.altitude, Some(101.));
+        assert_eq!(point.heartrate, Some(142.));
+        assert_eq!(point.distance, Some(22.22));
+        assert_eq!(point.watts, Some(202.));
+
+        let session = activity.session.as_ref().unwrap();
+        assert_eq!(session.start_time, fit_time(0));
+        assert_eq!(session.elapsed_time, Some(59.));
+        assert_eq!(session.total_ascent, Some(42.));
+
+        assert_eq!(activity.laps.len(), 2);
+        assert_eq!(activity.laps[1].start_time, fit_time(30));
+        assert_eq!(activity.laps[1].end_time, fit_time(59));
+        assert_eq!(activity.laps[1].elapsed_time, Some(29.));
+        assert_eq!(activity.laps[1].timer_time, Some(28.));
+        assert_eq!(activity.laps[1].distance, Some(333.3));
+    }
+
+    #[test]
+    fn compressed_timestamps_follow_the_last_full_one() {
+        let activity = FitParser::parse(GRAVEL_RIDE).unwrap();
+
+        assert_eq!(activity.track.points[30].time, fit_time(30));
+    }
+
+    #[test]
+    fn samples_before_the_gps_fix_get_the_first_position() {
+        let activity = FitParser::parse(GRAVEL_RIDE).unwrap();
+        let points = &activity.track.points;
+
+        assert_eq!(
+            (points[0].lat, points[0].lng),
+            (points[1].lat, points[1].lng)
+        );
+        assert!((points[1].lat - 46.0001).abs() < 1e-6);
+        assert!((points[1].lng - 7.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn files_cut_short_are_read_up_to_the_last_full_message() {
+        let activity = FitParser::parse(&GRAVEL_RIDE[..GRAVEL_RIDE.len() / 2]).unwrap();
+
+        assert!(!activity.track.points.is_empty());
+        assert!(activity.track.points.len() < 60);
+        assert!(activity.laps.is_empty());
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let mut without_records = GRAVEL_RIDE[..14].to_vec();
+        without_records.extend_from_slice(&[0x40, 0, 0, 0, 0, 0]);
+
+        let invalid: [&[u8]; 5] = [
+            b"",
+            b"\x0e",
+            b"\x0e\x20\x54\x08\x00\x00\x00\x00.GPX\x00\x00",
+            &GRAVEL_RIDE[..10],
+            &without_records,
+        ];
+
+        for content in invalid {
+            assert!(
+                matches!(FitParser::parse(content), Err(ImportError::Parse(_))),
+                "accepted {:?}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_files_do_not_panic() {
+        for end in 0..GRAVEL_RIDE.len() {
+            let _ = FitParser::parse(&GRAVEL_RIDE[..end]);
+        }
+
+        for index in 0..GRAVEL_RIDE.len() {
+            for byte in [0x00, 0x7F, 0xFF] {
+                let mut content = GRAVEL_RIDE.to_vec();
+                content[index] = byte;
+
+                let _ = FitParser::parse(&content);
+            }
+        }
+
+        // Compressed timestamp rolling over the last possible full one
+        let mut rolling_over = GRAVEL_RIDE[..14].to_vec();
+        rolling_over.extend_from_slice(&[0x40, 0, 0, 20, 0, 1, 253, 4, 0x86]);
+        rolling_over.extend_from_slice(&[0x00, 0xFE, 0xFF, 0xFF, 0xFF]);
+        rolling_over.extend_from_slice(&[0x81, 0, 0, 0, 0]);
+
+        assert!(FitParser::parse(&rolling_over).is_ok());
+    }
+}
@@ -0,0 +1,258 @@
+use chrono::{DateTime, Utc};
+
+use crate::data_types::strava::athlete::AthleteId;
+
+use super::{
+    activity::ActivityDetails,
+    track::{Track, TrackPoint},
+    ImportError, ImportResult,
+};
+
+pub struct GpxParser;
+
+impl GpxParser {
+    // Reads all track points of all the track segments in the file, routes are used if there is no track
+    pub fn parse(content: &str) -> ImportResult<Track> {
+        let document = roxmltree::Document::parse(content)?;
+        let root = document.root_element();
+
+        if root.tag_name().name() != "gpx" {
+            return Err(ImportError::Parse("missing gpx element".to_string()));
+        }
+
+        let child_text = |node: roxmltree::Node, name: &str| -> Option<String> {
+            node.children()
+                .find(|child| child.tag_name().name() == name)
+                .and_then(|child| child.text())
+                .map(|text| text.trim().to_string())
+        };
+
+        let mut track: Track = Default::default();
+
+        let point_tag = if root
+            .descendants()
+            .any(|node| node.tag_name().name() == "trkpt")
+        {
+            "trkpt"
+        } else {
+            "rtept"
+        };
+
+        if let Some(trk) = root
+            .children()
+            .find(|child| child.tag_name().name() == "trk" || child.tag_name().name() == "rte")
+        {
+            track.name = child_text(trk, "name");
+            track.sport = child_text(trk, "type");
+        }
+
+        if track.name.is_none() {
+            track.name = root
+                .children()
+                .find(|child| child.tag_name().name() == "metadata")
+                .and_then(|metadata| child_text(metadata, "name"));
+        }
+
+        for trkpt in root
+            .descendants()
+            .filter(|node| node.tag_name().name() == point_tag)
+        {
+            let coordinate = |name: &str| -> ImportResult<f64> {
+                trkpt
+                    .attribute(name)
+                    .and_then(|value| value.trim().parse::<f64>().ok())
+                    .ok_or_else(|| {
+                        ImportError::Parse(format!("{} without valid {}", point_tag, name))
+                    })
+            };
+
+            let mut point = TrackPoint {
+                lat: coordinate("lat")?,
+                lng: coordinate("lon")?,
+                altitude: child_text(trkpt, "ele").and_then(|ele| ele.parse().ok()),
+                time: child_text(trkpt, "time").and_then(|time| GpxParser::parse_time(&time)),
+                ..Default::default()
+            };
+
+            // Garmin TrackPointExtension and the power extension used by most devices
+            for extension in trkpt
+                .descendants()
+                .filter(|node| node.is_element() && node.text().is_some())
+            {
+                let value = extension
+                    .text()
+                    .and_then(|text| text.trim().parse::<f32>().ok());
+
+                match extension.tag_name().name() {
+                    "hr" => point.heartrate = value,
+                    "cad" => point.cadence = value,
+                    "atemp" => point.temp = value,
+                    "power" => point.watts = value,
+                    _ => {}
+                }
+            }
+
+            track.points.push(point);
+        }
+
+        if track.points.is_empty() {
+            return Err(ImportError::Parse("no track points".to_string()));
+        }
+
+        Ok(track)
+    }
+
+    // Details of an activity recorded on a device which does not upload to Strava
+    // the id is derived from the content so uploading the same file again updates the same activity
+    pub fn activity_details(
+        athlete_id: AthleteId,
+        content: &str,
+        track: &Track,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ActivityDetails {
+        let sport_type = r#type
+            .map(|r#type| r#type.to_string())
+            .unwrap_or_else(|| GpxParser::strava_type(track.sport.as_deref()));
+
+        ActivityDetails {
+            id: ActivityDetails::synthetic_id(athlete_id, content.as_bytes()),
+            name: track
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{} (GPX)", sport_type)),
+            r#type: ActivityDetails::legacy_type(&sport_type),
+            sport_type,
+            description: None,
+            start_date: track.start_time(),
+            external_id: filename.map(|filename| filename.to_string()),
+            ..Default::default()
+        }
+    }
+
+    // GPX type is free text, devices use sport names or Garmin's activity codes
+    pub fn strava_type(sport: Option<&str>) -> String {
+        let sport = sport.unwrap_or_default().to_lowercase();
+
+        let r#type = match sport.as_str() {
+            "running" | "run" => "Run",
+            "walking" | "walk" => "Walk",
+            "hiking" | "hike" => "Hike",
+            "swimming" | "swim" => "Swim",
+            "mountain_biking" | "mountain biking" => "MountainBikeRide",
+            "gravel_cycling" | "gravel cycling" => "GravelRide",
+            _ => "Ride",
+        };
+
+        r#type.to_string()
+    }
+
+    fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(time)
+            .ok()
+            .map(|time| time.with_timezone(&Utc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MORNING_RUN: &str = include_str!("../../fixtures/import/morning_run.gpx");
+
+    #[test]
+    fn parses_points_of_all_track_segments() {
+        let track = GpxParser::parse(MORNING_RUN).unwrap();
+
+        assert_eq!(track.name.as_deref(), Some("Morning Run"));
+        assert_eq!(track.sport.as_deref(), Some("running"));
+        assert_eq!(track.points.len(), 4);
+
+        let first = &track.points[0];
+        assert_eq!((first.lat, first.lng), (44.4, 26.1));
+        assert_eq!(first.altitude, Some(80.));
+        assert_eq!(first.heartrate, Some(120.));
+        assert_eq!(first.cadence, Some(80.));
+        assert_eq!(first.temp, Some(18.));
+        assert_eq!(first.watts, Some(210.));
+        assert_eq!(
+            track.start_time(),
+            GpxParser::parse_time("2023-05-01T06:00:00Z")
+        );
+    }
+
+    #[test]
+    fn streams_are_computed_from_positions_and_times() {
+        let streams = GpxParser::parse(MORNING_RUN).unwrap().streams();
+
+        assert_eq!(streams.time, vec![0., 30., 60., 90.]);
+        assert_eq!(streams.altitude, vec![80., 82., 84., 83.]);
+        assert_eq!(streams.heartrate, Some(vec![120., 130., 140., 150.]));
+
+        // 0.001 degrees of latitude are about 111 meters
+        let distance = streams.distance.last().cloned().unwrap();
+        assert!((distance - 333.6).abs() < 1., "distance {}", distance);
+    }
+
+    #[test]
+    fn details_come_from_the_track() {
+        let track = GpxParser::parse(MORNING_RUN).unwrap();
+        let details =
+            GpxParser::activity_details(1, MORNING_RUN, &track, None, Some("morning_run.gpx"));
+
+        assert_eq!(details.r#type, "Run");
+        assert_eq!(details.name, "Morning Run");
+        assert_eq!(details.external_id.as_deref(), Some("morning_run.gpx"));
+
+        let details = GpxParser::activity_details(1, MORNING_RUN, &track, Some("Hike"), None);
+        assert_eq!(details.r#type, "Hike");
+
+        // Grouped with the athlete's other rides
+        let details =
+            GpxParser::activity_details(1, MORNING_RUN, &track, Some("MountainBikeRide"), None);
+        assert_eq!(details.r#type, "Ride");
+        assert_eq!(details.sport_type, "MountainBikeRide");
+    }
+
+    #[test]
+    fn route_points_are_used_without_a_track() {
+        let track = GpxParser::parse(
+            r#"<gpx><metadata><name>Planned</name></metadata><rte>
+                <rtept lat="45.0" lon="25.0"/><rtept lat="45.1" lon="25.1"/>
+            </rte></gpx>"#,
+        )
+        .unwrap();
+
+        assert_eq!(track.name.as_deref(), Some("Planned"));
+        assert_eq!(track.points.len(), 2);
+        assert!(track.start_time().is_none());
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let invalid = [
+            "",
+            "not xml",
+            "<gpx><trk><trkseg><trkpt lat=\"44.4\" lon=\"26.1\"></trkseg></trk></gpx>",
+            "<kml><trk><trkseg><trkpt lat=\"44.4\" lon=\"26.1\"/></trkseg></trk></kml>",
+            "<gpx><trk><trkseg><trkpt lat=\"north\" lon=\"26.1\"/></trkseg></trk></gpx>",
+            "<gpx><trk><trkseg><trkpt lon=\"26.1\"/></trkseg></trk></gpx>",
+            "<gpx><trk><trkseg></trkseg></trk></gpx>",
+        ];
+
+        for content in invalid {
+            assert!(
+                matches!(GpxParser::parse(content), Err(ImportError::Parse(_))),
+                "accepted {:?}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_files_do_not_panic() {
+        for end in (0..MORNING_RUN.len()).filter(|end| MORNING_RUN.is_char_boundary(*end)) {
+            let _ = GpxParser::parse(&MORNING_RUN[..end]);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::Display;
+
+pub mod activity;
+pub mod archive;
+pub mod fit;
+pub mod gpx;
+pub mod tcx;
+pub mod track;
+
+#[derive(Debug, Clone)]
+pub enum ImportError {
+    Io(String),
+    // Zip could not be opened or an entry is missing
+    Archive(String),
+    Csv(String),
+    // Activity file content is not valid for its format
+    Parse(String),
+    UnsupportedFormat(String),
+}
+
+pub type ImportResult<T> = Result<T, ImportError>;
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Io(err) => write!(f, "io error: {}", err),
+            ImportError::Archive(err) => write!(f, "archive error: {}", err),
+            ImportError::Csv(err) => write!(f, "csv error: {}", err),
+            ImportError::Parse(err) => write!(f, "parse error: {}", err),
+            ImportError::UnsupportedFormat(file) => write!(f, "unsupported format: {}", file),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<std::io::Error> for ImportError {
+    fn from(err: std::io::Error) -> Self {
+        ImportError::Io(err.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for ImportError {
+    fn from(err: zip::result::ZipError) -> Self {
+        ImportError::Archive(err.to_string())
+    }
+}
+
+impl From<csv::Error> for ImportError {
+    fn from(err: csv::Error) -> Self {
+        ImportError::Csv(err.to_string())
+    }
+}
+
+impl From<roxmltree::Error> for ImportError {
+    fn from(err: roxmltree::Error) -> Self {
+        ImportError::Parse(err.to_string())
+    }
+}
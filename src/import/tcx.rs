@@ -0,0 +1,263 @@
+use chrono::{DateTime, Utc};
+
+use super::{
+    track::{RecordedActivity, Totals, TrackPoint},
+    ImportError, ImportResult,
+};
+
+pub struct TcxParser;
+
+impl TcxParser {
+    // Reads the first activity of the file, every lap's trackpoints are appended to the same track
+    pub fn parse(content: &str) -> ImportResult<RecordedActivity> {
+        let document = roxmltree::Document::parse(content)?;
+        let root = document.root_element();
+
+        if root.tag_name().name() != "TrainingCenterDatabase" {
+            return Err(ImportError::Parse(
+                "missing TrainingCenterDatabase element".to_string(),
+            ));
+        }
+
+        let Some(activity_node) = root
+            .descendants()
+            .find(|node| node.tag_name().name() == "Activity")
+        else {
+            return Err(ImportError::Parse("no activity".to_string()));
+        };
+
+        let mut activity: RecordedActivity = Default::default();
+        let mut positions: Vec<Option<(f64, f64)>> = Vec::new();
+
+        activity.track.sport = Some(TcxParser::strava_type(activity_node.attribute("Sport")));
+        activity.track.name = TcxParser::child(activity_node, "Notes")
+            .and_then(|notes| notes.text())
+            .map(|notes| notes.trim().to_string())
+            .filter(|notes| !notes.is_empty());
+        activity.device_name = TcxParser::child(activity_node, "Creator")
+            .and_then(|creator| TcxParser::child_text(creator, "Name"));
+
+        for lap in activity_node
+            .children()
+            .filter(|node| node.tag_name().name() == "Lap")
+        {
+            let first_point = activity.track.points.len();
+
+            for trackpoint in lap
+                .descendants()
+                .filter(|node| node.tag_name().name() == "Trackpoint")
+            {
+                let number = |name: &str| -> Option<f32> {
+                    TcxParser::child_text(trackpoint, name).and_then(|value| value.parse().ok())
+                };
+
+                let position = TcxParser::child(trackpoint, "Position").and_then(|position| {
+                    let degrees = |name: &str| -> Option<f64> {
+                        TcxParser::child_text(position, name).and_then(|value| value.parse().ok())
+                    };
+
+                    Some((degrees("LatitudeDegrees")?, degrees("LongitudeDegrees")?))
+                });
+
+                // Speed, power and running cadence live in the ActivityExtension's TPX element
+                let extension = |name: &str| -> Option<f32> {
+                    trackpoint
+                        .descendants()
+                        .find(|node| node.tag_name().name() == name)
+                        .and_then(|node| node.text())
+                        .and_then(|value| value.trim().parse().ok())
+                };
+
+                activity.track.points.push(TrackPoint {
+                    time: TcxParser::child_text(trackpoint, "Time")
+                        .and_then(|time| TcxParser::parse_time(&time)),
+                    altitude: number("AltitudeMeters"),
+                    distance: number("DistanceMeters"),
+                    heartrate: TcxParser::child(trackpoint, "HeartRateBpm")
+                        .and_then(|heartrate| TcxParser::child_text(heartrate, "Value"))
+                        .and_then(|value| value.parse().ok()),
+                    cadence: number("Cadence").or(extension("RunCadence")),
+                    watts: extension("Watts"),
+                    ..Default::default()
+                });
+                positions.push(position);
+            }
+
+            activity.laps.push(TcxParser::lap_totals(
+                lap,
+                first_point,
+                &activity.track.points,
+            ));
+        }
+
+        if activity.track.points.is_empty() {
+            return Err(ImportError::Parse("no trackpoints".to_string()));
+        }
+
+        activity.track.fill_positions(positions);
+
+        // TCX has no totals for the whole activity, only per lap
+        let first_lap = activity.laps.first().cloned().unwrap_or_default();
+        activity.session = Some(Totals {
+            start_time: first_lap.start_time,
+            end_time: activity.laps.last().and_then(|lap| lap.end_time),
+            elapsed_time: activity.laps.iter().map(|lap| lap.elapsed_time).sum(),
+            distance: activity.laps.iter().map(|lap| lap.distance).sum(),
+            max_speed: activity
+                .laps
+                .iter()
+                .filter_map(|lap| lap.max_speed)
+                .reduce(f32::max),
+            max_heartrate: activity
+                .laps
+                .iter()
+                .filter_map(|lap| lap.max_heartrate)
+                .reduce(f32::max),
+            ..Default::default()
+        });
+
+        Ok(activity)
+    }
+
+    // From TCX's Sport attribute, which only knows running and biking
+    fn strava_type(sport: Option<&str>) -> String {
+        let r#type = match sport {
+            Some("Running") => "Run",
+            Some("Other") => "Workout",
+            _ => "Ride",
+        };
+
+        r#type.to_string()
+    }
+
+    fn lap_totals(lap: roxmltree::Node, first_point: usize, points: &[TrackPoint]) -> Totals {
+        let number = |name: &str| -> Option<f32> {
+            TcxParser::child_text(lap, name).and_then(|value| value.parse().ok())
+        };
+        let bpm = |name: &str| -> Option<f32> {
+            TcxParser::child(lap, name)
+                .and_then(|heartrate| TcxParser::child_text(heartrate, "Value"))
+                .and_then(|value| value.parse().ok())
+        };
+
+        let start_time = lap
+            .attribute("StartTime")
+            .and_then(TcxParser::parse_time)
+            .or(points.get(first_point).and_then(|point| point.time));
+        let elapsed_time = number("TotalTimeSeconds");
+
+        Totals {
+            start_time,
+            end_time: match (start_time, elapsed_time) {
+                (Some(start_time), Some(elapsed_time)) => {
+                    Some(start_time + chrono::Duration::seconds(elapsed_time as i64))
+                }
+                _ => points[first_point..]
+                    .iter()
+                    .rev()
+                    .find_map(|point| point.time),
+            },
+            elapsed_time,
+            timer_time: elapsed_time,
+            distance: number("DistanceMeters"),
+            max_speed: number("MaximumSpeed"),
+            average_speed: lap
+                .descendants()
+                .find(|node| node.tag_name().name() == "AvgSpeed")
+                .and_then(|node| node.text())
+                .and_then(|value| value.trim().parse().ok()),
+            average_heartrate: bpm("AverageHeartRateBpm"),
+            max_heartrate: bpm("MaximumHeartRateBpm"),
+            average_cadence: number("Cadence"),
+            ..Default::default()
+        }
+    }
+
+    fn child<'a, 'input>(
+        node: roxmltree::Node<'a, 'input>,
+        name: &str,
+    ) -> Option<roxmltree::Node<'a, 'input>> {
+        node.children()
+            .find(|child| child.tag_name().name() == name)
+    }
+
+    fn child_text(node: roxmltree::Node, name: &str) -> Option<String> {
+        TcxParser::child(node, name)
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_string())
+    }
+
+    fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(time)
+            .ok()
+            .map(|time| time.with_timezone(&Utc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_LAPS: &str = include_str!("../../fixtures/import/two_laps.tcx");
+
+    #[test]
+    fn parses_points_of_all_laps() {
+        let activity = TcxParser::parse(TWO_LAPS).unwrap();
+
+        assert_eq!(activity.track.sport.as_deref(), Some("Run"));
+        assert_eq!(activity.track.name.as_deref(), Some("Track Intervals"));
+        assert_eq!(activity.device_name.as_deref(), Some("Forerunner 255"));
+        assert_eq!(activity.track.points.len(), 7);
+
+        let last = activity.track.points.last().unwrap();
+        assert_eq!((last.lat, last.lng), (44.406, 26.103));
+        assert_eq!(last.distance, Some(666.));
+        assert_eq!(last.heartrate, Some(150.));
+        assert_eq!(last.watts, Some(260.));
+    }
+
+    #[test]
+    fn laps_point_to_their_trackpoints() {
+        let activity = TcxParser::parse(TWO_LAPS).unwrap();
+        let laps = activity.strava_laps(-1);
+
+        assert_eq!(laps.len(), 2);
+        assert_eq!((laps[0].start_index, laps[0].end_index), (0, 3));
+        assert_eq!((laps[1].start_index, laps[1].end_index), (4, 6));
+        assert_eq!(laps[1].start_date_local, "2023-05-01T06:00:40Z");
+        assert_eq!(laps[1].distance, 300.);
+        assert_eq!(laps[1].elapsed_time, 30);
+
+        // Totals of the activity add up the laps'
+        let session = activity.session.unwrap();
+        assert_eq!(session.distance, Some(600.));
+        assert_eq!(session.elapsed_time, Some(60.));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let invalid = [
+            "",
+            "not xml",
+            "<gpx><trk/></gpx>",
+            "<TrainingCenterDatabase><Activities/></TrainingCenterDatabase>",
+            "<TrainingCenterDatabase><Activities><Activity><Lap/></Activity></Activities></TrainingCenterDatabase>",
+            "<TrainingCenterDatabase><Activities><Activity><Lap><Track><Trackpoint></Track></Lap></Activity></Activities></TrainingCenterDatabase>",
+        ];
+
+        for content in invalid {
+            assert!(
+                matches!(TcxParser::parse(content), Err(ImportError::Parse(_))),
+                "accepted {:?}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_files_do_not_panic() {
+        for end in (0..TWO_LAPS.len()).filter(|end| TWO_LAPS.is_char_boundary(*end)) {
+            let _ = TcxParser::parse(&TWO_LAPS[..end]);
+        }
+    }
+}
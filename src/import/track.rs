@@ -0,0 +1,517 @@
+use chrono::{DateTime, Utc};
+use geo_types::Coord;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    data_types::{
+        common::DocumentId,
+        strava::{activity::Lap, athlete::AthleteId},
+    },
+    util::geo::GeoUtils,
+};
+
+use super::activity::ActivityDetails;
+
+// Number of samples on each side averaged by the smoothed streams
+const SMOOTHING_WINDOW: usize = 5;
+// Below this speed (m/s) the athlete is considered stopped
+const MOVING_SPEED_THRESHOLD: f32 = 0.5;
+
+// One sample of a recorded activity, whatever the file format it came from
+#[derive(Debug, Clone, Default)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lng: f64,
+    pub altitude: Option<f32>,
+    pub time: Option<DateTime<Utc>>,
+    // Meters from the start as measured by the device, preferred to the one computed from positions
+    pub distance: Option<f32>,
+    pub heartrate: Option<f32>,
+    pub cadence: Option<f32>,
+    pub watts: Option<f32>,
+    pub temp: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    pub name: Option<String>,
+    // Sport as named by the file, not necessarily a Strava type
+    pub sport: Option<String>,
+    pub points: Vec<TrackPoint>,
+}
+
+// Totals of a lap or of the whole activity as computed by the device
+#[derive(Debug, Clone, Default)]
+pub struct Totals {
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub elapsed_time: Option<f32>,
+    // Elapsed time without the auto pauses
+    pub timer_time: Option<f32>,
+    pub distance: Option<f32>,
+    pub average_speed: Option<f32>,
+    pub max_speed: Option<f32>,
+    pub total_ascent: Option<f32>,
+    pub average_heartrate: Option<f32>,
+    pub max_heartrate: Option<f32>,
+    pub average_cadence: Option<f32>,
+    pub average_watts: Option<f32>,
+}
+
+// Activity file with what the device computed while recording it
+#[derive(Debug, Clone, Default)]
+pub struct RecordedActivity {
+    // Sport is already converted to a Strava type
+    pub track: Track,
+    pub session: Option<Totals>,
+    pub laps: Vec<Totals>,
+    pub device_name: Option<String>,
+}
+
+// Totals computed from the track, used when the file is the only source of the activity's details
+#[derive(Debug, Clone, Default)]
+pub struct TrackSummary {
+    pub distance: f32,
+    pub elapsed_time: i32,
+    pub moving_time: i32,
+    pub total_elevation_gain: f32,
+    pub average_speed: f32,
+    pub max_speed: f32,
+    pub average_heartrate: Option<f32>,
+    pub max_heartrate: Option<f32>,
+    pub average_cadence: Option<f32>,
+    pub average_watts: Option<f32>,
+}
+
+// Streams in the same units Strava uses: seconds, meters, m/s and percent grade
+#[derive(Debug, Clone, Default)]
+pub struct TrackStreams {
+    pub time: Vec<f32>,
+    pub latlng: Vec<[f32; 2]>,
+    pub altitude: Vec<f32>,
+    pub distance: Vec<f32>,
+    pub velocity_smooth: Vec<f32>,
+    pub grade_smooth: Vec<f32>,
+    pub moving: Vec<bool>,
+    pub heartrate: Option<Vec<f32>>,
+    pub cadence: Option<Vec<f32>>,
+    pub watts: Option<Vec<f32>>,
+    pub temp: Option<Vec<f32>>,
+}
+
+impl Track {
+    pub fn start_time(&self) -> Option<DateTime<Utc>> {
+        self.points.iter().find_map(|point| point.time)
+    }
+
+    pub fn has_latlng(&self) -> bool {
+        self.points
+            .iter()
+            .any(|point| point.lat != 0. || point.lng != 0.)
+    }
+
+    // Encoded with precision 5 like the polylines returned by Strava
+    pub fn polyline(&self) -> String {
+        if !self.has_latlng() {
+            return String::new();
+        }
+
+        polyline::encode_coordinates(
+            self.points.iter().map(|point| Coord {
+                x: point.lng,
+                y: point.lat,
+            }),
+            5,
+        )
+        .unwrap_or_default()
+    }
+
+    // Samples recorded before the GPS fix or while it was lost stay where the athlete last was
+    pub fn fill_positions(&mut self, positions: Vec<Option<(f64, f64)>>) {
+        let Some(mut last_latlng) = positions.iter().find_map(|latlng| *latlng) else {
+            return;
+        };
+
+        for (point, latlng) in self.points.iter_mut().zip(positions) {
+            if let Some(latlng) = latlng {
+                last_latlng = latlng;
+            }
+
+            (point.lat, point.lng) = last_latlng;
+        }
+    }
+
+    pub fn streams(&self) -> TrackStreams {
+        let start_ts = self.start_time().map_or(0, |start| start.timestamp());
+
+        let time: Vec<f32> = self
+            .points
+            .iter()
+            .enumerate()
+            .map(|(index, point)| {
+                point
+                    .time
+                    .map_or(index as f32, |time| (time.timestamp() - start_ts) as f32)
+            })
+            .collect();
+
+        let distance = Track::fill_gaps(self.points.iter().map(|point| point.distance))
+            .unwrap_or_else(|| {
+                let mut distance: Vec<f32> = Vec::with_capacity(self.points.len());
+                let mut total_distance = 0.;
+
+                for (index, point) in self.points.iter().enumerate() {
+                    if index > 0 {
+                        total_distance += Track::meters_between(&self.points[index - 1], point);
+                    }
+
+                    distance.push(total_distance);
+                }
+
+                distance
+            });
+
+        let altitude = Track::fill_gaps(self.points.iter().map(|point| point.altitude))
+            .unwrap_or_else(|| vec![0.; self.points.len()]);
+
+        let velocity_smooth = Track::smoothed_rate(&distance, &time, 1.);
+        let grade_smooth = Track::smoothed_rate(&altitude, &distance, 100.)
+            .iter()
+            .map(|grade| (grade * 10.).round() / 10.)
+            .collect();
+        let moving = velocity_smooth
+            .iter()
+            .map(|speed| *speed > MOVING_SPEED_THRESHOLD)
+            .collect();
+
+        TrackStreams {
+            latlng: self
+                .points
+                .iter()
+                .map(|point| [point.lat as f32, point.lng as f32])
+                .collect(),
+            heartrate: Track::fill_gaps(self.points.iter().map(|point| point.heartrate)),
+            cadence: Track::fill_gaps(self.points.iter().map(|point| point.cadence)),
+            watts: Track::fill_gaps(self.points.iter().map(|point| point.watts)),
+            temp: Track::fill_gaps(self.points.iter().map(|point| point.temp)),
+            time,
+            altitude,
+            distance,
+            velocity_smooth,
+            grade_smooth,
+            moving,
+        }
+    }
+
+    pub fn summary(&self) -> TrackSummary {
+        let streams = self.streams();
+
+        let distance = streams.distance.last().cloned().unwrap_or(0.);
+        let elapsed_time = streams.time.last().cloned().unwrap_or(0.) as i32;
+        let moving_time = (1..streams.time.len())
+            .filter(|index| streams.moving[*index])
+            .map(|index| streams.time[index] - streams.time[index - 1])
+            .sum::<f32>() as i32;
+
+        let smoothed_altitude = Track::smoothed(&streams.altitude);
+        let total_elevation_gain = smoothed_altitude
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).max(0.))
+            .sum();
+
+        TrackSummary {
+            distance,
+            elapsed_time,
+            moving_time,
+            total_elevation_gain,
+            average_speed: if moving_time > 0 {
+                distance / moving_time as f32
+            } else {
+                0.
+            },
+            max_speed: streams.velocity_smooth.iter().cloned().fold(0., f32::max),
+            // Zeros are pauses of the sensor for heartrate and cadence but real values for power
+            average_heartrate: streams
+                .heartrate
+                .as_ref()
+                .and_then(|heartrate| Track::average(heartrate, true)),
+            max_heartrate: streams
+                .heartrate
+                .as_ref()
+                .map(|heartrate| heartrate.iter().cloned().fold(0., f32::max)),
+            average_cadence: streams
+                .cadence
+                .as_ref()
+                .and_then(|cadence| Track::average(cadence, true)),
+            average_watts: streams
+                .watts
+                .as_ref()
+                .and_then(|watts| Track::average(watts, false)),
+        }
+    }
+
+    // Telemetry document in the shape StravaDBSync stores the API streams
+    pub fn to_telemetry_json(&self, athlete_id: AthleteId, r#type: &str) -> Value {
+        let streams = self.streams();
+        let mut telemetry = Map::new();
+
+        let mut insert = |key: &str, data: Value| {
+            telemetry.insert(key.to_string(), json!({ "data": data }));
+        };
+
+        insert("time", json!(streams.time));
+        insert("distance", json!(streams.distance));
+        insert("velocity_smooth", json!(streams.velocity_smooth));
+        insert("moving", json!(streams.moving));
+
+        if self.has_latlng() {
+            insert("latlng", json!(streams.latlng));
+            insert("grade_smooth", json!(streams.grade_smooth));
+        }
+
+        if self.points.iter().any(|point| point.altitude.is_some()) {
+            insert("altitude", json!(streams.altitude));
+        }
+
+        for (key, stream) in [
+            ("heartrate", streams.heartrate),
+            ("cadence", streams.cadence),
+            ("watts", streams.watts),
+            ("temp", streams.temp),
+        ] {
+            if let Some(stream) = stream {
+                insert(key, json!(stream));
+            }
+        }
+
+        telemetry.insert("athlete".to_string(), json!({ "id": athlete_id }));
+        telemetry.insert("type".to_string(), json!(r#type));
+        telemetry.insert("extended_streams".to_string(), json!(true));
+
+        Value::Object(telemetry)
+    }
+
+    fn meters_between(p1: &TrackPoint, p2: &TrackPoint) -> f32 {
+        if p1.lat == p2.lat && p1.lng == p2.lng {
+            return 0.;
+        }
+
+        let km = GeoUtils::distance(
+            Coord {
+                x: p1.lat,
+                y: p1.lng,
+            },
+            Coord {
+                x: p2.lat,
+                y: p2.lng,
+            },
+        );
+
+        // acos can go slightly out of range for points very close to each other
+        if km.is_nan() {
+            return 0.;
+        }
+
+        (km * 1000.) as f32
+    }
+
+    // Missing samples take the previous value (or the first known one), None if there are no samples at all
+    fn fill_gaps<I: Iterator<Item = Option<f32>>>(values: I) -> Option<Vec<f32>> {
+        let values: Vec<Option<f32>> = values.collect();
+        let mut last_value = values.iter().find_map(|value| *value)?;
+
+        Some(
+            values
+                .iter()
+                .map(|value| {
+                    if let Some(value) = value {
+                        last_value = *value;
+                    }
+
+                    last_value
+                })
+                .collect(),
+        )
+    }
+
+    fn average(values: &[f32], skip_zeros: bool) -> Option<f32> {
+        let values: Vec<f32> = values
+            .iter()
+            .cloned()
+            .filter(|value| !skip_zeros || *value > 0.)
+            .collect();
+
+        if values.is_empty() {
+            return None;
+        }
+
+        Some(values.iter().sum::<f32>() / values.len() as f32)
+    }
+
+    fn smoothed(values: &[f32]) -> Vec<f32> {
+        (0..values.len())
+            .map(|index| {
+                let from = index.saturating_sub(SMOOTHING_WINDOW);
+                let to = (index + SMOOTHING_WINDOW).min(values.len() - 1);
+
+                values[from..=to].iter().sum::<f32>() / (to - from + 1) as f32
+            })
+            .collect()
+    }
+
+    // d(values)/d(base) over a window around each sample, 0 where base does not change
+    fn smoothed_rate(values: &[f32], base: &[f32], scale: f32) -> Vec<f32> {
+        (0..values.len())
+            .map(|index| {
+                let from = index.saturating_sub(SMOOTHING_WINDOW);
+                let to = (index + SMOOTHING_WINDOW).min(values.len() - 1);
+                let base_delta = base[to] - base[from];
+
+                if base_delta <= 0. {
+                    return 0.;
+                }
+
+                (values[to] - values[from]) / base_delta * scale
+            })
+            .collect()
+    }
+}
+
+impl RecordedActivity {
+    // Totals of the whole activity take precedence over the ones computed from the track
+    pub fn summary(&self) -> TrackSummary {
+        let mut summary = self.track.summary();
+
+        let Some(session) = &self.session else {
+            return summary;
+        };
+
+        summary.distance = session.distance.unwrap_or(summary.distance);
+        summary.elapsed_time = session
+            .elapsed_time
+            .map_or(summary.elapsed_time, |time| time as i32);
+        summary.moving_time = session
+            .timer_time
+            .map_or(summary.moving_time, |time| time as i32);
+        summary.total_elevation_gain = session.total_ascent.unwrap_or(summary.total_elevation_gain);
+        summary.average_speed = session.average_speed.unwrap_or(summary.average_speed);
+        summary.max_speed = session.max_speed.unwrap_or(summary.max_speed);
+        summary.average_heartrate = session.average_heartrate.or(summary.average_heartrate);
+        summary.max_heartrate = session.max_heartrate.or(summary.max_heartrate);
+        summary.average_cadence = session.average_cadence.or(summary.average_cadence);
+        summary.average_watts = session.average_watts.or(summary.average_watts);
+
+        summary
+    }
+
+    // Laps in the shape of the API's, indexes point to the samples recorded during the lap
+    pub fn strava_laps(&self, act_id: DocumentId) -> Vec<Lap> {
+        let streams = self.track.streams();
+        let last_index = self.track.points.len().saturating_sub(1);
+
+        self.laps
+            .iter()
+            .enumerate()
+            .map(|(index, lap)| {
+                let start_index = lap
+                    .start_time
+                    .and_then(|start_time| {
+                        self.track
+                            .points
+                            .iter()
+                            .position(|point| point.time.is_some_and(|time| time >= start_time))
+                    })
+                    .unwrap_or(0);
+                let end_index = lap
+                    .end_time
+                    .and_then(|end_time| {
+                        self.track
+                            .points
+                            .iter()
+                            .rposition(|point| point.time.is_some_and(|time| time <= end_time))
+                    })
+                    .unwrap_or(last_index)
+                    .max(start_index);
+
+                let distance = lap.distance.unwrap_or_else(|| {
+                    streams.distance.get(end_index).cloned().unwrap_or(0.)
+                        - streams.distance.get(start_index).cloned().unwrap_or(0.)
+                });
+                let elapsed_time = lap.elapsed_time.unwrap_or(0.) as i32;
+                let moving_time = lap.timer_time.map_or(elapsed_time, |time| time as i32);
+
+                Lap {
+                    // Lap ids have to be unique across activities like Strava's
+                    id: act_id * 1000 - index as DocumentId,
+                    name: format!("Lap {}", index + 1),
+                    lap_index: index as i32 + 1,
+                    distance,
+                    moving_time,
+                    elapsed_time,
+                    average_speed: lap.average_speed.unwrap_or_else(|| {
+                        if moving_time > 0 {
+                            distance / moving_time as f32
+                        } else {
+                            0.
+                        }
+                    }),
+                    max_speed: lap.max_speed.unwrap_or(0.),
+                    total_elevation_gain: lap.total_ascent.unwrap_or(0.),
+                    average_heartrate: lap.average_heartrate,
+                    average_cadence: lap.average_cadence,
+                    average_watts: lap.average_watts,
+                    start_index: start_index as i32,
+                    end_index: end_index as i32,
+                    start_date_local: lap
+                        .start_time
+                        .or(self
+                            .track
+                            .points
+                            .get(start_index)
+                            .and_then(|point| point.time))
+                        .unwrap_or_default()
+                        .format("%Y-%m-%dT%H:%M:%SZ")
+                        .to_string(),
+                    start_index_poly: None,
+                    end_index_poly: None,
+                }
+            })
+            .collect()
+    }
+
+    // Files have no title, the activity is named after its type like Strava does for untitled uploads
+    pub fn activity_details(
+        &self,
+        athlete_id: AthleteId,
+        content: &[u8],
+        format: &str,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ActivityDetails {
+        let id = ActivityDetails::synthetic_id(athlete_id, content);
+        let sport_type = r#type
+            .map(|r#type| r#type.to_string())
+            .or(self.track.sport.clone())
+            .unwrap_or_else(|| "Ride".to_string());
+
+        ActivityDetails {
+            id,
+            name: self
+                .track
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{} ({})", sport_type, format)),
+            r#type: ActivityDetails::legacy_type(&sport_type),
+            sport_type,
+            description: None,
+            start_date: self
+                .session
+                .as_ref()
+                .and_then(|session| session.start_time)
+                .or(self.track.start_time()),
+            external_id: filename.map(|filename| filename.to_string()),
+            device_name: self.device_name.clone(),
+            laps: self.strava_laps(id),
+        }
+    }
+}
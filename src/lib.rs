@@ -1,49 +1,94 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use chrono::Utc;
 
 use data_types::{
+    common::DocumentId,
     gc::route::Route,
     strava::{
-        activity::Activity,
+        activity::{Activity, Split},
         athlete::{AthleteData, AthleteId, AthleteTokens},
+        segment::SegmentHistory,
+        telemetry::Telemetry,
     },
 };
 use database::{
     gc_db::GCDB,
-    strava_db::{AthletesCollection, StravaDB},
+    storage::{AthletesStorage, QueryResult},
+    strava_db::StravaDB,
+    DatabaseBackend,
 };
+use export::{geojson::GeoJsonWriter, gpx::GpxWriter, kml::KmlWriter, tcx::TcxWriter};
+use import::ImportResult;
 use mongodb::bson;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde_derive::Serialize;
+use sources::{directory::DirectorySource, ActivitySource};
 use util::facilities::DependenciesBuilder;
 
 use processors::{
+    import_activities::{ActivityImporter, ImportReport},
     DataCreationPipelineOptions, DataPipeline, PipelineOperationType, SubOperationType,
 };
-use strava::api::StravaApi;
+use strava::{
+    api::StravaApi,
+    cassette::CassetteClient,
+    error::{StravaApiError, StravaResult},
+    http::{TransportConfig, TransportError},
+};
 
-use crate::util::logging;
+use crate::config::Config;
 
+pub mod config;
 pub mod data_types;
 mod database;
+pub mod export;
+pub mod import;
 mod processors;
-mod strava;
+pub mod sources;
+pub mod strava;
 mod util;
 
+// The log macros are used by the binaries as well
+pub use util::logging;
+
+// Returned by the queries of the local server
+pub use database::storage::QueryError;
+
+// Every App shares them when the database is in memory, otherwise each App would start empty
+static MEMORY_STRAVA_DB: Lazy<Arc<StravaDB>> = Lazy::new(|| Arc::new(StravaDB::in_memory()));
+static MEMORY_GC_DB: Lazy<Arc<GCDB>> = Lazy::new(|| Arc::new(GCDB::in_memory()));
+
+// Shared by all TokenExchange instances so an athlete's tokens are refreshed by one of them at a time
+static REFRESH_LOCKS: Lazy<Mutex<HashMap<AthleteId, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 pub struct TokenExchange {
     athlete_id: AthleteId,
-    athletes_collection: AthletesCollection,
+    athletes_collection: Arc<dyn AthletesStorage>,
     tokens: RwLock<AthleteTokens>,
 }
 
 impl TokenExchange {
+    const CC: &str = "TokenExchange";
+
     async fn new(
-        athletes_collection: AthletesCollection,
+        athletes_collection: Arc<dyn AthletesStorage>,
         athlete_id: AthleteId,
         athlete_tokens: AthleteTokens,
     ) -> TokenExchange {
-        return Self {
+        Self {
             athlete_id,
             athletes_collection,
             tokens: RwLock::new(athlete_tokens),
-        };
+        }
     }
 
     fn get_tokens(&self) -> AthleteTokens {
@@ -54,8 +99,113 @@ impl TokenExchange {
         self.athletes_collection
             .set_athlete_tokens(self.athlete_id, tokens)
             .await;
+        self.set_cached_tokens(tokens);
+    }
+
+    fn set_cached_tokens(&self, tokens: &AthleteTokens) {
         *self.tokens.write().unwrap() = tokens.clone();
     }
+
+    async fn get_stored_tokens(&self) -> Option<AthleteTokens> {
+        self.athletes_collection
+            .get_athlete_data(self.athlete_id)
+            .await
+            .map(|athlete_data| athlete_data.tokens)
+    }
+
+    fn refresh_lock(athlete_id: AthleteId) -> Arc<tokio::sync::Mutex<()>> {
+        let mut refresh_locks = REFRESH_LOCKS.lock().unwrap();
+
+        Arc::clone(refresh_locks.entry(athlete_id).or_default())
+    }
+
+    // Refreshing a bit before expiry avoids tokens expiring while requests are in flight
+    fn needs_refresh(tokens: &AthleteTokens) -> bool {
+        let margin = Config::global().strava.token_refresh_margin_secs as i64;
+
+        Utc::now().timestamp() + margin >= tokens.expires_at
+    }
+
+    // Strava answers 400 for a refresh token which is no longer valid
+    fn is_rejected_refresh(err: &StravaApiError) -> bool {
+        match err {
+            StravaApiError::Unauthorized => true,
+            StravaApiError::Api { status, .. } => *status == 400,
+            _ => false,
+        }
+    }
+
+    // 'refresh' exchanges the given refresh token for new tokens, only one refresh per athlete runs at a time
+    async fn refresh_if_expiring<F, Fut>(&self, refresh: F) -> StravaResult<()>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = StravaResult<AthleteTokens>>,
+    {
+        if !TokenExchange::needs_refresh(&self.get_tokens()) {
+            return Ok(());
+        }
+
+        let refresh_lock = TokenExchange::refresh_lock(self.athlete_id);
+        let _refresh_guard = refresh_lock.lock().await;
+
+        // Someone else (another App or process) might have refreshed them while we waited
+        let tokens = self
+            .get_stored_tokens()
+            .await
+            .unwrap_or_else(|| self.get_tokens());
+
+        if !TokenExchange::needs_refresh(&tokens) {
+            self.set_cached_tokens(&tokens);
+            return Ok(());
+        }
+
+        logln!("Tokens expiring. Refreshing");
+
+        let err = match refresh(tokens.refresh_token.clone()).await {
+            Ok(new_tokens) => {
+                self.set_tokens(&new_tokens).await;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        if !TokenExchange::is_rejected_refresh(&err) {
+            return Err(err);
+        }
+
+        // Refresh token was already rotated by a refresh we could not coordinate with,
+        // the new one has to be in the database
+        let Some(stored_tokens) = self.get_stored_tokens().await else {
+            return Err(err);
+        };
+
+        if stored_tokens.refresh_token == tokens.refresh_token {
+            return Err(err);
+        }
+
+        logln!("Refresh token was rotated elsewhere, using the stored one");
+        self.set_cached_tokens(&stored_tokens);
+
+        if TokenExchange::needs_refresh(&stored_tokens) {
+            let new_tokens = refresh(stored_tokens.refresh_token.clone()).await?;
+            self.set_tokens(&new_tokens).await;
+        }
+
+        Ok(())
+    }
+}
+
+// What was removed when purging an athlete's data
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeReport {
+    pub athlete_id: AthleteId,
+    pub athlete_records: u64,
+    pub activities: u64,
+    pub telemetries: u64,
+    pub failed_downloads: u64,
+    pub sync_jobs: u64,
+    pub routes_deleted: u64,
+    pub shared_routes_updated: u64,
 }
 
 pub struct App {
@@ -68,21 +218,36 @@ pub struct App {
 impl App {
     const CC: &str = "App";
 
-    fn get_db_url() -> String {
-        // localhost does not have an env var set for this only server config
-        if let Ok(mongo_db_url) = std::env::var("MONGO_DB_URL") {
-            return mongo_db_url;
+    async fn connect_strava_db() -> Arc<StravaDB> {
+        let db_config = &Config::global().database;
+
+        match db_config.backend {
+            DatabaseBackend::Mongodb => {
+                Arc::new(StravaDB::new(&db_config.url, &db_config.strava_db).await)
+            }
+            DatabaseBackend::Memory => Arc::clone(&MEMORY_STRAVA_DB),
+        }
+    }
+
+    async fn connect_gc_db() -> Arc<GCDB> {
+        let db_config = &Config::global().database;
+
+        match db_config.backend {
+            DatabaseBackend::Mongodb => Arc::new(GCDB::new(&db_config.url, &db_config.gc_db).await),
+            DatabaseBackend::Memory => Arc::clone(&MEMORY_GC_DB),
         }
+    }
 
-        return "mongodb://localhost:27017".to_string();
+    fn get_strava_transport_config() -> TransportConfig {
+        TransportConfig::from_config(&Config::global().strava)
     }
 
     pub async fn anonym_athlete() -> App {
         Self {
             loggedin_athlete_id: None,
             strava_api: None,
-            strava_db: Arc::new(StravaDB::new(&App::get_db_url()).await),
-            gc_db: Arc::new(GCDB::new(&App::get_db_url()).await),
+            strava_db: App::connect_strava_db().await,
+            gc_db: App::connect_gc_db().await,
         }
     }
 
@@ -90,8 +255,8 @@ impl App {
         let mut this = Self {
             loggedin_athlete_id: Some(athlete_id),
             strava_api: None,
-            strava_db: Arc::new(StravaDB::new(&App::get_db_url()).await),
-            gc_db: Arc::new(GCDB::new(&App::get_db_url()).await),
+            strava_db: App::connect_strava_db().await,
+            gc_db: App::connect_gc_db().await,
         };
 
         if let Some(athlete_data) = this.strava_db.athletes.get_athlete_data(athlete_id).await {
@@ -102,7 +267,21 @@ impl App {
             )
             .await;
 
-            this.strava_api = Some(Arc::new(StravaApi::new(token_exchange, athlete_id)));
+            let transport = App::get_strava_transport_config();
+            let http_client = match CassetteClient::from_config(&transport) {
+                Ok(http_client) => http_client,
+                Err(err) => {
+                    logln!("No Strava client for athlete {}: {}", athlete_id, err);
+                    return None;
+                }
+            };
+
+            this.strava_api = Some(Arc::new(StravaApi::with_transport(
+                token_exchange,
+                athlete_id,
+                transport,
+                http_client,
+            )));
 
             return Some(this);
         }
@@ -110,17 +289,21 @@ impl App {
         None
     }
 
+    pub fn athlete_id(&self) -> Option<AthleteId> {
+        self.loggedin_athlete_id
+    }
+
     pub async fn query_activities(
         &self,
         stages: Vec<bson::Document>,
-    ) -> Vec<mongodb::bson::Document> {
+    ) -> QueryResult<Vec<mongodb::bson::Document>> {
         self.strava_db
             .activities
             .query_activities_docs(stages)
             .await
     }
 
-    pub async fn query_routes(&self, stages: Vec<bson::Document>) -> Vec<Route> {
+    pub async fn query_routes(&self, stages: Vec<bson::Document>) -> QueryResult<Vec<Route>> {
         self.gc_db.routes.query(stages).await
     }
 
@@ -128,44 +311,194 @@ impl App {
         self.gc_db.statistics.query().await
     }
 
-    pub async fn get_activity(&self, id: i64) -> Option<Activity> {
-        self.strava_db.activities.get(id).await
+    // Private activities are neither served nor exported
+    pub async fn get_activity(&self, act_id: i64) -> Option<Activity> {
+        self.strava_db
+            .activities
+            .get(act_id)
+            .await
+            .filter(|activity| !activity.private)
     }
 
-    pub async fn on_new_activity(&self, act_id: i64) {
-        self.create_data_pipeline().on_new_activity(act_id).await;
+    // None if the activity or its telemetry is not stored
+    pub async fn export_activity_tcx(&self, act_id: i64) -> Option<String> {
+        let activity = self.get_activity(act_id).await?;
+        let telemetry = self.strava_db.telemetries.get(act_id).await?;
+
+        Some(TcxWriter::write(&activity, &telemetry))
     }
 
-    pub async fn on_delete_activity(&self, act_id: i64) {
-        self.strava_db.activities.delete(act_id).await;
-
-        // Iterate over the routes and find the ones that contain the deleted activity
-        // if the route contains only one activity, then delete route
-        // else remove activity id from list and update
-        let mut existing_routes = self
-            .gc_db
-            .routes
-            .get_athlete_routes(self.loggedin_athlete_id.unwrap())
+    // None if the activity or its telemetry is not stored or it has no GPS data
+    pub async fn export_activity_gpx(&self, act_id: i64) -> Option<String> {
+        let activity = self.get_activity(act_id).await?;
+        let telemetry = self.strava_db.telemetries.get(act_id).await?;
+
+        GpxWriter::write_activity(&activity, &telemetry)
+    }
+
+    // Gradient indexes point into the master activity's telemetry, so the route is drawn from it
+    pub async fn export_route_gpx(&self, route_id: i64) -> Option<String> {
+        let route = self.gc_db.routes.get(route_id).await?;
+        let telemetry = self
+            .strava_db
+            .telemetries
+            .get(route.master_activity_id)
+            .await?;
+
+        GpxWriter::write_route(&route, &telemetry)
+    }
+
+    // FeatureCollection with the route, its gradients and segment efforts, None if the route does not exist
+    pub async fn export_route_geojson(&self, route_id: i64) -> Option<serde_json::Value> {
+        let route = self.gc_db.routes.get(route_id).await?;
+        let master_activity = self
+            .strava_db
+            .activities
+            .get(route.master_activity_id)
             .await;
 
-        while existing_routes.advance().await.unwrap() {
-            let mut route = existing_routes.deserialize_current().unwrap();
+        Some(GeoJsonWriter::write_route(&route, master_activity.as_ref()))
+    }
 
-            if let Some(found_idx) = route.activities.iter().position(|id| *id == act_id) {
-                if route.activities.len() == 1 {
-                    self.gc_db.routes.delete(&route).await;
-                } else {
-                    route.activities.remove(found_idx);
-                    self.gc_db.routes.update(&route).await;
-                }
-            }
+    pub async fn export_athlete_routes_geojson(&self, athlete_id: AthleteId) -> serde_json::Value {
+        let routes = self.gc_db.routes.get_athlete_routes(athlete_id).await;
+
+        GeoJsonWriter::write_routes(&routes)
+    }
+
+    pub async fn export_activity_kml(&self, act_id: i64) -> Option<String> {
+        let activity = self.get_activity(act_id).await?;
+        let telemetry = self.strava_db.telemetries.get(act_id).await?;
+
+        KmlWriter::write_activity(&activity, &telemetry)
+    }
+
+    // 3D track from the master activity's telemetry, the polyline if it is missing
+    pub async fn export_route_kml(&self, route_id: i64) -> Option<String> {
+        let route = self.gc_db.routes.get(route_id).await?;
+        let telemetry = self
+            .strava_db
+            .telemetries
+            .get(route.master_activity_id)
+            .await;
+
+        Some(KmlWriter::write_route(&route, telemetry.as_ref()))
+    }
+
+    // The athlete's route library for Google Earth, a folder per sport
+    pub async fn export_athlete_routes_kmz(&self, athlete_id: AthleteId) -> Vec<u8> {
+        let mut routes: Vec<(Route, Option<Telemetry>)> = Vec::new();
+
+        for route in self.gc_db.routes.get_athlete_routes(athlete_id).await {
+            let telemetry = self
+                .strava_db
+                .telemetries
+                .get(route.master_activity_id)
+                .await;
+
+            routes.push((route, telemetry));
         }
+
+        KmlWriter::kmz(&KmlWriter::write_routes(
+            &format!("Routes of athlete {}", athlete_id),
+            &routes,
+        ))
     }
 
-    // Currently unused, to be used when new athletes are uploaded or db rewritten
-    pub async fn create_athlete(&self, id: i64) -> AthleteData {
-        let mut default_athlete: AthleteData = Default::default();
-        default_athlete._id = id;
+    // Per km splits, None if the activity is not stored
+    pub async fn get_activity_splits(&self, act_id: i64) -> Option<Vec<Split>> {
+        self.get_activity(act_id)
+            .await
+            .map(|activity| activity.splits_metric)
+    }
+
+    // Segment with the athlete's efforts on it, None if the segment was never synced
+    pub async fn get_segment_history(
+        &self,
+        athlete_id: AthleteId,
+        seg_id: i64,
+    ) -> Option<SegmentHistory> {
+        let segment = self.strava_db.segments.get(seg_id).await?;
+        let efforts = self
+            .strava_db
+            .activities
+            .get_athlete_segment_efforts(athlete_id, seg_id)
+            .await;
+
+        Some(SegmentHistory { segment, efforts })
+    }
+
+    pub async fn on_new_activity(&self, act_id: i64) -> StravaResult<()> {
+        self.create_data_pipeline().on_new_activity(act_id).await
+    }
+
+    pub async fn on_delete_activity(&self, act_id: i64) {
+        self.create_data_pipeline().on_delete_activity(act_id).await;
+    }
+
+    // Re-downloads the activity and re-runs the pipeline stages affected by the changed fields
+    pub async fn on_update_activity(&self, act_id: i64) -> StravaResult<()> {
+        self.create_data_pipeline().on_update_activity(act_id).await
+    }
+
+    // Err when Strava could not be asked, e.g. it is down
+    pub async fn is_strava_access_revoked(&self) -> StravaResult<bool> {
+        match &self.strava_api {
+            Some(strava_api) => strava_api.is_access_revoked().await,
+            None => Ok(false),
+        }
+    }
+
+    // Removes everything stored about the athlete, used when access is revoked
+    pub async fn purge_athlete(&self, athlete_id: AthleteId) -> PurgeReport {
+        let act_ids = self
+            .strava_db
+            .activities
+            .get_athlete_activity_ids(athlete_id)
+            .await;
+
+        let mut report = PurgeReport {
+            athlete_id,
+            ..Default::default()
+        };
+
+        report.routes_deleted = self.gc_db.routes.delete_athlete_routes(athlete_id).await;
+
+        // Routes of other athletes must not keep references to the purged activities
+        let (shared_routes_updated, shared_routes_deleted) =
+            self.gc_db.routes.remove_activities(&act_ids).await;
+        report.shared_routes_updated = shared_routes_updated;
+        report.routes_deleted += shared_routes_deleted;
+
+        report.telemetries = self
+            .strava_db
+            .telemetries
+            .delete_athlete_telemetries(athlete_id)
+            .await;
+        report.activities = self
+            .strava_db
+            .activities
+            .delete_athlete_activities(athlete_id)
+            .await;
+        report.failed_downloads = self
+            .strava_db
+            .failed_downloads
+            .delete_athlete_failed_downloads(athlete_id)
+            .await;
+        report.sync_jobs = self.strava_db.sync_jobs.delete(athlete_id).await;
+        report.athlete_records = self.strava_db.athletes.delete(athlete_id).await;
+
+        logln!("Purged athlete data {:?}", report);
+
+        report
+    }
+
+    pub async fn create_athlete(&self, id: i64, tokens: AthleteTokens) -> AthleteData {
+        let default_athlete = AthleteData {
+            _id: id,
+            tokens,
+            ..Default::default()
+        };
         self.strava_db
             .athletes
             .set_athlete_data(&default_athlete)
@@ -174,24 +507,433 @@ impl App {
         default_athlete
     }
 
+    pub async fn registered_athletes(&self) -> Vec<AthleteId> {
+        self.strava_db.athletes.get_athlete_ids().await
+    }
+
+    pub fn strava_authorize_url(redirect_uri: &str, state: &str) -> StravaResult<String> {
+        StravaApi::authorize_url(redirect_uri, state)
+    }
+
+    // Random value sent through the OAuth flow, callbacks not carrying it back are rejected
+    pub fn new_oauth_state() -> String {
+        rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect()
+    }
+
+    // Completes the OAuth flow: exchanges the authorization code and stores the athlete's tokens
+    pub async fn register_athlete(code: &str) -> StravaResult<App> {
+        let transport = App::get_strava_transport_config();
+        let http_client = CassetteClient::from_config(&transport).map_err(|err| {
+            StravaApiError::Transport(TransportError {
+                message: err.to_string(),
+                is_timeout: false,
+            })
+        })?;
+
+        let (athlete_id, tokens) =
+            StravaApi::exchange_authorization_code(&transport, http_client.as_ref(), code).await?;
+
+        let app = App::anonym_athlete().await;
+
+        if app
+            .strava_db
+            .athletes
+            .get_athlete_data(athlete_id)
+            .await
+            .is_some()
+        {
+            // Athlete connected again, keep the sync progress and only replace the tokens
+            app.strava_db
+                .athletes
+                .set_athlete_tokens(athlete_id, &tokens)
+                .await;
+        } else {
+            app.create_athlete(athlete_id, tokens).await;
+        }
+
+        Ok(App::with_athlete(athlete_id).await.unwrap())
+    }
+
+    // Loads a Strava bulk export, then matches and processes routes as for synced activities
+    pub async fn import_strava_archive(&self, path: &Path) -> ImportResult<ImportReport> {
+        let report = self.create_activity_importer().import_archive(path).await?;
+
+        self.update_routes().await;
+
+        Ok(report)
+    }
+
+    // Stores an activity recorded outside Strava, update_routes makes it part of the routes
+    pub async fn import_gpx(
+        &self,
+        content: &str,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        self.create_activity_importer()
+            .import_gpx(content, r#type, filename)
+            .await
+    }
+
+    // Same as import_gpx for files from Garmin, Wahoo and other head units
+    pub async fn import_fit(
+        &self,
+        content: &[u8],
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        self.create_activity_importer()
+            .import_fit(content, r#type, filename)
+            .await
+    }
+
+    pub async fn import_tcx(
+        &self,
+        content: &str,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        self.create_activity_importer()
+            .import_tcx(content, r#type, filename)
+            .await
+    }
+
+    // Matches activities not part of a route yet and processes the changed routes
+    pub async fn update_routes(&self) {
+        self.create_data_pipeline()
+            .start(&DataCreationPipelineOptions {
+                route_matching: PipelineOperationType::Enabled(SubOperationType::Update),
+                route_processor: PipelineOperationType::Enabled(SubOperationType::None),
+                ..Default::default()
+            })
+            .await;
+    }
+
     pub async fn start_data_pipeline(&self) {
         self.create_data_pipeline()
             .start(&DataCreationPipelineOptions {
                 activity_syncer: PipelineOperationType::Enabled(SubOperationType::None),
+                telemetry_backfill: if Config::global().pipeline.backfill_streams {
+                    PipelineOperationType::Enabled(SubOperationType::None)
+                } else {
+                    PipelineOperationType::Disabled
+                },
                 route_matching: PipelineOperationType::Enabled(SubOperationType::Update),
                 route_processor: PipelineOperationType::Enabled(SubOperationType::None),
             })
             .await;
     }
 
-    fn create_data_pipeline(&self) -> DataPipeline {
-        DataPipeline::new(
+    // Syncs the athlete's activities from a single source and matches the new ones to routes
+    pub async fn sync_from_source(&self, source: Arc<dyn ActivitySource>) {
+        self.create_data_pipeline()
+            .with_sources(vec![source])
+            .start(&DataCreationPipelineOptions {
+                activity_syncer: PipelineOperationType::Enabled(SubOperationType::None),
+                route_matching: PipelineOperationType::Enabled(SubOperationType::Update),
+                route_processor: PipelineOperationType::Enabled(SubOperationType::None),
+                ..Default::default()
+            })
+            .await;
+    }
+
+    // Polls the configured directories, files dropped in an athlete's sub folder are synced as their activities
+    pub async fn watch_activity_directories() {
+        let sources_config = &Config::global().sources;
+
+        if sources_config.directories.is_empty() {
+            return;
+        }
+
+        let app = App::anonym_athlete().await;
+        // Kept between polls so only changed files are parsed again
+        let mut watched: HashMap<(String, AthleteId), Arc<DirectorySource>> = HashMap::new();
+
+        logln!(
+            "Watching {} for activity files",
+            sources_config.directories.join(", ")
+        );
+
+        loop {
+            for athlete_id in app.registered_athletes().await {
+                for directory in &sources_config.directories {
+                    let source = watched
+                        .entry((directory.clone(), athlete_id))
+                        .or_insert_with(|| {
+                            Arc::new(DirectorySource::for_athlete(
+                                Path::new(directory),
+                                athlete_id,
+                            ))
+                        })
+                        .clone();
+
+                    if source.scan() == 0 {
+                        continue;
+                    }
+
+                    logln!("New files in {} for athlete {}", source.name(), athlete_id);
+
+                    let athlete_app = App {
+                        loggedin_athlete_id: Some(athlete_id),
+                        strava_api: None,
+                        strava_db: app.strava_db.clone(),
+                        gc_db: app.gc_db.clone(),
+                    };
+
+                    athlete_app.sync_from_source(source).await;
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(sources_config.directory_poll_secs)).await;
+        }
+    }
+
+    fn create_activity_importer(&self) -> ActivityImporter {
+        ActivityImporter::new(
             DependenciesBuilder::new()
-                .with_gc_db(&self.gc_db)
                 .with_strava_db(&self.strava_db)
-                .with_strava_api(&self.strava_api.clone().unwrap())
                 .build(),
             self.loggedin_athlete_id.unwrap(),
         )
     }
+
+    fn create_data_pipeline(&self) -> DataPipeline {
+        let mut dependencies = DependenciesBuilder::new();
+        dependencies
+            .with_gc_db(&self.gc_db)
+            .with_strava_db(&self.strava_db);
+
+        // Activities from other sources are synced without Strava
+        if let Some(strava_api) = &self.strava_api {
+            dependencies.with_strava_api(strava_api);
+        }
+
+        DataPipeline::new(dependencies.build(), self.loggedin_athlete_id.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        import::tcx::TcxParser,
+        strava::{
+            http::{HttpClient, HttpMethod, HttpRequest, HttpResponse},
+            rate_limit::{RateLimitConfig, RateLimiter},
+        },
+    };
+
+    const TWO_LAPS: &str = include_str!("../fixtures/import/two_laps.tcx");
+
+    async fn store_activity(app: &App, act_id: DocumentId, private: bool) {
+        let recorded = TcxParser::parse(TWO_LAPS).unwrap();
+        let mut details = recorded.activity_details(1, TWO_LAPS.as_bytes(), "TCX", None, None);
+        details.set_id(act_id);
+
+        let mut activity =
+            details.to_activity_json(&recorded.summary(), &recorded.track.polyline(), 1);
+        activity["private"] = private.into();
+        let mut telemetry = recorded.track.to_telemetry_json(1, &details.r#type);
+
+        app.strava_db.activities.store(act_id, &mut activity).await;
+        app.strava_db
+            .telemetries
+            .store(act_id, &mut telemetry)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn private_activities_are_not_served() {
+        Config::init_test_global();
+
+        let app = App::anonym_athlete().await;
+        store_activity(&app, 71, false).await;
+        store_activity(&app, 72, true).await;
+
+        assert!(app.get_activity(71).await.is_some());
+        assert!(app.get_activity_splits(71).await.is_some());
+        assert!(app.export_activity_tcx(71).await.is_some());
+
+        assert!(app.get_activity(72).await.is_none());
+        assert!(app.get_activity_splits(72).await.is_none());
+        assert!(app.export_activity_tcx(72).await.is_none());
+        assert!(app.export_activity_gpx(72).await.is_none());
+    }
+
+    // Strava's token endpoint, the 'stale' refresh token was already rotated by another process
+    struct OAuthMock {
+        athlete_id: AthleteId,
+        athletes_collection: Arc<dyn AthletesStorage>,
+        requests: Mutex<Vec<HttpRequest>>,
+    }
+
+    impl OAuthMock {
+        fn refresh_tokens(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|request| request.url.split("refresh_token=").nth(1))
+                .map(|refresh_token| refresh_token.to_string())
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for OAuthMock {
+        async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            self.requests.lock().unwrap().push(request.clone());
+
+            let response = |status: u16, body: serde_json::Value| HttpResponse {
+                status,
+                headers: HashMap::new(),
+                body: body.to_string().into_bytes(),
+            };
+
+            if request.method == HttpMethod::Get {
+                return Ok(response(200, serde_json::json!({})));
+            }
+
+            // Leaves concurrent callers time to line up behind this refresh
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let refresh_token = request.url.split("refresh_token=").nth(1).unwrap();
+
+            if refresh_token == "stale" {
+                self.athletes_collection
+                    .set_athlete_tokens(self.athlete_id, &tokens("rotated", 0))
+                    .await;
+
+                return Ok(response(
+                    400,
+                    serde_json::json!({"message": "Bad Request", "errors": [{"code": "invalid"}]}),
+                ));
+            }
+
+            Ok(response(
+                200,
+                serde_json::json!({
+                    "access_token": format!("{}_next_access", refresh_token),
+                    "refresh_token": format!("{}_next", refresh_token),
+                    "expires_at": Utc::now().timestamp() + 6 * 3600,
+                }),
+            ))
+        }
+    }
+
+    fn tokens(refresh_token: &str, expires_at: i64) -> AthleteTokens {
+        AthleteTokens {
+            access_token: format!("{}_access", refresh_token),
+            refresh_token: refresh_token.to_string(),
+            expires_at,
+        }
+    }
+
+    // Athlete stored with expired tokens
+    async fn oauth_mock(
+        strava_db: &StravaDB,
+        athlete_id: AthleteId,
+        refresh_token: &str,
+    ) -> Arc<OAuthMock> {
+        Config::init_test_global();
+
+        let athletes_collection = strava_db.get_athletes_collection();
+        athletes_collection
+            .set_athlete_data(&AthleteData {
+                _id: athlete_id,
+                tokens: tokens(refresh_token, 0),
+                ..Default::default()
+            })
+            .await;
+
+        Arc::new(OAuthMock {
+            athlete_id,
+            athletes_collection,
+            requests: Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn mock_api(
+        strava_db: &StravaDB,
+        athlete_id: AthleteId,
+        mock: &Arc<OAuthMock>,
+    ) -> StravaApi {
+        let tokens = strava_db
+            .get_athletes_collection()
+            .get_athlete_data(athlete_id)
+            .await
+            .unwrap()
+            .tokens;
+        let token_exchange =
+            TokenExchange::new(strava_db.get_athletes_collection(), athlete_id, tokens).await;
+
+        StravaApi::with_transport(
+            token_exchange,
+            athlete_id,
+            TransportConfig::default(),
+            mock.clone(),
+        )
+        .with_rate_limiter(Arc::new(RateLimiter::new(RateLimitConfig::default())))
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_token_refresh() {
+        let strava_db = StravaDB::in_memory();
+        let mock = oauth_mock(&strava_db, 81, "initial").await;
+
+        let first_api = mock_api(&strava_db, 81, &mock).await;
+        let second_api = mock_api(&strava_db, 81, &mock).await;
+
+        let (first, second) = tokio::join!(first_api.get_athlete(), second_api.get_athlete());
+        assert!(first.is_ok() && second.is_ok());
+
+        assert_eq!(mock.refresh_tokens(), vec!["initial"]);
+        assert_eq!(
+            strava_db
+                .get_athletes_collection()
+                .get_athlete_data(81)
+                .await
+                .unwrap()
+                .tokens
+                .refresh_token,
+            "initial_next"
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_token_rotated_elsewhere_is_picked_up() {
+        let strava_db = StravaDB::in_memory();
+        let mock = oauth_mock(&strava_db, 82, "stale").await;
+        let strava_api = mock_api(&strava_db, 82, &mock).await;
+
+        strava_api.get_athlete().await.unwrap();
+
+        // Rejected stale token, then the rotated one read back from the database
+        assert_eq!(mock.refresh_tokens(), vec!["stale", "rotated"]);
+
+        let requests = mock.requests.lock().unwrap().clone();
+        assert_eq!(
+            requests.last().unwrap().headers,
+            vec![(
+                "Authorization".to_string(),
+                "Bearer rotated_next_access".to_string()
+            )]
+        );
+        assert_eq!(
+            strava_db
+                .get_athletes_collection()
+                .get_athlete_data(82)
+                .await
+                .unwrap()
+                .tokens
+                .refresh_token,
+            "rotated_next"
+        );
+    }
 }
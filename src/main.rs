@@ -1,9 +1,21 @@
-use ground_covered::App;
+use ground_covered::{config::Config, App};
 
 #[tokio::main]
 async fn main() {
     std::env::set_var("RUST_BACKTRACE", "0");
 
-    let app = App::with_athlete(4399230).await.unwrap();
-    app.start_data_pipeline().await;
+    match Config::load(std::env::args().skip(1)) {
+        Ok(config) => Config::init_global(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Athletes get registered through the OAuth flow in local_server
+    for athlete_id in App::anonym_athlete().await.registered_athletes().await {
+        if let Some(app) = App::with_athlete(athlete_id).await {
+            app.start_data_pipeline().await;
+        }
+    }
 }
@@ -65,7 +65,7 @@ pub struct Commonality {
     acts_unique: HashSet<DocumentId>,
 }
 
-impl<'a> Commonality {
+impl Commonality {
     const CC: &str = "Commonality";
 
     // Used for update procedure so we know where to start creating new indexes from
@@ -74,18 +74,17 @@ impl<'a> Commonality {
     }
 
     // Using stored data returnes the maximum match index between master_act_id and an activity from the dest set
-    pub fn is_matched(&self, master_act_id: DocumentId, dest: &Vec<DocumentId>) -> bool {
+    pub fn is_matched(&self, master_act_id: DocumentId, dest: &[DocumentId]) -> bool {
         let results = self.generate_match_results();
 
         let mut max_match_index = 0;
 
         results.iter().for_each(|result| {
-            if (result.1 == master_act_id && dest.contains(&result.2))
-                || (result.2 == master_act_id && dest.contains(&result.1))
+            if ((result.1 == master_act_id && dest.contains(&result.2))
+                || (result.2 == master_act_id && dest.contains(&result.1)))
+                && result.0 > max_match_index
             {
-                if result.0 > max_match_index {
-                    max_match_index = result.0;
-                }
+                max_match_index = result.0;
             }
         });
 
@@ -93,7 +92,7 @@ impl<'a> Commonality {
     }
 
     pub fn load_telemetry(&mut self, telemetry: &Telemetry) -> bool {
-        if telemetry.latlng.data.len() == 0 {
+        if telemetry.latlng.data.is_empty() {
             return false;
         }
 
@@ -141,7 +140,7 @@ impl<'a> Commonality {
             }
         }
 
-        return true;
+        true
     }
 
     // Output is vector of routes which contain matched activities or standalone ones (that don't have any match)
@@ -184,7 +183,7 @@ impl<'a> Commonality {
             })
         });
 
-        results.sort_by_key(|k| std::cmp::Reverse(100 * k.0 as i32));
+        results.sort_by_key(|k| std::cmp::Reverse(100 * k.0));
 
         results
     }
@@ -281,7 +280,7 @@ impl<'a> Commonality {
                     let group_info = groups.entry(group_id).or_default();
                     group_info.activities.get_mut().insert(*act_id);
 
-                    let mut distribution = group_info
+                    let distribution = group_info
                         .match_distribution
                         .get_mut()
                         .entry(match_percentage)
@@ -306,7 +305,7 @@ impl<'a> Commonality {
                             .get_mut()
                             .insert(*dest_member_id);
 
-                        act_to_group.remove(&dest_member_id);
+                        act_to_group.remove(dest_member_id);
                         act_to_group.insert(*dest_member_id, src_group_id);
                     }
 
@@ -360,7 +359,7 @@ impl<'a> Commonality {
         for (_, group_info) in groups {
             let group = group_info.activities.take();
 
-            if group.len() == 0 {
+            if group.is_empty() {
                 continue;
             }
 
@@ -411,3 +410,95 @@ impl<'a> Commonality {
         resulting_routes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // A straight track of points spaced by about 110m going north from the start
+    fn telemetry(act_id: DocumentId, r#type: &str, start: [f32; 2], points: usize) -> Telemetry {
+        let latlng: Vec<[f32; 2]> = (0..points)
+            .map(|index| [start[0] + index as f32 * 0.001, start[1]])
+            .collect();
+        let distance: Vec<f32> = (0..points).map(|index| index as f32 * 111.).collect();
+
+        serde_json::from_value(json!({
+            "_id": act_id,
+            "type": r#type,
+            "latlng": { "data": latlng },
+            "distance": { "data": distance },
+        }))
+        .unwrap()
+    }
+
+    fn sorted_groups(routes: &[Route]) -> Vec<Vec<DocumentId>> {
+        let mut groups: Vec<Vec<DocumentId>> = routes
+            .iter()
+            .map(|route| {
+                let mut activities = route.activities.clone();
+                activities.sort();
+                activities
+            })
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn identical_tracks_are_grouped_in_one_route() {
+        let mut processor = Commonality::default();
+        processor.set_set_first_route_index(10);
+
+        assert!(processor.load_telemetry(&telemetry(1, "Ride", [44.40, 26.10], 40)));
+        assert!(processor.load_telemetry(&telemetry(2, "Ride", [44.40, 26.10], 40)));
+        assert!(processor.load_telemetry(&telemetry(3, "Ride", [46.50, 6.60], 40)));
+
+        assert!(processor.is_matched(1, &[2]));
+        assert!(!processor.is_matched(1, &[3]));
+
+        let routes = processor.matched_routes();
+
+        assert_eq!(sorted_groups(&routes), vec![vec![1, 2], vec![3]]);
+        assert!(routes.iter().all(|route| route._id > 10.));
+        assert!(routes
+            .iter()
+            .all(|route| route.activities.contains(&route.master_activity_id)));
+    }
+
+    #[test]
+    fn tracks_of_other_types_are_not_grouped() {
+        let mut processor = Commonality::default();
+
+        processor.load_telemetry(&telemetry(1, "Ride", [44.40, 26.10], 40));
+        processor.load_telemetry(&telemetry(2, "Run", [44.40, 26.10], 40));
+
+        assert_eq!(
+            sorted_groups(&processor.matched_routes()),
+            vec![vec![1], vec![2]]
+        );
+    }
+
+    #[test]
+    fn tracks_of_different_lengths_are_not_grouped() {
+        let mut processor = Commonality::default();
+
+        processor.load_telemetry(&telemetry(1, "Ride", [44.40, 26.10], 40));
+        processor.load_telemetry(&telemetry(2, "Ride", [44.40, 26.10], 10));
+
+        assert!(!processor.is_matched(1, &[2]));
+        assert_eq!(
+            sorted_groups(&processor.matched_routes()),
+            vec![vec![1], vec![2]]
+        );
+    }
+
+    #[test]
+    fn telemetries_without_points_are_skipped() {
+        let mut processor = Commonality::default();
+
+        assert!(!processor.load_telemetry(&telemetry(1, "Ride", [44.40, 26.10], 0)));
+        assert!(processor.matched_routes().is_empty());
+    }
+}
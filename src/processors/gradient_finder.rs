@@ -52,7 +52,7 @@ impl GradientFinder {
                 return GradientType::Desc;
             }
 
-            return GradientType::None;
+            GradientType::None
         };
 
         let gradient_flipped = |gradient: f32, type_gradient_curr_in: &GradientType| -> bool {
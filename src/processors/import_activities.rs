@@ -0,0 +1,300 @@
+use std::path::Path;
+
+use mongodb::bson::DateTime;
+use serde_derive::Serialize;
+
+use crate::{
+    data_types::{common::DocumentId, strava::athlete::AthleteId},
+    import::{
+        archive::StravaArchive, fit::FitParser, gpx::GpxParser, tcx::TcxParser, track::Track,
+        ImportResult,
+    },
+    logln,
+    processors::sync_from_strava::StravaDBSync,
+    util::{
+        facilities::{Facilities, Required},
+        geo::GeoUtils,
+    },
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: u64,
+    // Already in the database, either synced from the API or imported before
+    pub skipped_existing: u64,
+    // Manual activities, stored without telemetry
+    pub without_track: u64,
+    // Left for the API sync to download
+    pub failed: Vec<(DocumentId, String)>,
+}
+
+// Writes activities from files with the same schema StravaDBSync produces
+pub struct ActivityImporter {
+    athlete_id: AthleteId,
+    dependencies: Facilities,
+}
+
+impl ActivityImporter {
+    const CC: &str = "ActivityImporter";
+
+    pub fn new(dependencies: Facilities, athlete_id: AthleteId) -> Self {
+        dependencies.check(vec![Required::StravaDB]);
+
+        Self {
+            athlete_id,
+            dependencies,
+        }
+    }
+
+    // Strava's bulk export zip
+    pub async fn import_archive(&self, path: &Path) -> ImportResult<ImportReport> {
+        let mut archive = StravaArchive::open(path)?;
+        let activities = archive.activities()?;
+        let strava_db = self.dependencies.strava_db();
+
+        let mut report: ImportReport = Default::default();
+
+        logln!(
+            "Importing {} activities from {}",
+            activities.len(),
+            path.display()
+        );
+
+        for activity in activities {
+            if strava_db.activities.exists(activity.id).await {
+                report.skipped_existing += 1;
+                continue;
+            }
+
+            let track = match &activity.filename {
+                Some(filename) => match archive.read_track(filename) {
+                    Ok(track) => Some(track),
+                    Err(err) => {
+                        logln!("Skipping activity {}: {}", activity.id, err);
+                        report.failed.push((activity.id, err.to_string()));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let mut activity_json =
+                StravaArchive::build_activity_json(&activity, track.as_ref(), self.athlete_id);
+
+            self.store(
+                activity.id,
+                &activity.r#type,
+                &mut activity_json,
+                track.as_ref(),
+            )
+            .await;
+
+            if track.is_none() {
+                report.without_track += 1;
+            }
+
+            report.imported += 1;
+        }
+
+        logln!("Import done {:?}", report);
+
+        Ok(report)
+    }
+
+    // Single GPX file, uploading the same file again replaces the activity created the first time
+    pub async fn import_gpx(
+        &self,
+        content: &str,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        let track = GpxParser::parse(content)?;
+        let details =
+            GpxParser::activity_details(self.athlete_id, content, &track, r#type, filename);
+
+        let mut activity_json =
+            details.to_activity_json(&track.summary(), &track.polyline(), self.athlete_id);
+
+        self.store(
+            details.id,
+            &details.r#type,
+            &mut activity_json,
+            Some(&track),
+        )
+        .await;
+
+        logln!("Imported GPX {:?} as activity {}", filename, details.id);
+
+        Ok(details.id)
+    }
+
+    // Single FIT file, laps and the totals of the session come from the file instead of being computed
+    pub async fn import_fit(
+        &self,
+        content: &[u8],
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        let activity = FitParser::parse(content)?;
+        let details = activity.activity_details(self.athlete_id, content, "FIT", r#type, filename);
+
+        let mut activity_json = details.to_activity_json(
+            &activity.summary(),
+            &activity.track.polyline(),
+            self.athlete_id,
+        );
+
+        self.store(
+            details.id,
+            &details.r#type,
+            &mut activity_json,
+            Some(&activity.track),
+        )
+        .await;
+
+        logln!("Imported FIT {:?} as activity {}", filename, details.id);
+
+        Ok(details.id)
+    }
+
+    // Single TCX file, laps come from the file like for FIT
+    pub async fn import_tcx(
+        &self,
+        content: &str,
+        r#type: Option<&str>,
+        filename: Option<&str>,
+    ) -> ImportResult<DocumentId> {
+        let activity = TcxParser::parse(content.trim())?;
+        let details =
+            activity.activity_details(self.athlete_id, content.as_bytes(), "TCX", r#type, filename);
+
+        let mut activity_json = details.to_activity_json(
+            &activity.summary(),
+            &activity.track.polyline(),
+            self.athlete_id,
+        );
+
+        self.store(
+            details.id,
+            &details.r#type,
+            &mut activity_json,
+            Some(&activity.track),
+        )
+        .await;
+
+        logln!("Imported TCX {:?} as activity {}", filename, details.id);
+
+        Ok(details.id)
+    }
+
+    async fn store(
+        &self,
+        act_id: DocumentId,
+        r#type: &str,
+        activity_json: &mut serde_json::Value,
+        track: Option<&Track>,
+    ) {
+        let strava_db = self.dependencies.strava_db();
+
+        strava_db.activities.store(act_id, activity_json).await;
+
+        // Done by the date fixer for synced activities
+        if let Some(start_date) = activity_json["start_date"]
+            .as_str()
+            .and_then(|start_date| chrono::DateTime::parse_from_rfc3339(start_date).ok())
+        {
+            strava_db
+                .activities
+                .set_start_date_local_date(
+                    act_id,
+                    &Some(DateTime::from_millis(start_date.timestamp_millis())),
+                )
+                .await;
+        }
+
+        if let Some(track) = track {
+            strava_db
+                .telemetries
+                .store(
+                    act_id,
+                    &mut track.to_telemetry_json(self.athlete_id, r#type),
+                )
+                .await;
+
+            self.remap_laps(act_id).await;
+        }
+    }
+
+    // Done by the segment poly indexer for synced activities
+    async fn remap_laps(&self, act_id: DocumentId) {
+        let strava_db = self.dependencies.strava_db();
+
+        let (Some(mut activity), Some(telemetry)) = (
+            strava_db.activities.get(act_id).await,
+            strava_db.telemetries.get(act_id).await,
+        ) else {
+            return;
+        };
+
+        if activity.laps.is_empty() {
+            return;
+        }
+
+        let indexes_in_polyline =
+            GeoUtils::create_polyline_mapping_table(&activity.map.polyline, &telemetry.latlng.data);
+
+        StravaDBSync::remap_laps(&self.dependencies, &mut activity, &indexes_in_polyline).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        data_types::common::Identifiable, database::strava_db::StravaDB,
+        util::facilities::DependenciesBuilder,
+    };
+
+    const MORNING_RUN: &str = include_str!("../../fixtures/import/morning_run.gpx");
+
+    #[tokio::test]
+    async fn athletes_uploading_the_same_file_get_their_own_activity() {
+        let strava_db = Arc::new(StravaDB::in_memory());
+        let dependencies = DependenciesBuilder::new()
+            .with_strava_db(&strava_db)
+            .build();
+
+        let first_id = ActivityImporter::new(dependencies.clone(), 1)
+            .import_gpx(MORNING_RUN, None, None)
+            .await
+            .unwrap();
+        let second_id = ActivityImporter::new(dependencies.clone(), 2)
+            .import_gpx(MORNING_RUN, None, None)
+            .await
+            .unwrap();
+        let again_id = ActivityImporter::new(dependencies, 1)
+            .import_gpx(MORNING_RUN, None, None)
+            .await
+            .unwrap();
+
+        assert_ne!(first_id, second_id);
+        assert_eq!(again_id, first_id);
+
+        for (athlete_id, act_id) in [(1, first_id), (2, second_id)] {
+            let activities = strava_db
+                .activities
+                .get_athlete_activities(athlete_id)
+                .await;
+
+            assert_eq!(
+                activities
+                    .iter()
+                    .map(|activity| activity.as_i64())
+                    .collect::<Vec<_>>(),
+                vec![act_id]
+            );
+        }
+    }
+}
@@ -1,14 +1,24 @@
 use chrono::Utc;
 use geo_types::Coord;
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Arc};
 
 use crate::{
-    data_types::{common::DocumentId, strava::athlete::AthleteId},
-    logvbln,
+    config::Config,
+    data_types::{
+        common::{DocumentId, Identifiable},
+        strava::{
+            athlete::AthleteId,
+            sync_job::{SyncJob, SyncJobKind, SyncJobState},
+        },
+    },
+    logln, logvbln,
     processors::sync_from_strava::StravaDBSync,
+    sources::{configured_sources, ActivitySource},
+    strava::error::{StravaApiError, StravaResult},
     util::{
         facilities::{Facilities, Required},
         geo::GeoUtils,
+        DateTimeUtils,
     },
 };
 
@@ -16,6 +26,7 @@ use self::commonality::Commonality;
 
 pub mod commonality;
 pub mod gradient_finder;
+pub mod import_activities;
 pub mod sync_from_strava;
 
 #[derive(PartialEq, Default)]
@@ -37,6 +48,7 @@ pub enum PipelineOperationType {
 #[derive(Default)]
 pub struct DataCreationPipelineOptions {
     pub activity_syncer: PipelineOperationType,
+    pub telemetry_backfill: PipelineOperationType,
     pub route_matching: PipelineOperationType,
     pub route_processor: PipelineOperationType,
 }
@@ -44,6 +56,8 @@ pub struct DataCreationPipelineOptions {
 pub struct DataPipeline {
     athlete_id: AthleteId,
     dependencies: Facilities,
+    // Where activities are synced from, in order
+    sources: Vec<Arc<dyn ActivitySource>>,
 }
 
 impl DataPipeline {
@@ -54,10 +68,17 @@ impl DataPipeline {
 
         Self {
             athlete_id,
+            sources: configured_sources(dependencies.optional_strava_api(), athlete_id),
             dependencies,
         }
     }
 
+    // Replaces the sources from the config
+    pub fn with_sources(mut self, sources: Vec<Arc<dyn ActivitySource>>) -> Self {
+        self.sources = sources;
+        self
+    }
+
     pub async fn start(&mut self, options: &DataCreationPipelineOptions) {
         if options.activity_syncer != PipelineOperationType::Disabled {
             // 0 //
@@ -65,6 +86,11 @@ impl DataPipeline {
             self.run_sync_activities().await;
         }
 
+        if options.telemetry_backfill != PipelineOperationType::Disabled {
+            // Re-download streams of activities synced before extended streams were stored
+            self.run_backfill_telemetry().await;
+        }
+
         if options.route_matching != PipelineOperationType::Disabled {
             // 1 //
             // Run route matching module => update routes collections
@@ -87,23 +113,117 @@ impl DataPipeline {
         }
     }
 
-    pub async fn on_new_activity(&mut self, act_id: DocumentId) {
+    pub async fn on_new_activity(&mut self, act_id: DocumentId) -> StravaResult<()> {
         let mut syncer = StravaDBSync::new(self.dependencies.clone(), self.athlete_id);
 
         // Downloads a new activity from Strava API and process it
-        syncer.process_new_activity(act_id).await;
+        syncer.process_new_activity(act_id).await?;
 
         self.run_update_commonalities().await;
 
         self.run_route_processor().await;
+
+        Ok(())
+    }
+
+    pub async fn on_update_activity(&mut self, act_id: DocumentId) -> StravaResult<()> {
+        let mut syncer = StravaDBSync::new(self.dependencies.clone(), self.athlete_id);
+
+        let changes = syncer.refresh_activity(act_id).await?;
+
+        if changes.r#type || changes.private {
+            // Activity has to be matched again against the routes of its new type,
+            // private activities are left out of the routes
+            self.remove_activity_from_routes(act_id).await;
+            self.run_update_commonalities().await;
+        }
+
+        if changes.description || changes.location {
+            self.reset_routes_with_master(act_id).await;
+        }
+
+        if changes.r#type || changes.private || changes.description || changes.location {
+            self.run_route_processor().await;
+        }
+
+        Ok(())
+    }
+
+    pub async fn on_delete_activity(&mut self, act_id: DocumentId) {
+        self.dependencies
+            .strava_db()
+            .activities
+            .delete(act_id)
+            .await;
+
+        self.remove_activity_from_routes(act_id).await;
+
+        // Routes which lost their master activity need to be processed again
+        self.run_route_processor().await;
+    }
+
+    async fn remove_activity_from_routes(&self, act_id: DocumentId) {
+        // Iterate over the routes and find the ones that contain the activity
+        // if the route contains only one activity, then delete route
+        // else remove activity id from list and update
+        let existing_routes = self
+            .dependencies
+            .gc_db()
+            .routes
+            .get_athlete_routes(self.athlete_id)
+            .await;
+
+        for mut route in existing_routes {
+            if route.activities.contains(&act_id) {
+                route.remove_activities(&[act_id]);
+
+                if route.activities.is_empty() {
+                    self.dependencies.gc_db().routes.delete(&route).await;
+                } else {
+                    self.dependencies.gc_db().routes.update(&route).await;
+                }
+            }
+        }
+    }
+
+    // Marks the routes having act_id as master so the route processor extracts their data again
+    async fn reset_routes_with_master(&self, act_id: DocumentId) {
+        let existing_routes = self
+            .dependencies
+            .gc_db()
+            .routes
+            .get_athlete_routes(self.athlete_id)
+            .await;
+
+        for mut route in existing_routes {
+            if route.master_activity_id == act_id {
+                route.r#type = "".to_string();
+                self.dependencies.gc_db().routes.update(&route).await;
+            }
+        }
     }
 
     async fn run_sync_activities(&mut self) {
+        for source in self.sources.clone() {
+            logvbln!("Syncing activities from {}", source.name());
+
+            if source.resumable() {
+                self.run_sync_resumable_source(source).await;
+            } else {
+                self.run_sync_whole_source(source).await;
+            }
+        }
+
+        logvbln!("done syncing.");
+    }
+
+    async fn run_sync_resumable_source(&mut self, source: Arc<dyn ActivitySource>) {
         // Sync =
         // all activities from 0 to before_ts (if before_ts is not 0)
         //  +
         // all activities from after_ts to current timestamp (if interval passed and first stage is completed)
-        let mut syncer = StravaDBSync::new(self.dependencies.clone(), self.athlete_id);
+        let mut syncer =
+            StravaDBSync::with_source(self.dependencies.clone(), self.athlete_id, source);
 
         let athlete_data = self
             .dependencies
@@ -115,39 +235,132 @@ impl DataPipeline {
 
         let (after_ts, before_ts) = (athlete_data.after_ts, athlete_data.before_ts);
 
+        // Downloads which failed in previous runs go first
+        if let Err(err) = syncer.retry_failed_downloads().await {
+            DataPipeline::log_sync_stopped(&err);
+            return;
+        }
+
         logvbln!("sync_athlete_activities {} {}", before_ts, after_ts);
 
-        if before_ts != 0 && after_ts != before_ts {
-            if let (last_activity_ts, false) =
-                syncer.download_activities_in_range(0, before_ts).await
-            {
-                // when done move before to 0 and after to last activity ts
+        let current_ts: i64 = Utc::now().timestamp();
+
+        let mut job = match self
+            .dependencies
+            .strava_db()
+            .sync_jobs
+            .get(self.athlete_id)
+            .await
+        {
+            // A crashed or paused job is resumed before starting a new one
+            Some(job) if job.state != SyncJobState::Completed => {
+                logln!(
+                    "Resuming {:?} sync at page {} ({:?})",
+                    job.kind,
+                    job.page,
+                    job.state
+                );
+                job
+            }
+            _ if before_ts != 0 && after_ts != before_ts => SyncJob::new(
+                self.athlete_id,
+                SyncJobKind::Backfill,
+                0,
+                before_ts,
+                Config::global().pipeline.sync_page_size,
+                current_ts,
+            ),
+            _ => SyncJob::new(
+                self.athlete_id,
+                SyncJobKind::Incremental,
+                after_ts,
+                current_ts,
+                Config::global().pipeline.sync_page_size,
+                current_ts,
+            ),
+        };
+
+        match syncer.run_sync_job(&mut job).await {
+            Ok(()) => {
+                // Backfill done: before goes to 0 and the incremental syncs start where it ended
+                // incremental done: after moves to the end of the job's range
                 self.dependencies
                     .strava_db()
                     .athletes
-                    .save_after_before_timestamps(self.athlete_id, last_activity_ts, 0)
+                    .save_after_before_timestamps(
+                        self.athlete_id,
+                        job.before_ts,
+                        match job.kind {
+                            SyncJobKind::Backfill => 0,
+                            SyncJobKind::Incremental => job.before_ts,
+                        },
+                    )
                     .await;
             }
-        } else {
-            let current_ts: i64 = Utc::now().timestamp();
-            let days_since_last_sync = (current_ts - after_ts) / 86400;
+            Err(err) => DataPipeline::log_sync_stopped(&err),
+        }
+    }
 
-            if days_since_last_sync >= 0 {
-                if let (_, false) = syncer
-                    .download_activities_in_range(after_ts, current_ts)
-                    .await
-                {
-                    // when done move after to current
-                    self.dependencies
-                        .strava_db()
-                        .athletes
-                        .save_after_before_timestamps(self.athlete_id, current_ts, current_ts)
-                        .await;
-                }
+    // Lists everything the source has, activities already stored are skipped
+    async fn run_sync_whole_source(&mut self, source: Arc<dyn ActivitySource>) {
+        let mut syncer =
+            StravaDBSync::with_source(self.dependencies.clone(), self.athlete_id, source);
+
+        if let Err(err) = syncer.retry_failed_downloads().await {
+            DataPipeline::log_sync_stopped(&err);
+            return;
+        }
+
+        let current_ts: i64 = Utc::now().timestamp();
+
+        let mut job = SyncJob::new(
+            self.athlete_id,
+            SyncJobKind::Incremental,
+            0,
+            current_ts,
+            Config::global().pipeline.sync_page_size,
+            current_ts,
+        );
+
+        if let Err(err) = syncer.run_sync_job(&mut job).await {
+            DataPipeline::log_sync_stopped(&err);
+        }
+    }
+
+    async fn run_backfill_telemetry(&mut self) {
+        for mut syncer in self.source_syncers() {
+            if let Err(err) = syncer.backfill_telemetry().await {
+                DataPipeline::log_sync_stopped(&err);
             }
         }
+    }
 
-        logvbln!("done syncing.");
+    // Streams are downloaded again from the source each activity was synced from
+    fn source_syncers(&self) -> Vec<StravaDBSync> {
+        self.sources
+            .iter()
+            .map(|source| {
+                StravaDBSync::with_source(
+                    self.dependencies.clone(),
+                    self.athlete_id,
+                    Arc::clone(source),
+                )
+            })
+            .collect()
+    }
+
+    fn log_sync_stopped(err: &StravaApiError) {
+        if let StravaApiError::RateLimited {
+            retry_after: Some(retry_after),
+        } = err
+        {
+            logln!(
+                "Sync paused by rate limit, next run after {} resumes it",
+                DateTimeUtils::timestamp_to_str(Utc::now().timestamp() + *retry_after as i64)
+            );
+        } else {
+            logln!("Sync stopped: {}", err);
+        }
     }
 
     async fn run_update_commonalities(&self) {
@@ -158,7 +371,7 @@ impl DataPipeline {
         let mut already_grouped_act_ids: HashSet<DocumentId> = HashSet::new();
 
         {
-            let mut existing_routes = self
+            let existing_routes = self
                 .dependencies
                 .gc_db()
                 .routes
@@ -166,9 +379,7 @@ impl DataPipeline {
                 .await;
 
             // Read routes and determine the activity IDs already matched
-            while existing_routes.advance().await.unwrap() {
-                let route = existing_routes.deserialize_current().unwrap();
-
+            for route in existing_routes {
                 // Collect already matched activities
                 route.activities.iter().for_each(|ma| {
                     already_grouped_act_ids.insert(*ma);
@@ -184,41 +395,63 @@ impl DataPipeline {
                 .routes
                 .get_last_route_id(self.athlete_id)
                 .await
-                .unwrap();
+                .unwrap_or(0);
             processor.set_set_first_route_index(last_route_idx + 1);
         }
 
         {
-            let mut syncer = StravaDBSync::new(self.dependencies.clone(), self.athlete_id);
-
-            // Load processor with the missing activities' telemetry
-            let all_activity_ids = self
+            // Load processor with the missing activities' telemetry, private ones are not matched
+            let all_activities: HashSet<DocumentId> = self
                 .dependencies
                 .strava_db()
                 .activities
-                .get_athlete_activity_ids(self.athlete_id)
-                .await;
-            let all_activities: HashSet<DocumentId> = HashSet::from_iter(all_activity_ids);
+                .get_athlete_activities(self.athlete_id)
+                .await
+                .iter()
+                .filter(|activity| !activity.private)
+                .map(|activity| activity.as_i64())
+                .collect();
 
             // Find which activities are not matched by subtracting matched ones from all activities
             let missing_activity_ids: Vec<&DocumentId> = all_activities
                 .difference(&already_grouped_act_ids)
                 .collect();
 
-            let mut count_missing = 0;
-            for index in 0..missing_activity_ids.len() {
-                let missing_activity_id = missing_activity_ids[index];
-                
-                // Ensure telemetry is in DB
-                syncer.download_telemetry(*missing_activity_id).await;
+            // Streams missing from the DB are downloaded up front, several at a time
+            let mut act_ids_without_telemetry: Vec<DocumentId> = Vec::new();
 
-                let telemetry_missing = self
+            for missing_activity_id in &missing_activity_ids {
+                if !self
                     .dependencies
                     .strava_db()
                     .telemetries
-                    .get(*missing_activity_id)
+                    .exists(**missing_activity_id)
                     .await
-                    .unwrap();
+                {
+                    act_ids_without_telemetry.push(**missing_activity_id);
+                }
+            }
+
+            // Activities of sources which are no longer enabled are matched with the streams already stored
+            for syncer in self.source_syncers() {
+                let act_ids = syncer.own_activity_ids(&act_ids_without_telemetry).await;
+
+                if let Err(err) = syncer.download_telemetries(&act_ids).await {
+                    DataPipeline::log_sync_stopped(&err);
+                }
+            }
+
+            let mut count_missing = 0;
+            for missing_activity_id in &missing_activity_ids {
+                let Some(telemetry_missing) = self
+                    .dependencies
+                    .strava_db()
+                    .telemetries
+                    .get(**missing_activity_id)
+                    .await
+                else {
+                    continue;
+                };
 
                 if processor.load_telemetry(&telemetry_missing) {
                     count_missing += 1;
@@ -234,23 +467,23 @@ impl DataPipeline {
             let mut matched_missing_activities = processor.matched_routes();
 
             // Go over the routes again and try to match the master activity of the route with the activities in each group from the missing ones
-            let mut existing_routes = self
+            let existing_routes = self
                 .dependencies
                 .gc_db()
                 .routes
                 .get_athlete_routes(self.athlete_id)
                 .await;
 
-            while existing_routes.advance().await.unwrap() {
-                let mut route = existing_routes.deserialize_current().unwrap();
-
-                let telemetry_master = self
+            for mut route in existing_routes {
+                let Some(telemetry_master) = self
                     .dependencies
                     .strava_db()
                     .telemetries
                     .get(route.master_activity_id)
                     .await
-                    .unwrap();
+                else {
+                    continue;
+                };
 
                 processor.load_telemetry(&telemetry_master);
 
@@ -275,20 +508,20 @@ impl DataPipeline {
                             .activities
                             .extend(missing_group.activities.iter().cloned());
 
-                        matched_missing_activities.remove(index_grouped_route as usize);
+                        matched_missing_activities.remove(index_grouped_route);
                         self.dependencies.gc_db().routes.update(&route).await;
 
                         break;
                     }
                 }
 
-                if matched_missing_activities.len() == 0 {
+                if matched_missing_activities.is_empty() {
                     break;
                 }
             }
 
             // Unmatched groups need to create new routes
-            if matched_missing_activities.len() > 0 {
+            if !matched_missing_activities.is_empty() {
                 println!(
                     "{} routes not merged and need to be created",
                     matched_missing_activities.len()
@@ -313,33 +546,34 @@ impl DataPipeline {
     async fn run_rewrite_commonalities(&self) {
         let mut processor: Commonality = Default::default();
 
-        let mut sorted_activities_cursor = self
+        let sorted_activity_ids = self
             .dependencies
             .strava_db()
             .activities
-            .get_athlete_activities_sorted_distance_asc(self.athlete_id)
+            .get_athlete_activity_ids_sorted_distance_asc(self.athlete_id)
+            .await;
+
+        let private_activity_ids: HashSet<DocumentId> = self
+            .dependencies
+            .strava_db()
+            .activities
+            .get_athlete_activities(self.athlete_id)
             .await
-            .unwrap();
+            .iter()
+            .filter(|activity| activity.private)
+            .map(|activity| activity.as_i64())
+            .collect();
 
         let mut items_to_process = 0;
 
-        while sorted_activities_cursor.advance().await.unwrap() {
-            let mut act_id: DocumentId = 0;
-
-            let res_float = sorted_activities_cursor.current().get_f64("_id");
-
-            if let Ok(id) = res_float {
-                act_id = id as i64;
-            } else {
-                let res_int = sorted_activities_cursor.current().get_i32("_id");
-                if let Ok(id) = res_int {
-                    act_id = id as i64;
-                }
+        for act_id in sorted_activity_ids {
+            if private_activity_ids.contains(&act_id) {
+                continue;
             }
 
             let res_telemetry = self.dependencies.strava_db().telemetries.get(act_id).await;
 
-            if let None = res_telemetry {
+            if res_telemetry.is_none() {
                 continue;
             }
 
@@ -371,17 +605,15 @@ impl DataPipeline {
             pub end_index: i32,
         }
 
-        let mut routes = self
+        let routes = self
             .dependencies
             .gc_db()
             .routes
             .get_athlete_routes(self.athlete_id)
             .await;
 
-        while routes.advance().await.unwrap() {
-            let mut route = routes.deserialize_current().unwrap();
-
-            if route.r#type != "" {
+        for mut route in routes {
+            if !route.r#type.is_empty() {
                 continue;
             }
 
@@ -419,55 +651,49 @@ impl DataPipeline {
                     / 100;
 
             // Get all matched activities and find the gradients
-            if let Some(mut activities) = self
+            let activities = self
                 .dependencies
                 .strava_db()
                 .activities
                 .get_athlete_activities_with_ids(self.athlete_id, &route.activities)
-                .await
-            {
-                while activities.advance().await.unwrap() {
-                    let activity = activities.deserialize_current().unwrap();
-                    let act_id: DocumentId =
-                        crate::data_types::common::Identifiable::as_i64(&activity);
-
-                    let telemetry = self
-                        .dependencies
-                        .strava_db()
-                        .telemetries
-                        .get(act_id)
-                        .await
-                        .unwrap();
-
-                    // Run GradientFinder
-                    if act_id == route.master_activity_id {
-                        let mut gradients =
-                            gradient_finder::GradientFinder::find_gradients(&telemetry);
-
-                        if gradients.len() > 0 {
-                            let remapped_indexes = GeoUtils::create_polyline_mapping_table(
-                                &route.polyline,
-                                &telemetry.latlng.data,
-                            );
-                            gradients.iter_mut().for_each(|gradient| {
-                                // Search through the segment efforts and find a matching start to fill the location data
-                                gradient.location_city = route.location_city.clone();
-                                gradient.location_country = Some(route.location_country.clone());
-
-                                for effort in &activity.segment_efforts {
-                                    if gradient.start_index >= effort.start_index as usize {
-                                        gradient.location_city = effort.segment.city.clone();
-                                        gradient.location_country = effort.segment.country.clone();
-                                    }
+                .await;
+
+            for activity in activities {
+                let act_id: DocumentId = crate::data_types::common::Identifiable::as_i64(&activity);
+
+                // Telemetry download might have failed, it is retried on next sync
+                let Some(telemetry) = self.dependencies.strava_db().telemetries.get(act_id).await
+                else {
+                    continue;
+                };
+
+                // Run GradientFinder
+                if act_id == route.master_activity_id {
+                    let mut gradients = gradient_finder::GradientFinder::find_gradients(&telemetry);
+
+                    if !gradients.is_empty() {
+                        let remapped_indexes = GeoUtils::create_polyline_mapping_table(
+                            &route.polyline,
+                            &telemetry.latlng.data,
+                        );
+                        gradients.iter_mut().for_each(|gradient| {
+                            // Search through the segment efforts and find a matching start to fill the location data
+                            gradient.location_city = route.location_city.clone();
+                            gradient.location_country = Some(route.location_country.clone());
+
+                            for effort in &activity.segment_efforts {
+                                if gradient.start_index >= effort.start_index as usize {
+                                    gradient.location_city = effort.segment.city.clone();
+                                    gradient.location_country = effort.segment.country.clone();
                                 }
+                            }
 
-                                // Rewrite indexes with the remapped ones
-                                gradient.start_index = remapped_indexes[gradient.start_index];
-                                gradient.end_index = remapped_indexes[gradient.end_index];
-                            });
+                            // Rewrite indexes with the remapped ones
+                            gradient.start_index = remapped_indexes[gradient.start_index];
+                            gradient.end_index = remapped_indexes[gradient.end_index];
+                        });
 
-                            route.gradients = gradients;
-                        }
+                        route.gradients = gradients;
                     }
                 }
             }
@@ -476,3 +702,139 @@ impl DataPipeline {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::gc::route::Route,
+        database::{gc_db::GCDB, strava_db::StravaDB},
+        import::tcx::TcxParser,
+        util::facilities::DependenciesBuilder,
+    };
+
+    const ATHLETE_ID: AthleteId = 5;
+    const TWO_LAPS: &str = include_str!("../../fixtures/import/two_laps.tcx");
+
+    fn memory_pipeline() -> DataPipeline {
+        Config::init_test_global();
+
+        let dependencies = DependenciesBuilder::new()
+            .with_strava_db(&Arc::new(StravaDB::in_memory()))
+            .with_gc_db(&Arc::new(GCDB::in_memory()))
+            .build();
+
+        DataPipeline::new(dependencies, ATHLETE_ID).with_sources(Vec::new())
+    }
+
+    // Stores the fixture's activity and streams under act_id, as if imported again
+    async fn store_activity(pipeline: &DataPipeline, act_id: DocumentId, private: bool) {
+        let strava_db = pipeline.dependencies.strava_db();
+        let recorded = TcxParser::parse(TWO_LAPS).unwrap();
+        let mut details = recorded.activity_details(1, TWO_LAPS.as_bytes(), "TCX", None, None);
+        details.set_id(act_id);
+
+        let mut activity =
+            details.to_activity_json(&recorded.summary(), &recorded.track.polyline(), ATHLETE_ID);
+        activity["private"] = private.into();
+        let mut telemetry = recorded
+            .track
+            .to_telemetry_json(ATHLETE_ID, &details.r#type);
+
+        strava_db.activities.store(act_id, &mut activity).await;
+        strava_db.telemetries.store(act_id, &mut telemetry).await;
+    }
+
+    async fn routes(pipeline: &DataPipeline) -> Vec<Route> {
+        pipeline
+            .dependencies
+            .gc_db()
+            .routes
+            .get_athlete_routes(ATHLETE_ID)
+            .await
+    }
+
+    fn sorted_activities(route: &Route) -> Vec<DocumentId> {
+        let mut activities = route.activities.clone();
+        activities.sort();
+        activities
+    }
+
+    fn matching_options(route_matching: SubOperationType) -> DataCreationPipelineOptions {
+        DataCreationPipelineOptions {
+            route_matching: PipelineOperationType::Enabled(route_matching),
+            route_processor: PipelineOperationType::Enabled(SubOperationType::None),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_groups_public_activities_in_processed_routes() {
+        let mut pipeline = memory_pipeline();
+        store_activity(&pipeline, 1, false).await;
+        store_activity(&pipeline, 2, false).await;
+        store_activity(&pipeline, 3, true).await;
+
+        pipeline
+            .start(&matching_options(SubOperationType::Rewrite))
+            .await;
+
+        let routes = routes(&pipeline).await;
+        assert_eq!(routes.len(), 1);
+        assert_eq!(sorted_activities(&routes[0]), vec![1, 2]);
+        assert!(routes[0].activities.contains(&routes[0].master_activity_id));
+        assert_eq!(routes[0].r#type, "RouteRun");
+        assert!(!routes[0].polyline.is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_add_new_activities_to_existing_routes() {
+        let mut pipeline = memory_pipeline();
+        store_activity(&pipeline, 1, false).await;
+
+        pipeline
+            .start(&matching_options(SubOperationType::Rewrite))
+            .await;
+        let route_id = routes(&pipeline).await[0]._id;
+
+        store_activity(&pipeline, 2, false).await;
+        pipeline
+            .start(&matching_options(SubOperationType::Update))
+            .await;
+
+        let routes = routes(&pipeline).await;
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0]._id, route_id);
+        assert_eq!(sorted_activities(&routes[0]), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn deleted_activities_leave_their_routes() {
+        let mut pipeline = memory_pipeline();
+        store_activity(&pipeline, 1, false).await;
+        store_activity(&pipeline, 2, false).await;
+
+        pipeline
+            .start(&matching_options(SubOperationType::Rewrite))
+            .await;
+        let master_act_id = routes(&pipeline).await[0].master_activity_id;
+        let other_act_id = if master_act_id == 1 { 2 } else { 1 };
+
+        // The route gets a new master and is processed again
+        pipeline.on_delete_activity(master_act_id).await;
+
+        let strava_db = pipeline.dependencies.strava_db();
+        assert!(strava_db.activities.get(master_act_id).await.is_none());
+
+        let routes_left = routes(&pipeline).await;
+        assert_eq!(routes_left.len(), 1);
+        assert_eq!(routes_left[0].activities, vec![other_act_id]);
+        assert_eq!(routes_left[0].master_activity_id, other_act_id);
+        assert_eq!(routes_left[0].r#type, "RouteRun");
+
+        // Routes without activities are deleted
+        pipeline.on_delete_activity(other_act_id).await;
+
+        assert!(routes(&pipeline).await.is_empty());
+    }
+}
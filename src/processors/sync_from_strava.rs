@@ -1,11 +1,22 @@
-use mongodb::bson::doc;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
 
 use crate::{
+    config::Config,
     data_types::{
         common::{DocumentId, Identifiable},
-        strava::{activity::Activity, athlete::AthleteId},
+        strava::{
+            activity::Activity,
+            athlete::AthleteId,
+            failed_download::{DownloadKind, FailedDownload},
+            sync_job::{ActivitySyncEntry, ActivitySyncStatus, SyncJob, SyncJobState},
+        },
     },
     logln, logvbln,
+    sources::{strava::StravaSource, ActivitySource},
+    strava::error::{StravaApiError, StravaResult},
     util::{
         facilities::{Facilities, Required},
         geo::GeoUtils,
@@ -13,9 +24,36 @@ use crate::{
     },
 };
 
+// Fields of an activity which changed after being re-downloaded
+#[derive(Debug, Default)]
+pub struct ActivityChanges {
+    pub r#type: bool,
+    pub description: bool,
+    pub private: bool,
+    pub location: bool,
+}
+
+impl ActivityChanges {
+    pub fn between(old: &Activity, new: &Activity) -> Self {
+        Self {
+            r#type: old.r#type != new.r#type,
+            description: old.description != new.description,
+            private: old.private != new.private,
+            location: old.location_city != new.location_city
+                || old.location_country != new.location_country,
+        }
+    }
+}
+
+// Documents downloaded concurrently are written to the database in batches of this size
+const WRITE_BATCH_SIZE: usize = 25;
+
 pub struct StravaDBSync {
     athlete_id: AthleteId,
     dependencies: Facilities,
+    source: Arc<dyn ActivitySource>,
+    // Requests in flight when downloading details and streams
+    concurrency: usize,
 }
 
 impl StravaDBSync {
@@ -24,13 +62,46 @@ impl StravaDBSync {
     pub fn new(dependencies: Facilities, athlete_id: AthleteId) -> Self {
         dependencies.check(vec![Required::StravaDB, Required::StravaApi]);
 
+        let source = Arc::new(StravaSource::new(dependencies.strava_api()));
+
+        StravaDBSync::with_source(dependencies, athlete_id, source)
+    }
+
+    // Segments are still downloaded from Strava, they need the Strava API as well
+    pub fn with_source(
+        dependencies: Facilities,
+        athlete_id: AthleteId,
+        source: Arc<dyn ActivitySource>,
+    ) -> Self {
+        dependencies.check(vec![Required::StravaDB]);
+
         Self {
             athlete_id,
             dependencies,
+            source,
+            concurrency: Config::global().pipeline.download_concurrency,
         }
     }
 
-    pub async fn process_new_activity(&mut self, act_id: DocumentId) {
+    pub fn source_name(&self) -> &str {
+        self.source.name()
+    }
+
+    // Activity details from the source, tagged with where they came from
+    async fn fetch_activity(&self, act_id: DocumentId) -> StravaResult<serde_json::Value> {
+        let mut activity = self.source.fetch_activity(act_id).await?;
+
+        if let Some(activity) = activity.as_object_mut() {
+            activity.insert(
+                "source".to_string(),
+                serde_json::Value::String(self.source.name().to_string()),
+            );
+        }
+
+        Ok(activity)
+    }
+
+    pub async fn process_new_activity(&mut self, act_id: DocumentId) -> StravaResult<()> {
         if self
             .dependencies
             .strava_db()
@@ -40,94 +111,469 @@ impl StravaDBSync {
         {
             logvbln!("Activity {} already in DB. Skipping download.", act_id);
 
-            return;
+            return Ok(());
         }
 
-        if let Some(mut new_activity) = self.dependencies.strava_api().get_activity(act_id).await {
+        self.fetch_and_store_activity(act_id).await?;
+
+        self.post_process_activity(act_id).await
+    }
+
+    async fn fetch_and_store_activity(&self, act_id: DocumentId) -> StravaResult<()> {
+        let mut new_activity = match self.fetch_activity(act_id).await {
+            Ok(new_activity) => new_activity,
+            Err(err) => {
+                self.record_failed_download(act_id, DownloadKind::Activity, &err)
+                    .await;
+                return Err(err);
+            }
+        };
+
+        self.dependencies
+            .strava_db()
+            .activities
+            .store(act_id, &mut new_activity)
+            .await;
+
+        Ok(())
+    }
+
+    // Re-downloads an activity which is already stored and reports what changed
+    pub async fn refresh_activity(&mut self, act_id: DocumentId) -> StravaResult<ActivityChanges> {
+        let Some(old_activity) = self.dependencies.strava_db().activities.get(act_id).await else {
+            // Never downloaded, treat as a new one
+            self.process_new_activity(act_id).await?;
+            return Ok(Default::default());
+        };
+
+        let mut updated_activity = self.fetch_activity(act_id).await?;
+
+        // Replaces the whole document so the post processed fields have to be computed again
+        self.dependencies
+            .strava_db()
+            .activities
+            .store(act_id, &mut updated_activity)
+            .await;
+
+        self.post_process_activity(act_id).await?;
+
+        let new_activity = self
+            .dependencies
+            .strava_db()
+            .activities
+            .get(act_id)
+            .await
+            .unwrap();
+
+        let changes = ActivityChanges::between(&old_activity, &new_activity);
+
+        if changes.r#type {
+            // Telemetry type is used to group activities when matching routes
             self.dependencies
                 .strava_db()
-                .activities
-                .store(act_id, &mut new_activity)
+                .telemetries
+                .set_type(act_id, &new_activity.r#type)
                 .await;
+        }
 
-            let mut db_activity = self
-                .dependencies
-                .strava_db()
-                .activities
-                .get(act_id)
-                .await
-                .unwrap();
+        logvbln!("Activity {} changes {:?}", act_id, changes);
 
-            // Download telemetry streams
-            self.download_telemetry(db_activity.as_i64()).await;
+        Ok(changes)
+    }
 
-            // Remap indexes of segments from the whole telemetry to the polyline's telemetry
-            // special procedure for re-writing activities
-            self.run_segment_poly_indexer(&mut db_activity).await;
+    // Steps run after the activity details are stored, can be re-run for activities missing telemetry
+    async fn post_process_activity(&mut self, act_id: DocumentId) -> StravaResult<()> {
+        let mut db_activity = self
+            .dependencies
+            .strava_db()
+            .activities
+            .get(act_id)
+            .await
+            .unwrap();
 
-            // Add location city and country from first segment effort
-            self.run_location_fixer_activities(&mut db_activity).await;
+        // Download telemetry streams
+        self.download_telemetry(db_activity.as_i64()).await?;
 
-            // Fix string dates to DateTime
-            self.run_date_fixer_activities(&mut db_activity).await;
-        }
+        // Remap indexes of segments from the whole telemetry to the polyline's telemetry
+        // special procedure for re-writing activities
+        self.run_segment_poly_indexer(&mut db_activity).await;
+
+        // Add location city and country from first segment effort
+        self.run_location_fixer_activities(&mut db_activity).await;
+
+        // Fix string dates to DateTime
+        self.run_date_fixer_activities(&mut db_activity).await;
+
+        // Catalog the segments the activity went through
+        self.download_segments(&db_activity).await;
+
+        Ok(())
     }
 
-    pub async fn download_activities_in_range(
-        &mut self,
-        after_ts: i64,
-        before_ts: i64,
-    ) -> (i64, bool) {
+    // Runs the job until Strava has no more activities in its range, progress is saved after every step
+    // so a job stopped by a crash or the rate limit resumes from the activity it was working on
+    pub async fn run_sync_job(&mut self, job: &mut SyncJob) -> StravaResult<()> {
         logln!(
-            "download from {} to {}",
-            DateTimeUtils::timestamp_to_str(after_ts),
-            DateTimeUtils::timestamp_to_str(before_ts)
+            "{:?} sync from {} to {}, page {}",
+            job.kind,
+            DateTimeUtils::timestamp_to_str(job.after_ts),
+            DateTimeUtils::timestamp_to_str(job.before_ts),
+            job.page
         );
 
-        const ACTIVITIES_PER_PAGE: usize = 2;
-
-        let mut last_activity_ts = before_ts;
-        let mut page = 1;
-        let mut has_more_items = false;
+        job.state = SyncJobState::Running;
+        self.save_sync_job(job).await;
 
         loop {
-            if let Some(activities_list) = self
-                .dependencies
-                .strava_api()
-                .list_athlete_activities(after_ts, before_ts, ACTIVITIES_PER_PAGE, page)
+            // Activities listed before the job stopped go first
+            self.sync_job_page(job).await?;
+
+            if !job.has_more {
+                job.state = SyncJobState::Completed;
+                self.save_sync_job(job).await;
+
+                logln!(
+                    "{:?} sync done, {} activities",
+                    job.kind,
+                    job.processed_count
+                );
+
+                return Ok(());
+            }
+
+            // Listing + detail and telemetry for each activity on the page
+            if let Err(err) = self.ensure_daily_quota(1 + 2 * job.page_size as u32) {
+                return self.pause_sync_job(job, None, err).await;
+            }
+
+            if let Err(err) = self.list_sync_job_page(job).await {
+                return self.pause_sync_job(job, None, err).await;
+            }
+        }
+    }
+
+    // Pages are requested after the cursor rather than by number, activities deleted in the meantime
+    // would otherwise shift the pages and some activities would never be listed
+    async fn list_sync_job_page(&self, job: &mut SyncJob) -> StravaResult<()> {
+        // Sources list in ascending start date when 'after' is given
+        let activities_list = self
+            .source
+            .list_activities(
+                job.cursor_ts,
+                job.cursor_id,
+                job.before_ts,
+                job.page_size,
+                1,
+            )
+            .await?;
+
+        if activities_list.is_empty() && job.page == 0 {
+            logln!("No activities in range.")
+        }
+
+        job.page += 1;
+        let full_page = activities_list.len() == job.page_size;
+        // Only the activities at the cursor are kept, to recognize them if they are listed again
+        let cursor_ts = job.cursor_ts;
+        job.activities.retain(|entry| entry.start_ts >= cursor_ts);
+
+        let mut listed_new = false;
+
+        for activity in activities_list {
+            let Some(act_id) = activity["id"].as_i64() else {
+                continue;
+            };
+
+            // 'after' might include the activity the cursor points to
+            if job.activities.iter().any(|entry| entry.act_id == act_id) {
+                continue;
+            }
+
+            let start_ts = activity["start_date"]
+                .as_str()
+                .map_or(job.cursor_ts, DateTimeUtils::zulu2ts);
+
+            (job.cursor_ts, job.cursor_id) = (job.cursor_ts, job.cursor_id).max((start_ts, act_id));
+            listed_new = true;
+            job.activities.push(ActivitySyncEntry {
+                act_id,
+                start_ts,
+                revision: activity["revision"].as_i64(),
+                status: ActivitySyncStatus::Listed,
+                error: None,
+            });
+        }
+
+        // A full page of activities listed before means the source can't get past the cursor
+        job.has_more = full_page && listed_new;
+
+        self.save_sync_job(job).await;
+
+        Ok(())
+    }
+
+    // Activities of another revision are synced again
+    async fn is_stored(&self, act_id: DocumentId, revision: Option<i64>) -> bool {
+        let activities = &self.dependencies.strava_db().activities;
+
+        match revision {
+            None => activities.exists(act_id).await,
+            Some(revision) => activities
+                .get(act_id)
                 .await
+                .is_some_and(|activity| activity.revision == Some(revision)),
+        }
+    }
+
+    // Details and streams of the page are fetched concurrently, post processing then runs one
+    // activity at a time in start date order, the order routes are matched in
+    async fn sync_job_page(&mut self, job: &mut SyncJob) -> StravaResult<()> {
+        for index in job.pending_activities() {
+            let act_id = job.activities[index].act_id;
+
+            // Stored by the webhook or by an earlier job
+            if job.activities[index].status == ActivitySyncStatus::Listed
+                && self.is_stored(act_id, job.activities[index].revision).await
             {
-                has_more_items = activities_list.len() == ACTIVITIES_PER_PAGE;
+                logvbln!("Activity {} already in DB. Skipping download.", act_id);
 
-                for activity in activities_list {
-                    let act_id = activity["id"].as_i64().unwrap();
+                job.activities[index].status = ActivitySyncStatus::PostProcessed;
+                job.processed_count += 1;
+            }
+        }
 
-                    last_activity_ts =
-                        DateTimeUtils::zulu2ts(&activity["start_date"].as_str().unwrap());
+        self.save_sync_job(job).await;
 
-                    self.dependencies
-                        .strava_db()
-                        .athletes
-                        .save_after_before_timestamps(self.athlete_id, after_ts, last_activity_ts)
-                        .await;
+        self.sync_job_downloads(job, DownloadKind::Activity).await?;
+        self.sync_job_downloads(job, DownloadKind::Telemetry)
+            .await?;
 
-                    self.process_new_activity(act_id).await;
+        for index in job.pending_activities() {
+            let act_id = job.activities[index].act_id;
+
+            if job.activities[index].status != ActivitySyncStatus::TelemetryFetched {
+                continue;
+            }
+
+            match self.post_process_activity(act_id).await {
+                Ok(()) => {
+                    job.activities[index].status = ActivitySyncStatus::PostProcessed;
+                    job.processed_count += 1;
                 }
-            } else {
-                logln!("No activities in range.")
+                Err(err) if StravaDBSync::stops_sync(&err) => {
+                    return self.pause_sync_job(job, Some(act_id), err).await;
+                }
+                Err(err) => StravaDBSync::skip_sync_job_activity(job, index, err),
             }
 
-            if !has_more_items {
-                break;
+            self.save_sync_job(job).await;
+        }
+
+        Ok(())
+    }
+
+    // Downloads the step following the activities' current status, the job is saved after every batch
+    async fn sync_job_downloads(&self, job: &mut SyncJob, kind: DownloadKind) -> StravaResult<()> {
+        let (status, next_status) = match kind {
+            DownloadKind::Activity => (
+                ActivitySyncStatus::Listed,
+                ActivitySyncStatus::DetailFetched,
+            ),
+            DownloadKind::Telemetry => (
+                ActivitySyncStatus::DetailFetched,
+                ActivitySyncStatus::TelemetryFetched,
+            ),
+        };
+
+        let mut indexes: Vec<usize> = Vec::new();
+
+        for index in job.pending_activities() {
+            if job.activities[index].status != status {
+                continue;
+            }
+
+            // Streams stored before the job stopped, those of a new revision replace the stored ones
+            if kind == DownloadKind::Telemetry
+                && job.activities[index].revision.is_none()
+                && self
+                    .dependencies
+                    .strava_db()
+                    .telemetries
+                    .exists(job.activities[index].act_id)
+                    .await
+            {
+                job.activities[index].status = next_status;
+                continue;
             }
 
-            page += 1;
+            indexes.push(index);
         }
 
-        (last_activity_ts, has_more_items)
+        for batch in indexes.chunks(WRITE_BATCH_SIZE) {
+            let act_ids: Vec<DocumentId> = batch
+                .iter()
+                .map(|index| job.activities[*index].act_id)
+                .collect();
+
+            let mut stop_error: Option<(DocumentId, StravaApiError)> = None;
+
+            for (index, result) in batch.iter().zip(self.download_batch(&act_ids, kind).await) {
+                match result {
+                    Ok(()) => job.activities[*index].status = next_status,
+                    Err(err) if StravaDBSync::stops_sync(&err) => {
+                        stop_error.get_or_insert((job.activities[*index].act_id, err));
+                    }
+                    Err(err) => StravaDBSync::skip_sync_job_activity(job, *index, err),
+                }
+            }
+
+            if let Some((act_id, err)) = stop_error {
+                return self.pause_sync_job(job, Some(act_id), err).await;
+            }
+
+            self.save_sync_job(job).await;
+        }
+
+        self.save_sync_job(job).await;
+
+        Ok(())
+    }
+
+    fn skip_sync_job_activity(job: &mut SyncJob, index: usize, err: StravaApiError) {
+        let act_id = job.activities[index].act_id;
+
+        logln!("Skipping activity {}: {}", act_id, err);
+
+        job.activities[index].error = Some(err.to_string());
+        job.add_error(Some(act_id), err.to_string(), Utc::now().timestamp());
+    }
+
+    // No point in continuing, next requests will fail the same way
+    fn stops_sync(err: &StravaApiError) -> bool {
+        matches!(
+            err,
+            StravaApiError::Unauthorized | StravaApiError::RateLimited { .. }
+        )
     }
 
-    pub async fn download_telemetry(&mut self, act_id: DocumentId) {        
+    // At most 'download_concurrency' requests are in flight, the rate limiter spaces them within the budget
+    // results are in the order of act_ids and the downloaded documents are written in one batch
+    async fn download_batch(
+        &self,
+        act_ids: &[DocumentId],
+        kind: DownloadKind,
+    ) -> Vec<StravaResult<()>> {
+        if let Err(err) = self.ensure_daily_quota(act_ids.len() as u32) {
+            return act_ids.iter().map(|_| Err(err.clone())).collect();
+        }
+
+        // Collected first, a stream over the iterator would not be Send
+        let requests: Vec<_> = act_ids
+            .iter()
+            .map(|act_id| self.fetch_document(*act_id, kind))
+            .collect();
+
+        let fetched: Vec<StravaResult<serde_json::Value>> = stream::iter(requests)
+            .buffered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut documents: Vec<(DocumentId, serde_json::Value)> = Vec::new();
+        let mut results: Vec<StravaResult<()>> = Vec::new();
+
+        for (act_id, result) in act_ids.iter().zip(fetched) {
+            match result {
+                Ok(document) => {
+                    documents.push((*act_id, document));
+                    results.push(Ok(()));
+                }
+                Err(err) => {
+                    self.record_failed_download(*act_id, kind, &err).await;
+                    results.push(Err(err));
+                }
+            }
+        }
+
+        match kind {
+            DownloadKind::Activity => {
+                self.dependencies
+                    .strava_db()
+                    .activities
+                    .store_many(documents)
+                    .await
+            }
+            DownloadKind::Telemetry => {
+                self.dependencies
+                    .strava_db()
+                    .telemetries
+                    .store_many(documents)
+                    .await
+            }
+        }
+
+        results
+    }
+
+    async fn fetch_document(
+        &self,
+        act_id: DocumentId,
+        kind: DownloadKind,
+    ) -> StravaResult<serde_json::Value> {
+        match kind {
+            DownloadKind::Activity => self.fetch_activity(act_id).await,
+            DownloadKind::Telemetry => self.fetch_telemetry(act_id).await,
+        }
+    }
+
+    async fn pause_sync_job(
+        &self,
+        job: &mut SyncJob,
+        act_id: Option<DocumentId>,
+        err: StravaApiError,
+    ) -> StravaResult<()> {
+        job.state = SyncJobState::Paused;
+        job.add_error(act_id, err.to_string(), Utc::now().timestamp());
+        self.save_sync_job(job).await;
+
+        Err(err)
+    }
+
+    // Jobs of sources which are not resumable are listed whole again on the next run
+    async fn save_sync_job(&self, job: &mut SyncJob) {
+        job.updated_ts = Utc::now().timestamp();
+
+        if !self.source.resumable() {
+            return;
+        }
+
+        self.dependencies.strava_db().sync_jobs.store(job).await;
+    }
+
+    // Stops before starting work which can't be finished with today's remaining quota
+    // progress is saved per activity so the next run resumes from the same point
+    fn ensure_daily_quota(&self, requests_needed: u32) -> StravaResult<()> {
+        let Some(quota) = self.source.remaining_quota() else {
+            return Ok(());
+        };
+
+        if let Some(daily_remaining) = quota.daily_remaining() {
+            if daily_remaining < requests_needed {
+                logln!(
+                    "Daily quota left {}, pausing sync for {}s",
+                    daily_remaining,
+                    quota.daily_reset_in
+                );
+
+                return Err(StravaApiError::RateLimited {
+                    retry_after: Some(quota.daily_reset_in),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn download_telemetry(&mut self, act_id: DocumentId) -> StravaResult<()> {
         // Check telemetry for activity
         if !self
             .dependencies
@@ -136,34 +582,278 @@ impl StravaDBSync {
             .exists(act_id)
             .await
         {
-            let act = self
-                .dependencies
-                .strava_db()
-                .activities
-                .get(act_id)
-                .await
-                .unwrap();
-
             logln!("Downloading activity telemetry...{}", act_id);
-            
-            if let Some(mut telemetry_json) = self
-                .dependencies
-                .strava_api()
-                .get_activity_telemetry(act_id)
-                .await
-            {
-                let mut m = telemetry_json.as_object().unwrap().clone();
-                m.insert(
-                    "athlete".to_string(),
-                    serde_json::json!({ "id": self.athlete_id }),
-                );
-                m.insert("type".to_string(), serde_json::Value::String(act.r#type));
 
-                telemetry_json = serde_json::Value::Object(m);
-                self.dependencies
+            if let Err(err) = self.fetch_and_store_telemetry(act_id).await {
+                self.record_failed_download(act_id, DownloadKind::Telemetry, &err)
+                    .await;
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Re-downloads the streams of activities stored before the extended streams were requested
+    // progress is kept in the telemetry documents so an interrupted backfill continues where it stopped
+    pub async fn backfill_telemetry(&mut self) -> StravaResult<()> {
+        let act_ids = self
+            .own_activity_ids(
+                &self
+                    .dependencies
                     .strava_db()
                     .telemetries
-                    .store(act_id, &mut telemetry_json)
+                    .get_athlete_ids_without_extended_streams(self.athlete_id)
+                    .await,
+            )
+            .await;
+
+        logln!(
+            "Backfilling telemetry of {} activities from {}",
+            act_ids.len(),
+            self.source_name()
+        );
+
+        self.download_telemetries(&act_ids).await
+    }
+
+    // Of the given activities the ones synced from this syncer's source, the others are downloaded from theirs
+    pub async fn own_activity_ids(&self, act_ids: &[DocumentId]) -> Vec<DocumentId> {
+        self.dependencies
+            .strava_db()
+            .activities
+            .get_athlete_activities_with_ids(self.athlete_id, act_ids)
+            .await
+            .iter()
+            .filter(|activity| activity.source_name() == self.source_name())
+            .map(|activity| activity.as_i64())
+            .collect()
+    }
+
+    // Streams of stored activities, downloaded concurrently, skipped activities are only logged
+    pub async fn download_telemetries(&self, act_ids: &[DocumentId]) -> StravaResult<()> {
+        for batch in act_ids.chunks(WRITE_BATCH_SIZE) {
+            let mut stop_error: Option<StravaApiError> = None;
+
+            for (act_id, result) in batch
+                .iter()
+                .zip(self.download_batch(batch, DownloadKind::Telemetry).await)
+            {
+                match result {
+                    Ok(()) => {}
+                    Err(err) if StravaDBSync::stops_sync(&err) => {
+                        stop_error.get_or_insert(err);
+                    }
+                    Err(err) => logln!("Skipping telemetry of {}: {}", act_id, err),
+                }
+            }
+
+            if let Some(err) = stop_error {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_and_store_telemetry(&self, act_id: DocumentId) -> StravaResult<()> {
+        let mut telemetry_json = self.fetch_telemetry(act_id).await?;
+
+        self.dependencies
+            .strava_db()
+            .telemetries
+            .store(act_id, &mut telemetry_json)
+            .await;
+
+        Ok(())
+    }
+
+    async fn fetch_telemetry(&self, act_id: DocumentId) -> StravaResult<serde_json::Value> {
+        let act = self
+            .dependencies
+            .strava_db()
+            .activities
+            .get(act_id)
+            .await
+            .ok_or(StravaApiError::NotFound)?;
+
+        let telemetry_json = self.source.fetch_streams(act_id).await?;
+
+        let mut m = telemetry_json.as_object().cloned().ok_or_else(|| {
+            StravaApiError::MalformedJson("expected telemetry streams object".to_string())
+        })?;
+        m.insert(
+            "athlete".to_string(),
+            serde_json::json!({ "id": self.athlete_id }),
+        );
+        m.insert("type".to_string(), serde_json::Value::String(act.r#type));
+        m.insert(
+            "extended_streams".to_string(),
+            serde_json::Value::Bool(true),
+        );
+
+        Ok(serde_json::Value::Object(m))
+    }
+
+    // Segments are shared between athletes so each one is only downloaded once,
+    // a failed one is picked up again by the next activity going through it
+    async fn download_segments(&self, activity: &Activity) {
+        let mut seg_ids: Vec<DocumentId> = activity
+            .segment_efforts
+            .iter()
+            .map(|effort| effort.segment.id)
+            .collect();
+        seg_ids.sort();
+        seg_ids.dedup();
+
+        for seg_id in seg_ids {
+            if self.dependencies.strava_db().segments.exists(seg_id).await {
+                continue;
+            }
+
+            if let Err(err) = self.download_segment(seg_id).await {
+                logln!("Skipping segment {}: {}", seg_id, err);
+            }
+        }
+    }
+
+    async fn download_segment(&self, seg_id: DocumentId) -> StravaResult<()> {
+        logln!("Downloading segment...{}", seg_id);
+
+        let mut segment_json = self.dependencies.strava_api().get_segment(seg_id).await?;
+        let streams_json = self
+            .dependencies
+            .strava_api()
+            .get_segment_telemetry(seg_id)
+            .await?;
+
+        segment_json["streams"] = streams_json;
+
+        self.dependencies
+            .strava_db()
+            .segments
+            .store(seg_id, &mut segment_json)
+            .await;
+
+        Ok(())
+    }
+
+    // Retries the downloads which failed in previous runs
+    pub async fn retry_failed_downloads(&mut self) -> StravaResult<()> {
+        let failed_downloads = self
+            .dependencies
+            .strava_db()
+            .failed_downloads
+            .get_athlete_failed_downloads(self.athlete_id)
+            .await
+            .into_iter()
+            .filter(|failed_download| failed_download.source_name() == self.source.name())
+            .collect::<Vec<_>>();
+
+        if failed_downloads.is_empty() {
+            return Ok(());
+        }
+
+        logln!("Retrying {} failed downloads", failed_downloads.len());
+
+        for failed_download in failed_downloads {
+            let act_id = failed_download.as_i64();
+
+            let result = match failed_download.kind {
+                DownloadKind::Activity => self.process_new_activity(act_id).await,
+                DownloadKind::Telemetry => {
+                    // Activity might have been deleted in the meantime
+                    if !self
+                        .dependencies
+                        .strava_db()
+                        .activities
+                        .exists(act_id)
+                        .await
+                    {
+                        Ok(())
+                    } else {
+                        self.post_process_activity(act_id).await
+                    }
+                }
+            };
+
+            match result {
+                Ok(_) => {
+                    self.dependencies
+                        .strava_db()
+                        .failed_downloads
+                        .delete(act_id)
+                        .await;
+                }
+                Err(StravaApiError::Unauthorized) | Err(StravaApiError::RateLimited { .. }) => {
+                    return result;
+                }
+                Err(err) => logln!("Activity {} still failing: {}", act_id, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn record_failed_download(
+        &self,
+        act_id: DocumentId,
+        kind: DownloadKind,
+        err: &StravaApiError,
+    ) {
+        let failed_downloads = &self.dependencies.strava_db().failed_downloads;
+
+        // Deleted or private activities will never be downloadable
+        if let StravaApiError::NotFound = err {
+            failed_downloads.delete(act_id).await;
+            return;
+        }
+
+        let attempts = failed_downloads
+            .get(act_id)
+            .await
+            .map_or(0, |failed_download| failed_download.attempts);
+
+        failed_downloads
+            .store(&FailedDownload {
+                _id: act_id,
+                athlete_id: self.athlete_id,
+                kind,
+                error: err.to_string(),
+                attempts: attempts + 1,
+                source: Some(self.source.name().to_string()),
+                last_attempt_ts: Utc::now().timestamp(),
+            })
+            .await;
+    }
+
+    // Lap indexes point into the whole telemetry, exports and splits need them in the polyline
+    // also used for imported activities
+    pub async fn remap_laps(
+        dependencies: &Facilities,
+        activity: &mut Activity,
+        indexes_in_polyline: &[usize],
+    ) {
+        // Laps of activities without GPS (treadmill, trainer) can't be placed on the polyline
+        if indexes_in_polyline.is_empty() {
+            return;
+        }
+
+        for lap in activity.laps.iter_mut() {
+            let start_index = lap.start_index.max(0) as usize;
+            let end_index = lap.end_index.max(0) as usize;
+
+            if let (Some(start_index_poly), Some(end_index_poly)) = (
+                indexes_in_polyline.get(start_index),
+                indexes_in_polyline.get(end_index.min(indexes_in_polyline.len() - 1)),
+            ) {
+                lap.start_index_poly = Some(*start_index_poly as i32);
+                lap.end_index_poly = Some(*end_index_poly as i32);
+
+                dependencies
+                    .strava_db()
+                    .activities
+                    .set_lap_indexes_poly(lap.id, *start_index_poly as i32, *end_index_poly as i32)
                     .await;
             }
         }
@@ -186,19 +876,31 @@ impl StravaDBSync {
         let mut needs_poly_index_update = false;
 
         // If field already exists then skip (for new activities does not apply just in case code is run on existing ones)
-        for effort in &activity.segment_efforts {
-            if let None = effort.start_index_poly {
-                indexes_in_polyline = GeoUtils::create_polyline_mapping_table(
-                    &activity.map.polyline,
-                    &telemetry.latlng.data,
-                );
+        let needs_lap_poly_index_update = activity
+            .laps
+            .iter()
+            .any(|lap| lap.start_index_poly.is_none());
 
+        // Efforts indexed before end_index_poly was stored only have the start index
+        for effort in &activity.segment_efforts {
+            if effort.start_index_poly.is_none() || effort.end_index_poly.is_none() {
                 needs_poly_index_update = true;
                 break;
             }
         }
 
-        for mut segment_effort in activity.segment_efforts.iter_mut() {
+        if needs_poly_index_update || needs_lap_poly_index_update {
+            indexes_in_polyline = GeoUtils::create_polyline_mapping_table(
+                &activity.map.polyline,
+                &telemetry.latlng.data,
+            );
+        }
+
+        if needs_lap_poly_index_update {
+            StravaDBSync::remap_laps(&self.dependencies, activity, &indexes_in_polyline).await;
+        }
+
+        for segment_effort in activity.segment_efforts.iter_mut() {
             if needs_poly_index_update {
                 segment_effort.start_index_poly =
                     Some(indexes_in_polyline[segment_effort.start_index as usize] as i32);
@@ -233,33 +935,18 @@ impl StravaDBSync {
     }
 
     async fn run_date_fixer_activities(&self, activity: &mut Activity) {
-        // Query for adding a date field from String field
-        let query = doc! {
-          "$addFields":
-          {
-            "start_date_local_date": {
-              "$dateFromString": {
-                "dateString": "$start_date_local",
-                "onError": "null"
-              }
-            }
-          }
-        };
+        // Date field from the String field so activities can be filtered by date,
+        // left unset when the string is not a date like $dateFromString with onError null
+        let start_date_local_date =
+            chrono::DateTime::parse_from_rfc3339(&activity.start_date_local)
+                .ok()
+                .map(|date| mongodb::bson::DateTime::from_millis(date.timestamp_millis()));
 
-        let fixed_activities = self
-            .dependencies
+        self.dependencies
             .strava_db()
             .activities
-            .query_activities(vec![doc! {"$match": {"_id":activity._id}}, query])
+            .set_start_date_local_date(activity.as_i64(), &start_date_local_date)
             .await;
-
-        for activity in fixed_activities {
-            self.dependencies
-                .strava_db()
-                .activities
-                .set_start_date_local_date(activity.as_i64(), &activity.start_date_local_date)
-                .await;
-        }
     }
 
     async fn run_location_fixer_activities(&self, activity: &mut Activity) {
@@ -270,14 +957,12 @@ impl StravaDBSync {
         activity.segment_efforts.iter().for_each(|effort| {
             if let Some(effort_city) = &effort.segment.city {
                 activity.location_city = Some(effort_city.to_string());
-                return;
             }
         });
 
         activity.segment_efforts.iter().for_each(|effort| {
             if let Some(effort_country) = &effort.segment.country {
                 activity.location_country = effort_country.to_string();
-                return;
             }
         });
 
@@ -294,3 +979,140 @@ impl StravaDBSync {
             .await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        data_types::strava::{athlete::AthleteTokens, sync_job::SyncJobKind},
+        database::strava_db::StravaDB,
+        strava::{
+            api::StravaApi,
+            cassette::CassetteClient,
+            http::TransportConfig,
+            retry::{RetryPolicies, RetryPolicy},
+        },
+        util::facilities::DependenciesBuilder,
+        TokenExchange,
+    };
+
+    const ATHLETE_ID: AthleteId = 5;
+    const SYNC_JOB_CASSETTE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/fixtures/cassettes/sync_job.json"
+    );
+
+    // Strava answered from the cassette, tokens valid long enough to never be refreshed
+    async fn replay_dependencies(cassette: &Arc<CassetteClient>) -> Facilities {
+        Config::init_test_global();
+
+        let strava_db = Arc::new(StravaDB::in_memory());
+        let token_exchange = TokenExchange::new(
+            strava_db.get_athletes_collection(),
+            ATHLETE_ID,
+            AthleteTokens {
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at: i64::MAX / 2,
+            },
+        )
+        .await;
+        let strava_api = StravaApi::with_transport(
+            token_exchange,
+            ATHLETE_ID,
+            TransportConfig::default(),
+            cassette.clone(),
+        )
+        .with_retry_policies(RetryPolicies::new(RetryPolicy::none()));
+
+        DependenciesBuilder::new()
+            .with_strava_db(&strava_db)
+            .with_strava_api(&Arc::new(strava_api))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn sync_job_replays_as_recorded() {
+        let cassette = Arc::new(CassetteClient::replay(Path::new(SYNC_JOB_CASSETTE)).unwrap());
+        let dependencies = replay_dependencies(&cassette).await;
+        let strava_db = dependencies.strava_db();
+
+        let mut job = SyncJob::new(
+            ATHLETE_ID,
+            SyncJobKind::Backfill,
+            1682899200,
+            1685577600,
+            30,
+            Utc::now().timestamp(),
+        );
+
+        StravaDBSync::new(dependencies, ATHLETE_ID)
+            .run_sync_job(&mut job)
+            .await
+            .unwrap();
+
+        cassette.verify().unwrap();
+
+        assert_eq!(job.state, SyncJobState::Completed);
+        assert_eq!(job.processed_count, 1);
+        assert!(job.errors.is_empty());
+
+        let activity = strava_db.activities.get(9001).await.unwrap();
+        assert_eq!(activity.source.as_deref(), Some("strava"));
+        assert_eq!(activity.location_city.as_deref(), Some("Lausanne"));
+
+        let effort = &activity.segment_efforts[0];
+        assert_eq!(
+            (effort.start_index_poly, effort.end_index_poly),
+            (Some(1), Some(3))
+        );
+        assert_eq!(effort.distance_from_start, Some(135.));
+
+        assert!(strava_db.telemetries.exists(9001).await);
+        assert!(strava_db.segments.exists(77).await);
+        assert_eq!(
+            strava_db.sync_jobs.get(ATHLETE_ID).await.unwrap().state,
+            SyncJobState::Completed
+        );
+    }
+
+    #[tokio::test]
+    async fn efforts_missing_the_end_index_are_indexed_again() {
+        let cassette = Arc::new(CassetteClient::replay(Path::new(SYNC_JOB_CASSETTE)).unwrap());
+        let dependencies = replay_dependencies(&cassette).await;
+        let strava_db = dependencies.strava_db();
+
+        let mut job = SyncJob::new(
+            ATHLETE_ID,
+            SyncJobKind::Backfill,
+            1682899200,
+            1685577600,
+            30,
+            Utc::now().timestamp(),
+        );
+        let mut sync = StravaDBSync::new(dependencies, ATHLETE_ID);
+        sync.run_sync_job(&mut job).await.unwrap();
+
+        // Document as stored before end_index_poly existed
+        let mut json = serde_json::to_value(strava_db.activities.get(9001).await.unwrap()).unwrap();
+        json["segment_efforts"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("end_index_poly");
+        strava_db.activities.store(9001, &mut json).await;
+
+        let mut activity = strava_db.activities.get(9001).await.unwrap();
+        assert_eq!(activity.segment_efforts[0].end_index_poly, None);
+
+        sync.run_segment_poly_indexer(&mut activity).await;
+
+        let activity = strava_db.activities.get(9001).await.unwrap();
+        let effort = &activity.segment_efforts[0];
+        assert_eq!(
+            (effort.start_index_poly, effort.end_index_poly),
+            (Some(1), Some(3))
+        );
+    }
+}
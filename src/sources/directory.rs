@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{
+    data_types::{common::DocumentId, strava::athlete::AthleteId},
+    import::{
+        activity::ActivityDetails, fit::FitParser, gpx::GpxParser, tcx::TcxParser, ImportError,
+        ImportResult,
+    },
+    logln,
+    strava::error::{StravaApiError, StravaResult},
+};
+
+use super::ActivitySource;
+
+const SUPPORTED_EXTENSIONS: &[&str] = &["gpx", "fit", "tcx"];
+
+struct DirectoryActivity {
+    act_id: DocumentId,
+    // Id of the file's content, copies of a file are the same activity and an edited file a new revision of it
+    content_id: DocumentId,
+    start_ts: i64,
+    activity: Value,
+    telemetry: Value,
+}
+
+struct DirectoryFile {
+    modified: SystemTime,
+    // None when the file could not be parsed, it is tried again once it changes
+    activity: Option<DirectoryActivity>,
+}
+
+// Folder of activity files, parsed files are kept until they change on disk
+pub struct DirectorySource {
+    path: PathBuf,
+    athlete_id: AthleteId,
+    name: String,
+    files: Mutex<HashMap<PathBuf, DirectoryFile>>,
+}
+
+impl DirectorySource {
+    const CC: &str = "DirectorySource";
+
+    pub fn new(path: &Path, athlete_id: AthleteId) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            athlete_id,
+            name: format!("directory:{}", path.display()),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Each athlete has a sub folder named after their id
+    pub fn for_athlete(directory: &Path, athlete_id: AthleteId) -> Self {
+        DirectorySource::new(&directory.join(athlete_id.to_string()), athlete_id)
+    }
+
+    // Reads the files added or changed since the last scan and returns how many there were,
+    // a missing folder is the same as an empty one
+    pub fn scan(&self) -> usize {
+        let Ok(entries) = std::fs::read_dir(&self.path) else {
+            return 0;
+        };
+
+        let mut files = self.files.lock().unwrap();
+        let mut found: Vec<PathBuf> = Vec::new();
+        let mut changed = 0;
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let supported = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    SUPPORTED_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+                });
+
+            let Some(modified) = entry
+                .metadata()
+                .ok()
+                .filter(|metadata| supported && metadata.is_file())
+                .and_then(|metadata| metadata.modified().ok())
+            else {
+                continue;
+            };
+
+            found.push(path.clone());
+
+            if files
+                .get(&path)
+                .is_some_and(|file| file.modified == modified)
+            {
+                continue;
+            }
+
+            let activity = match self.parse(&path) {
+                Ok(activity) => Some(activity),
+                Err(err) => {
+                    logln!("Skipping {}: {}", path.display(), err);
+                    None
+                }
+            };
+
+            files.insert(path, DirectoryFile { modified, activity });
+            changed += 1;
+        }
+
+        files.retain(|path, _| found.contains(path));
+
+        changed
+    }
+
+    fn parse(&self, path: &Path) -> ImportResult<DirectoryActivity> {
+        let content = std::fs::read(path)?;
+        let filename = path.file_name().and_then(|filename| filename.to_str());
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let text = || -> ImportResult<&str> {
+            std::str::from_utf8(&content)
+                .map_err(|err| ImportError::Parse(format!("not UTF-8: {}", err)))
+        };
+
+        let (mut details, summary, track) = match extension.as_str() {
+            "gpx" => {
+                let track = GpxParser::parse(text()?)?;
+                let details =
+                    GpxParser::activity_details(self.athlete_id, text()?, &track, None, filename);
+
+                (details, track.summary(), track)
+            }
+            "fit" | "tcx" => {
+                let recorded = if extension == "fit" {
+                    FitParser::parse(&content)?
+                } else {
+                    TcxParser::parse(text()?.trim())?
+                };
+                let details = recorded.activity_details(
+                    self.athlete_id,
+                    &content,
+                    &extension.to_uppercase(),
+                    None,
+                    filename,
+                );
+
+                (details, recorded.summary(), recorded.track)
+            }
+            _ => {
+                return Err(ImportError::Parse(format!(
+                    "unsupported file type '{}'",
+                    extension
+                )))
+            }
+        };
+
+        let content_id = details.id;
+        let start_ts = details
+            .start_date
+            .map_or(0, |start_date| start_date.timestamp());
+
+        // Editing the file keeps the activity, the content alone would make it a new one
+        let relative_path = path.strip_prefix(&self.path).unwrap_or(path);
+        details.set_id(ActivityDetails::synthetic_id(
+            self.athlete_id,
+            format!("{}@{}", relative_path.display(), start_ts).as_bytes(),
+        ));
+
+        let mut activity = details.to_activity_json(&summary, &track.polyline(), self.athlete_id);
+        activity["revision"] = content_id.into();
+
+        Ok(DirectoryActivity {
+            act_id: details.id,
+            content_id,
+            start_ts,
+            telemetry: track.to_telemetry_json(self.athlete_id, &details.r#type),
+            activity,
+        })
+    }
+
+    fn find<T>(
+        &self,
+        act_id: DocumentId,
+        value: impl Fn(&DirectoryActivity) -> T,
+    ) -> StravaResult<T> {
+        let files = self.files.lock().unwrap();
+
+        files
+            .values()
+            .filter_map(|file| file.activity.as_ref())
+            .find(|activity| activity.act_id == act_id)
+            .map(value)
+            .ok_or(StravaApiError::NotFound)
+    }
+}
+
+#[async_trait]
+impl ActivitySource for DirectorySource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn list_activities(
+        &self,
+        after_ts: i64,
+        after_id: DocumentId,
+        before_ts: i64,
+        per_page: usize,
+        page: usize,
+    ) -> StravaResult<Vec<Value>> {
+        self.scan();
+
+        let files = self.files.lock().unwrap();
+        let mut activities: Vec<&DirectoryActivity> = files
+            .values()
+            .filter_map(|file| file.activity.as_ref())
+            .filter(|activity| activity.start_ts >= after_ts && activity.start_ts < before_ts)
+            .collect();
+
+        // The same file copied twice is one activity, whichever copy the cursor is at
+        activities
+            .sort_by_key(|activity| (activity.start_ts, activity.content_id, activity.act_id));
+        activities.dedup_by_key(|activity| activity.content_id);
+
+        // Files without a start time all start at 0, the id orders them for the cursor
+        activities.retain(|activity| (activity.start_ts, activity.act_id) > (after_ts, after_id));
+        activities.sort_by_key(|activity| (activity.start_ts, activity.act_id));
+
+        Ok(activities
+            .into_iter()
+            .skip(page.saturating_sub(1) * per_page)
+            .take(per_page)
+            .map(|activity| {
+                json!({
+                    "id": activity.act_id,
+                    "start_date": activity.activity["start_date"],
+                    "name": activity.activity["name"],
+                    "type": activity.activity["type"],
+                    "revision": activity.content_id,
+                })
+            })
+            .collect())
+    }
+
+    async fn fetch_activity(&self, act_id: DocumentId) -> StravaResult<Value> {
+        self.find(act_id, |activity| activity.activity.clone())
+    }
+
+    async fn fetch_streams(&self, act_id: DocumentId) -> StravaResult<Value> {
+        self.find(act_id, |activity| activity.telemetry.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        data_types::strava::sync_job::{SyncJob, SyncJobKind, SyncJobState},
+        database::strava_db::StravaDB,
+        processors::sync_from_strava::StravaDBSync,
+        util::facilities::DependenciesBuilder,
+    };
+
+    const MORNING_RUN: &str = include_str!("../../fixtures/import/morning_run.gpx");
+
+    // Every scan starts from a new source, files written within the same mtime tick would look unchanged
+    async fn listed_ids(path: &Path) -> Vec<DocumentId> {
+        DirectorySource::new(path, 1)
+            .list_activities(0, DocumentId::MIN, i64::MAX, 10, 1)
+            .await
+            .unwrap()
+            .iter()
+            .map(|activity| activity["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn edited_files_keep_their_activity() {
+        let path = std::env::temp_dir().join(format!("gc_directory_{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        std::fs::write(path.join("run.gpx"), MORNING_RUN).unwrap();
+        let ids = listed_ids(&path).await;
+
+        std::fs::write(
+            path.join("run.gpx"),
+            MORNING_RUN.replace("Morning Run", "Renamed Run"),
+        )
+        .unwrap();
+        let edited_ids = listed_ids(&path).await;
+
+        // A copy is the same activity, listed once
+        std::fs::write(
+            path.join("copy.gpx"),
+            MORNING_RUN.replace("Morning Run", "Renamed Run"),
+        )
+        .unwrap();
+        let copied_ids = listed_ids(&path).await;
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(ids.len(), 1);
+        assert_eq!(edited_ids, ids);
+        assert_eq!(copied_ids.len(), 1);
+    }
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gc_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    // Track without times, like a route drawn in a planner
+    fn untimed_gpx(name: &str, lat: f32) -> String {
+        format!(
+            r#"<gpx><trk><name>{}</name><trkseg>
+                <trkpt lat="{}" lon="26.1000"></trkpt>
+                <trkpt lat="{}" lon="26.1010"></trkpt>
+            </trkseg></trk></gpx>"#,
+            name,
+            lat,
+            lat + 0.001
+        )
+    }
+
+    // Syncs the whole directory with a new source, like the pipeline does for sources which are not resumable
+    async fn sync_directory(strava_db: &Arc<StravaDB>, path: &Path, page_size: usize) -> SyncJob {
+        Config::init_test_global();
+
+        let dependencies = DependenciesBuilder::new().with_strava_db(strava_db).build();
+        let source = Arc::new(DirectorySource::new(path, 1));
+        let mut job = SyncJob::new(
+            1,
+            SyncJobKind::Incremental,
+            0,
+            Utc::now().timestamp(),
+            page_size,
+            Utc::now().timestamp(),
+        );
+
+        StravaDBSync::with_source(dependencies, 1, source)
+            .run_sync_job(&mut job)
+            .await
+            .unwrap();
+
+        job
+    }
+
+    #[tokio::test]
+    async fn files_starting_at_the_same_time_are_listed_page_by_page() {
+        let path = temp_directory("same_start");
+        for index in 0..5 {
+            std::fs::write(
+                path.join(format!("planned_{}.gpx", index)),
+                untimed_gpx(&format!("Planned {}", index), 44.4 + index as f32 * 0.01),
+            )
+            .unwrap();
+        }
+
+        let strava_db = Arc::new(StravaDB::in_memory());
+        let job = sync_directory(&strava_db, &path, 2).await;
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(job.state, SyncJobState::Completed);
+        assert_eq!(job.processed_count, 5);
+        assert_eq!(
+            strava_db.activities.get_athlete_activities(1).await.len(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn edited_files_are_synced_again() {
+        let path = temp_directory("edited_sync");
+        let strava_db = Arc::new(StravaDB::in_memory());
+
+        std::fs::write(path.join("run.gpx"), MORNING_RUN).unwrap();
+        sync_directory(&strava_db, &path, 10).await;
+
+        std::fs::write(
+            path.join("run.gpx"),
+            MORNING_RUN.replace("Morning Run", "Renamed Run"),
+        )
+        .unwrap();
+        sync_directory(&strava_db, &path, 10).await;
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let activities = strava_db.activities.get_athlete_activities(1).await;
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].name, "Renamed Run");
+    }
+}
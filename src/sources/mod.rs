@@ -0,0 +1,75 @@
+use std::{path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    data_types::{common::DocumentId, strava::athlete::AthleteId},
+    strava::{api::StravaApi, error::StravaResult, rate_limit::RateLimitQuota},
+};
+
+use self::{directory::DirectorySource, strava::StravaSource};
+
+pub mod directory;
+pub mod strava;
+
+// Where StravaDBSync gets activities from, documents use Strava's API schema whatever the backend
+#[async_trait]
+pub trait ActivitySource: Send + Sync {
+    // Stored in the 'source' field of the activities synced from it
+    fn name(&self) -> &str;
+
+    // Summaries with at least 'id' and 'start_date', started after after_ts and before before_ts, oldest first.
+    // Activities started at after_ts are listed when their id is greater than after_id, sources which can't
+    // order by id list them all. A 'revision' is set by sources whose activities change after they were synced
+    async fn list_activities(
+        &self,
+        after_ts: i64,
+        after_id: DocumentId,
+        before_ts: i64,
+        per_page: usize,
+        page: usize,
+    ) -> StravaResult<Vec<Value>>;
+
+    // Like the activity detail endpoint
+    async fn fetch_activity(&self, act_id: DocumentId) -> StravaResult<Value>;
+
+    // Streams keyed by type, like the streams endpoint with key_by_type
+    async fn fetch_streams(&self, act_id: DocumentId) -> StravaResult<Value>;
+
+    // None for sources without a request budget
+    fn remaining_quota(&self) -> Option<RateLimitQuota> {
+        None
+    }
+
+    // Listing a resumable source is expensive, its sync jobs are saved and it is synced by date ranges.
+    // Others are listed whole on every sync since files can be added for any date
+    fn resumable(&self) -> bool {
+        false
+    }
+}
+
+// Sources enabled in the config for the athlete, Strava needs the athlete's API client
+pub fn configured_sources(
+    strava_api: Option<Arc<StravaApi>>,
+    athlete_id: AthleteId,
+) -> Vec<Arc<dyn ActivitySource>> {
+    let sources_config = &Config::global().sources;
+    let mut sources: Vec<Arc<dyn ActivitySource>> = Vec::new();
+
+    if sources_config.strava {
+        if let Some(strava_api) = strava_api {
+            sources.push(Arc::new(StravaSource::new(strava_api)));
+        }
+    }
+
+    for directory in &sources_config.directories {
+        sources.push(Arc::new(DirectorySource::for_athlete(
+            Path::new(directory),
+            athlete_id,
+        )));
+    }
+
+    sources
+}
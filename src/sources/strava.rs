@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{
+    data_types::common::DocumentId,
+    strava::{api::StravaApi, error::StravaResult, rate_limit::RateLimitQuota},
+};
+
+use super::ActivitySource;
+
+pub const STRAVA_SOURCE: &str = "strava";
+
+pub struct StravaSource {
+    strava_api: Arc<StravaApi>,
+}
+
+impl StravaSource {
+    pub fn new(strava_api: Arc<StravaApi>) -> Self {
+        Self { strava_api }
+    }
+}
+
+#[async_trait]
+impl ActivitySource for StravaSource {
+    fn name(&self) -> &str {
+        STRAVA_SOURCE
+    }
+
+    // Strava only filters by date
+    async fn list_activities(
+        &self,
+        after_ts: i64,
+        _after_id: DocumentId,
+        before_ts: i64,
+        per_page: usize,
+        page: usize,
+    ) -> StravaResult<Vec<Value>> {
+        self.strava_api
+            .list_athlete_activities(after_ts, before_ts, per_page, page)
+            .await
+    }
+
+    async fn fetch_activity(&self, act_id: DocumentId) -> StravaResult<Value> {
+        self.strava_api.get_activity(act_id).await
+    }
+
+    async fn fetch_streams(&self, act_id: DocumentId) -> StravaResult<Value> {
+        self.strava_api.get_activity_telemetry(act_id).await
+    }
+
+    fn remaining_quota(&self) -> Option<RateLimitQuota> {
+        Some(self.strava_api.remaining_quota())
+    }
+
+    fn resumable(&self) -> bool {
+        true
+    }
+}
@@ -1,187 +1,322 @@
+use std::future::Future;
+use std::sync::Arc;
 
-use chrono::Utc;
-use curl::easy::{Easy, List};
-use serde_derive::Deserialize;
 use serde_json::Value;
-use toml;
 
+use crate::config::Config;
 use crate::data_types::strava::athlete::{AthleteId, AthleteTokens};
+use crate::strava::cassette::CassetteClient;
+use crate::strava::error::{StravaApiError, StravaResult};
+use crate::strava::http::{HttpClient, HttpRequest, HttpResponse, TransportConfig};
+use crate::strava::rate_limit::{RateLimitQuota, RateLimiter};
+use crate::strava::retry::{Endpoint, RetryPolicies};
 use crate::{logln, logvbln, TokenExchange};
 
-const STRAVA_BASE_URL: &str = "https://www.strava.com/api/v3/";
+const STRAVA_AUTHORIZE_URL: &str = "https://www.strava.com/oauth/authorize";
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug, Clone)]
 struct Secrets {
     client_id: String,
     client_secret: String,
-    user_authorization_code: String,
 }
 
 pub struct StravaApi {
     athlete_id: AthleteId,
     token_exchange: TokenExchange,
     secrets: Secrets,
+    transport: TransportConfig,
+    http_client: Arc<dyn HttpClient>,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policies: RetryPolicies,
 }
 
 impl StravaApi {
     const CC: &str = "StravaAPI";
 
-    fn get_refreshed_tokens(&self) -> AthleteTokens {
-        let mut handle = Easy::new();
-
+    async fn get_refreshed_tokens(&self, refresh_token: &str) -> StravaResult<AthleteTokens> {
         let header = format!(
-            "client_id={}&client_secret={}&code={}&\
+            "client_id={}&client_secret={}&\
              grant_type=refresh_token&\
              refresh_token={}",
             self.secrets.client_id,
             self.secrets.client_secret,
-            self.secrets.user_authorization_code,
-
-            self.token_exchange.get_tokens().refresh_token
+            refresh_token
         );
 
-        handle
-            .url(&(STRAVA_BASE_URL.to_string() + "oauth/token?" + &header))
-            .unwrap();
-
-        handle.post(true).unwrap();
+        let response = self
+            .http_client
+            .execute(HttpRequest::post(
+                &self.transport.url(&("oauth/token?".to_string() + &header)),
+            ))
+            .await?;
 
-        let mut buffer_response = Vec::new();
-        let mut transfer = handle.transfer();
+        let json_result = StravaApi::verify_if_error(&response)?;
+        logvbln!("{:?}", json_result);
 
-        transfer
-            .write_function(|data| {
-                buffer_response.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-
-        transfer.perform().unwrap();
-        drop(transfer);
+        Ok(serde_json::from_value(json_result)?)
+    }
 
-        let s = std::str::from_utf8(&buffer_response);
-        logvbln!("{:?}", s);
+    async fn get_request(&self, endpoint: Endpoint, url: &str) -> StravaResult<serde_json::Value> {
+        self.with_retries(endpoint, url, || self.send_request(url))
+            .await
+    }
 
-        serde_json::from_str(s.unwrap()).unwrap()
+    // Runs 'request' until it succeeds or the endpoint's retry policy gives up
+    async fn with_retries<T, F, Fut>(
+        &self,
+        endpoint: Endpoint,
+        url: &str,
+        request: F,
+    ) -> StravaResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = StravaResult<T>>,
+    {
+        let policy = self.retry_policies.for_endpoint(endpoint);
+        let mut attempt = 0;
+
+        loop {
+            match request().await {
+                Ok(result) => return Ok(result),
+                Err(err) => {
+                    if let Some(delay) = policy.delay_for(attempt, &err) {
+                        logln!(
+                            "Request to {} failed ({}), retry {} in {}ms",
+                            url,
+                            err,
+                            attempt + 1,
+                            delay.as_millis()
+                        );
+
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    } else {
+                        return Err(err);
+                    }
+                }
+            }
+        }
     }
 
-    async fn get_request(&self, url: &str) -> Option<serde_json::Value> {
-        self.refresh_tokens_if_expired().await;
+    async fn send_request(&self, url: &str) -> StravaResult<serde_json::Value> {
+        self.refresh_tokens_if_expired().await?;
 
         let bearer = self.get_access_token();
 
-        let mut handle = Easy::new();
-        let mut list = List::new();
-
-        list.append(&format!("Authorization: Bearer {}", bearer))
-            .unwrap();
-        handle.http_headers(list).unwrap();
-
-        handle.get(true).unwrap();
-
-        handle.url(url).unwrap();
-
-        let mut buffer_response = Vec::new();
-        let mut transfer = handle.transfer();
-
-        transfer
-            .write_function(|data| {
-                buffer_response.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-
-        transfer.perform().unwrap();
-        drop(transfer);
+        self.rate_limiter.acquire().await?;
 
-        let s = std::str::from_utf8(&buffer_response);
+        let response = self
+            .http_client
+            .execute(
+                HttpRequest::get(url).with_header("Authorization", &format!("Bearer {}", bearer)),
+            )
+            .await
+            .map_err(|err| {
+                logln!("Request to {} failed: {}", url, err);
+                err
+            })?;
 
-        let result = serde_json::from_str(s.unwrap());
+        self.rate_limiter.update_from_response(&response);
 
-        StravaApi::verify_if_error(result)
+        StravaApi::verify_if_error(&response)
     }
 
-    fn verify_if_error(
-        result: Result<serde_json::Value, serde_json::Error>,
-    ) -> Option<serde_json::Value> {
-        if let Some(json_result) = result.ok() {
-            if let Some(_) = json_result.get("errors") {
-                panic!("{:?}", json_result.get("message").unwrap())
-            } else {
-                return Some(json_result);
-            }
-        } else {
-            return None;
+    fn verify_if_error(response: &HttpResponse) -> StravaResult<serde_json::Value> {
+        if let Some(err) = StravaApiError::from_response(response) {
+            return Err(err);
         }
+
+        let json_result: serde_json::Value = serde_json::from_slice(&response.body)?;
+
+        // Strava can also report failures inside a successful response
+        if json_result.get("errors").is_some() {
+            return Err(StravaApiError::Api {
+                status: response.status,
+                message: json_result
+                    .get("message")
+                    .and_then(|message| message.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+
+        Ok(json_result)
     }
 
-    fn read_secrets_from_file() -> Secrets {
-        let secrets_content = std::fs::read_to_string(
-            std::env::current_dir()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned()
-                + "/secrets.toml",
-        )
-        .expect("Unable to open secrets.toml");
+    fn read_secrets_from_config() -> Secrets {
+        let strava_config = &Config::global().strava;
 
-        toml::from_str(&secrets_content).unwrap()
+        Secrets {
+            client_id: strava_config.client_id.clone(),
+            client_secret: strava_config.client_secret.clone(),
+        }
     }
 
     fn get_access_token(&self) -> String {
-        return self.token_exchange.get_tokens().access_token.clone();
+        self.token_exchange.get_tokens().access_token.clone()
     }
 
-    async fn refresh_tokens_if_expired(&self) {
-        let current_ts: i64 = Utc::now().timestamp();
+    async fn refresh_tokens_if_expired(&self) -> StravaResult<()> {
+        self.token_exchange
+            .refresh_if_expiring(|refresh_token| async move {
+                self.with_retries(Endpoint::OAuthToken, "oauth/token", || {
+                    self.get_refreshed_tokens(&refresh_token)
+                })
+                .await
+            })
+            .await
+    }
 
-        if current_ts > self.token_exchange.get_tokens().expires_at as i64 {
-            logln!("Tokens EXPIRED. Refreshing");
+    // Fails when the configured cassette can't be read
+    pub fn new(token_exchange: TokenExchange, athlete_id: i64) -> std::io::Result<Self> {
+        let transport = TransportConfig::from_config(&Config::global().strava);
+        let http_client = CassetteClient::from_config(&transport)?;
 
-            let new_tokens = self.get_refreshed_tokens();            
-            self.token_exchange.set_tokens(&new_tokens).await;
-        }
+        Ok(StravaApi::with_transport(
+            token_exchange,
+            athlete_id,
+            transport,
+            http_client,
+        ))
     }
 
-    pub fn new(
+    // Used to point the API to a different server (mock Strava) or to plug in another HTTP client
+    pub fn with_transport(
         token_exchange: TokenExchange,
         athlete_id: i64,
+        transport: TransportConfig,
+        http_client: Arc<dyn HttpClient>,
     ) -> Self {
         Self {
             athlete_id,
             token_exchange,
-            secrets: StravaApi::read_secrets_from_file(),
+            secrets: StravaApi::read_secrets_from_config(),
+            transport,
+            http_client,
+            rate_limiter: RateLimiter::shared(),
+            retry_policies: RetryPolicies::from(Config::global()),
         }
     }
 
-    pub async fn get_activity(&self, act_id: i64) -> Option<serde_json::Value> {
+    pub fn with_retry_policies(mut self, retry_policies: RetryPolicies) -> Self {
+        self.retry_policies = retry_policies;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn remaining_quota(&self) -> RateLimitQuota {
+        self.rate_limiter.quota()
+    }
+
+    // Page where the athlete grants us access, Strava then redirects to redirect_uri with a code
+    // and the same state, which ties the callback to the browser that started the flow
+    pub fn authorize_url(redirect_uri: &str, state: &str) -> StravaResult<String> {
+        let secrets = StravaApi::read_secrets_from_config();
+
+        let url = reqwest::Url::parse_with_params(
+            STRAVA_AUTHORIZE_URL,
+            &[
+                ("client_id", secrets.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("response_type", "code"),
+                ("approval_prompt", "auto"),
+                ("scope", "read,activity:read_all"),
+                ("state", state),
+            ],
+        )
+        .map_err(|err| StravaApiError::Api {
+            status: 0,
+            message: err.to_string(),
+        })?;
+
+        Ok(url.to_string())
+    }
+
+    // Exchanges the code received after authorization for the athlete's tokens
+    pub async fn exchange_authorization_code(
+        transport: &TransportConfig,
+        http_client: &dyn HttpClient,
+        code: &str,
+    ) -> StravaResult<(AthleteId, AthleteTokens)> {
+        let secrets = StravaApi::read_secrets_from_config();
+
+        let url = reqwest::Url::parse_with_params(
+            &transport.url("oauth/token"),
+            &[
+                ("client_id", secrets.client_id.as_str()),
+                ("client_secret", secrets.client_secret.as_str()),
+                ("code", code),
+                ("grant_type", "authorization_code"),
+            ],
+        )
+        .map_err(|err| StravaApiError::Api {
+            status: 0,
+            message: err.to_string(),
+        })?;
+
+        let response = http_client.execute(HttpRequest::post(url.as_str())).await?;
+        let json_result = StravaApi::verify_if_error(&response)?;
+
+        let athlete_id = json_result["athlete"]["id"].as_i64().ok_or_else(|| {
+            StravaApiError::MalformedJson("missing athlete id in token response".to_string())
+        })?;
+
+        Ok((athlete_id, serde_json::from_value(json_result)?))
+    }
+
+    pub async fn get_athlete(&self) -> StravaResult<serde_json::Value> {
+        self.get_request(Endpoint::Athlete, &self.transport.url("athlete"))
+            .await
+    }
+
+    // Asked to Strava, a deauthorization event alone can be sent by anyone
+    pub async fn is_access_revoked(&self) -> StravaResult<bool> {
+        match self.get_athlete().await {
+            Ok(_) => Ok(false),
+            // Access token refused or, when it had to be refreshed first, refresh token refused
+            Err(err) if TokenExchange::is_rejected_refresh(&err) => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn get_activity(&self, act_id: i64) -> StravaResult<serde_json::Value> {
         self.get_request(
-            &(STRAVA_BASE_URL.to_string() + &format!("activities/{}", act_id.to_string())),
+            Endpoint::Activity,
+            &self
+                .transport
+                .url(&format!("activities/{}", act_id)),
         )
         .await
     }
 
-    pub async fn get_activity_telemetry(&self, act_id: i64) -> Option<serde_json::Value> {
+    pub async fn get_activity_telemetry(&self, act_id: i64) -> StravaResult<serde_json::Value> {
         self.get_request(
-            &(STRAVA_BASE_URL.to_string() + &format!("activities/{}/streams?keys=time,latlng,altitude,velocity_smooth,grade_smooth,distance&key_by_type=true", act_id.to_string()))
+            Endpoint::ActivityTelemetry,
+            &self.transport.url(&format!("activities/{}/streams?keys=time,latlng,altitude,velocity_smooth,grade_smooth,distance,heartrate,cadence,watts,temp,moving&key_by_type=true", act_id))
         ).await
     }
 
-    pub async fn get_segment(&self, seg_id: i64) -> Option<serde_json::Value> {
+    pub async fn get_segment(&self, seg_id: i64) -> StravaResult<serde_json::Value> {
         self.get_request(
-            &(STRAVA_BASE_URL.to_string() + &format!("segments/{}", seg_id.to_string())),
+            Endpoint::Segment,
+            &self
+                .transport
+                .url(&format!("segments/{}", seg_id)),
         )
         .await
     }
 
-    pub async fn get_segment_telemetry(&self, seg_id: i64) -> Option<serde_json::Value> {
+    pub async fn get_segment_telemetry(&self, seg_id: i64) -> StravaResult<serde_json::Value> {
         self.get_request(
-            &(STRAVA_BASE_URL.to_string()
-                + &format!(
-                    "/segments/{}/streams?keys=latlng,distance,altitude&key_by_type=true",
-                    seg_id.to_string()
-                )),
+            Endpoint::SegmentTelemetry,
+            &self.transport.url(&format!(
+                "/segments/{}/streams?keys=latlng,distance,altitude&key_by_type=true",
+                seg_id
+            )),
         )
         .await
     }
@@ -192,21 +327,126 @@ impl StravaApi {
         before_ts: i64,
         per_page: usize,
         page: usize,
-    ) -> Option<Vec<Value>> {
+    ) -> StravaResult<Vec<Value>> {
         let result = self
             .get_request(
-                &(STRAVA_BASE_URL.to_string()
-                    + &format!(
-                        "athlete/activities?after={}&before={}&per_page={}&page={}",
-                        after_ts, before_ts, per_page, page
-                    )),
+                Endpoint::AthleteActivities,
+                &self.transport.url(&format!(
+                    "athlete/activities?after={}&before={}&per_page={}&page={}",
+                    after_ts, before_ts, per_page, page
+                )),
             )
-            .await;
+            .await?;
 
-        if let Some(activities) = result {
-            return Some(activities.as_array().unwrap().to_vec());
-        } else {
-            return None;
+        match result.as_array() {
+            Some(activities) => Ok(activities.to_vec()),
+            None => Err(StravaApiError::MalformedJson(
+                "expected a list of activities".to_string(),
+            )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        data_types::strava::athlete::AthleteTokens,
+        database::strava_db::StravaDB,
+        strava::{
+            error::StravaApiError,
+            http::TransportError,
+            rate_limit::{RateLimitConfig, RateLimiter},
+        },
+    };
+
+    // Answers with a server error to the first 'failures' requests
+    struct FlakyClient {
+        failures: usize,
+        requests: AtomicUsize,
+    }
+
+    impl FlakyClient {
+        fn new(failures: usize) -> Arc<Self> {
+            Arc::new(Self {
+                failures,
+                requests: AtomicUsize::new(0),
+            })
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for FlakyClient {
+        async fn execute(&self, _request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            let status = if self.requests.fetch_add(1, Ordering::SeqCst) < self.failures {
+                503
+            } else {
+                200
+            };
+
+            Ok(HttpResponse {
+                status,
+                headers: HashMap::new(),
+                body: b"{}".to_vec(),
+            })
+        }
+    }
+
+    async fn flaky_api(client: &Arc<FlakyClient>, config: &Config) -> StravaApi {
+        Config::init_test_global();
+
+        let token_exchange = TokenExchange::new(
+            StravaDB::in_memory().get_athletes_collection(),
+            5,
+            AthleteTokens {
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at: i64::MAX / 2,
+            },
+        )
+        .await;
+
+        StravaApi::with_transport(
+            token_exchange,
+            5,
+            TransportConfig::default(),
+            client.clone(),
+        )
+        .with_rate_limiter(Arc::new(RateLimiter::new(RateLimitConfig::default())))
+        .with_retry_policies(RetryPolicies::from(config))
+    }
+
+    #[tokio::test]
+    async fn endpoint_retry_settings_take_effect() {
+        let mut config = Config::default();
+        config.set("pipeline.max_retries", "0").unwrap();
+        config
+            .set("strava.retry.activity.max_retries", "1")
+            .unwrap();
+        config
+            .set("strava.retry.activity.base_delay_ms", "0")
+            .unwrap();
+
+        let client = FlakyClient::new(1);
+        let strava_api = flaky_api(&client, &config).await;
+        assert!(strava_api.get_activity(1).await.is_ok());
+        assert_eq!(client.requests(), 2);
+
+        let client = FlakyClient::new(1);
+        let strava_api = flaky_api(&client, &config).await;
+        assert!(matches!(
+            strava_api.get_segment(1).await,
+            Err(StravaApiError::ServerError { status: 503 })
+        ));
+        assert_eq!(client.requests(), 1);
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    logln,
+    strava::http::{
+        HttpClient, HttpMethod, HttpRequest, HttpResponse, ReqwestClient, TransportConfig,
+        TransportError,
+    },
+};
+
+// Every StravaApi created while a cassette is configured shares it, so recordings are not overwritten
+static CASSETTES: Lazy<Mutex<HashMap<PathBuf, Arc<CassetteClient>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Never written to cassettes, they are committed as test fixtures
+const FILTERED_QUERY_PARAMS: &[&str] = &["client_id", "client_secret", "refresh_token", "code"];
+const FILTERED_BODY_FIELDS: &[&str] = &["access_token", "refresh_token"];
+const FILTERED: &str = "FILTERED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    // Requests go to Strava and every interaction is appended to the cassette
+    Record,
+    // Requests are answered from the cassette, nothing is sent
+    #[default]
+    Replay,
+}
+
+impl FromStr for CassetteMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cassette {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    played: Vec<bool>,
+    unmatched: Vec<String>,
+}
+
+pub struct CassetteClient {
+    path: PathBuf,
+    mode: CassetteMode,
+    // Only used when recording
+    inner: Option<Arc<dyn HttpClient>>,
+    cassette: Mutex<Cassette>,
+    replay_state: Mutex<ReplayState>,
+}
+
+impl CassetteClient {
+    const CC: &str = "Cassette";
+
+    // Starts an empty cassette, an existing file is replaced on the first request
+    pub fn record(path: &Path, inner: Arc<dyn HttpClient>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            mode: CassetteMode::Record,
+            inner: Some(inner),
+            cassette: Mutex::new(Default::default()),
+            replay_state: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn replay(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let cassette: Cassette = serde_json::from_str(&content)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode: CassetteMode::Replay,
+            inner: None,
+            replay_state: Mutex::new(ReplayState {
+                played: vec![false; cassette.interactions.len()],
+                unmatched: Vec::new(),
+            }),
+            cassette: Mutex::new(cassette),
+        })
+    }
+
+    // Client for the configured cassette, the real network when there is none
+    // fails when the cassette to replay can't be read
+    pub fn from_config(transport: &TransportConfig) -> std::io::Result<Arc<dyn HttpClient>> {
+        let strava_config = &Config::global().strava;
+
+        let Some(path) = &strava_config.cassette else {
+            return Ok(Arc::new(ReqwestClient::new(transport)));
+        };
+
+        let path = PathBuf::from(path);
+        let mut cassettes = CASSETTES.lock().unwrap();
+
+        if let Some(cassette) = cassettes.get(&path) {
+            return Ok(cassette.clone());
+        }
+
+        let cassette = Arc::new(match strava_config.cassette_mode {
+            CassetteMode::Record => {
+                CassetteClient::record(&path, Arc::new(ReqwestClient::new(transport)))
+            }
+            CassetteMode::Replay => CassetteClient::replay(&path).map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("unable to read cassette {}: {}", path.display(), err),
+                )
+            })?,
+        });
+
+        logln!(
+            "Strava requests use cassette {} ({:?})",
+            path.display(),
+            strava_config.cassette_mode
+        );
+
+        cassettes.insert(path, cassette.clone());
+
+        Ok(cassette)
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    // For tests to check a replay went as recorded: every request matched and every interaction was played
+    pub fn verify(&self) -> Result<(), String> {
+        let cassette = self.cassette.lock().unwrap();
+        let replay_state = self.replay_state.lock().unwrap();
+
+        let unplayed: Vec<String> = cassette
+            .interactions
+            .iter()
+            .zip(replay_state.played.iter())
+            .filter(|(_, played)| !**played)
+            .map(|(interaction, _)| {
+                format!("{} {}", interaction.request.method, interaction.request.url)
+            })
+            .collect();
+
+        if replay_state.unmatched.is_empty() && unplayed.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!(
+            "unmatched requests: [{}], interactions never played: [{}]",
+            replay_state.unmatched.join(", "),
+            unplayed.join(", ")
+        ))
+    }
+
+    // Identical requests are answered in the order they were recorded
+    fn play(&self, request: &RecordedRequest) -> Result<HttpResponse, TransportError> {
+        let cassette = self.cassette.lock().unwrap();
+        let mut replay_state = self.replay_state.lock().unwrap();
+
+        let index = cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .position(|(index, interaction)| {
+                !replay_state.played[index]
+                    && interaction.request.method == request.method
+                    && interaction.request.url == request.url
+            });
+
+        let Some(index) = index else {
+            let description = format!("{} {}", request.method, request.url);
+
+            logln!(
+                "No recorded interaction for {} in {}",
+                description,
+                self.path.display()
+            );
+            replay_state.unmatched.push(description.clone());
+
+            return Err(TransportError {
+                message: format!("no recorded interaction for {}", description),
+                is_timeout: false,
+            });
+        };
+
+        replay_state.played[index] = true;
+
+        let response = &cassette.interactions[index].response;
+
+        Ok(HttpResponse {
+            status: response.status,
+            headers: response.headers.clone(),
+            body: response.body.clone().into_bytes(),
+        })
+    }
+
+    // Transport errors are not recorded, replaying them would need a response
+    fn append(&self, request: RecordedRequest, response: &HttpResponse) {
+        let mut cassette = self.cassette.lock().unwrap();
+
+        cassette.interactions.push(Interaction {
+            request,
+            response: RecordedResponse {
+                status: response.status,
+                headers: response.headers.clone(),
+                body: CassetteClient::filter_body(&response.body),
+            },
+        });
+
+        // Saved after every interaction so a recording survives the process being stopped
+        if let Some(parent) = self.path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+
+        if let Err(err) = std::fs::write(
+            &self.path,
+            serde_json::to_string_pretty(&*cassette).unwrap(),
+        ) {
+            logln!("Unable to write cassette {}: {}", self.path.display(), err);
+        }
+    }
+
+    // Method and url with the secrets replaced, headers are left out since they only carry the bearer token
+    fn recorded_request(request: &HttpRequest) -> RecordedRequest {
+        let method = match request.method {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+        };
+
+        let url = match reqwest::Url::parse(&request.url) {
+            Ok(mut url) => {
+                let query: Vec<(String, String)> = url
+                    .query_pairs()
+                    .map(|(name, value)| {
+                        if FILTERED_QUERY_PARAMS.contains(&name.as_ref()) {
+                            (name.to_string(), FILTERED.to_string())
+                        } else {
+                            (name.to_string(), value.to_string())
+                        }
+                    })
+                    .collect();
+
+                if !query.is_empty() {
+                    url.query_pairs_mut().clear().extend_pairs(query);
+                }
+
+                url.to_string()
+            }
+            Err(_) => request.url.clone(),
+        };
+
+        RecordedRequest {
+            method: method.to_string(),
+            url,
+        }
+    }
+
+    fn filter_body(body: &[u8]) -> String {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(mut json)) => {
+                for field in FILTERED_BODY_FIELDS {
+                    if let Some(value) = json.get_mut(*field) {
+                        *value = Value::String(FILTERED.to_string());
+                    }
+                }
+
+                Value::Object(json).to_string()
+            }
+            _ => String::from_utf8_lossy(body).to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl HttpClient for CassetteClient {
+    async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        let recorded_request = CassetteClient::recorded_request(&request);
+
+        match &self.inner {
+            Some(inner) => {
+                let response = inner.execute(request).await?;
+                self.append(recorded_request, &response);
+
+                Ok(response)
+            }
+            None => self.play(&recorded_request),
+        }
+    }
+}
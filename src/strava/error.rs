@@ -0,0 +1,87 @@
+use crate::strava::http::{HttpResponse, TransportError};
+
+#[derive(Debug, Clone)]
+pub enum StravaApiError {
+    // Request never got a response (DNS, connection, timeout)
+    Transport(TransportError),
+    // 401 - tokens are invalid or the athlete revoked access
+    Unauthorized,
+    // 404 - resource was deleted or is private
+    NotFound,
+    // 429 - quota exhausted, retry_after in seconds if server specified it
+    RateLimited { retry_after: Option<u64> },
+    // 5xx
+    ServerError { status: u16 },
+    // Response body could not be parsed
+    MalformedJson(String),
+    // Any other error status or an 'errors' payload returned by Strava
+    Api { status: u16, message: String },
+}
+
+pub type StravaResult<T> = Result<T, StravaApiError>;
+
+impl StravaApiError {
+    // Classifies the response, returns None for successful ones
+    pub fn from_response(response: &HttpResponse) -> Option<StravaApiError> {
+        match response.status {
+            200..=299 => None,
+            401 => Some(StravaApiError::Unauthorized),
+            404 => Some(StravaApiError::NotFound),
+            429 => Some(StravaApiError::RateLimited {
+                retry_after: response
+                    .header("Retry-After")
+                    .and_then(|value| value.trim().parse::<u64>().ok()),
+            }),
+            500..=599 => Some(StravaApiError::ServerError {
+                status: response.status,
+            }),
+            status => Some(StravaApiError::Api {
+                status,
+                message: String::from_utf8_lossy(&response.body).to_string(),
+            }),
+        }
+    }
+
+    // Errors which might go away if the same request is sent again later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StravaApiError::Transport(_)
+                | StravaApiError::RateLimited { .. }
+                | StravaApiError::ServerError { .. }
+        )
+    }
+}
+
+impl From<TransportError> for StravaApiError {
+    fn from(err: TransportError) -> Self {
+        StravaApiError::Transport(err)
+    }
+}
+
+impl From<serde_json::Error> for StravaApiError {
+    fn from(err: serde_json::Error) -> Self {
+        StravaApiError::MalformedJson(err.to_string())
+    }
+}
+
+impl std::fmt::Display for StravaApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StravaApiError::Transport(err) => write!(f, "transport error: {}", err),
+            StravaApiError::Unauthorized => write!(f, "unauthorized"),
+            StravaApiError::NotFound => write!(f, "resource not found"),
+            StravaApiError::RateLimited { retry_after } => match retry_after {
+                Some(secs) => write!(f, "rate limited, retry after {}s", secs),
+                None => write!(f, "rate limited"),
+            },
+            StravaApiError::ServerError { status } => write!(f, "server error {}", status),
+            StravaApiError::MalformedJson(err) => write!(f, "malformed json: {}", err),
+            StravaApiError::Api { status, message } => {
+                write!(f, "api error {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StravaApiError {}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+
+use crate::config::StravaConfig;
+
+pub const STRAVA_BASE_URL: &str = "https://www.strava.com/api/v3/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    // Header names are stored lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportError {
+    pub message: String,
+    pub is_timeout: bool,
+}
+
+impl HttpRequest {
+    pub fn get(url: &str) -> Self {
+        Self {
+            method: HttpMethod::Get,
+            url: url.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn post(url: &str) -> Self {
+        Self {
+            method: HttpMethod::Post,
+            url: url.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.get(&name.to_lowercase())
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_timeout {
+            write!(f, "timeout: {}", self.message)
+        } else {
+            write!(f, "{}", self.message)
+        }
+    }
+}
+
+// Anything able to send a request to the Strava API (real network, mock server, fixtures)
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, TransportError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    pub base_url: String,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            base_url: STRAVA_BASE_URL.to_string(),
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn from_config(config: &StravaConfig) -> Self {
+        Self {
+            base_url: config.api_url.clone(),
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    // Joins the base url and the endpoint path making sure there is exactly one '/' between them
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+}
+
+pub struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .connect_timeout(config.connect_timeout)
+                .timeout(config.request_timeout)
+                .build()
+                .expect("Unable to create HTTP client"),
+        }
+    }
+}
+
+#[async_trait]
+impl HttpClient for ReqwestClient {
+    async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        let to_transport_error = |err: reqwest::Error| TransportError {
+            is_timeout: err.is_timeout(),
+            message: err.to_string(),
+        };
+
+        let mut builder = match request.method {
+            HttpMethod::Get => self.client.get(&request.url),
+            HttpMethod::Post => self.client.post(&request.url),
+        };
+
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        let response = builder.send().await.map_err(to_transport_error)?;
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.as_str().to_lowercase(), value.to_string()))
+            })
+            .collect();
+
+        let body = response.bytes().await.map_err(to_transport_error)?.to_vec();
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
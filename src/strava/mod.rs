@@ -1 +1,6 @@
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod cassette;
+pub mod error;
+pub mod http;
+pub mod rate_limit;
+pub mod retry;
\ No newline at end of file
@@ -0,0 +1,378 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+
+use crate::{
+    config::{Config, PipelineConfig},
+    logln,
+    strava::{
+        error::{StravaApiError, StravaResult},
+        http::HttpResponse,
+    },
+};
+
+// Strava quotas are per application, so all StravaApi instances share the same budget
+static SHARED_RATE_LIMITER: Lazy<Arc<RateLimiter>> = Lazy::new(|| {
+    Arc::new(RateLimiter::new(RateLimitConfig::from(
+        &Config::global().pipeline,
+    )))
+});
+
+const SHORT_WINDOW_SECS: i64 = 15 * 60;
+const DAILY_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    // Number of requests kept aside in each window so the budget is never fully consumed
+    pub short_reserve: u32,
+    pub daily_reserve: u32,
+    // Longer waits are reported as RateLimited instead of blocking the caller
+    pub max_wait: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            short_reserve: 2,
+            daily_reserve: 10,
+            max_wait: Duration::from_secs(SHORT_WINDOW_SECS as u64),
+        }
+    }
+}
+
+impl From<&PipelineConfig> for RateLimitConfig {
+    fn from(config: &PipelineConfig) -> Self {
+        Self {
+            short_reserve: config.rate_limit_short_reserve,
+            daily_reserve: config.rate_limit_daily_reserve,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitQuota {
+    // A limit of 0 means Strava did not report it yet
+    pub short_limit: u32,
+    pub short_usage: u32,
+    pub daily_limit: u32,
+    pub daily_usage: u32,
+    // Seconds until each window resets
+    pub short_reset_in: u64,
+    pub daily_reset_in: u64,
+}
+
+impl RateLimitQuota {
+    pub fn short_remaining(&self) -> Option<u32> {
+        RateLimitQuota::remaining(self.short_limit, self.short_usage)
+    }
+
+    pub fn daily_remaining(&self) -> Option<u32> {
+        RateLimitQuota::remaining(self.daily_limit, self.daily_usage)
+    }
+
+    fn remaining(limit: u32, usage: u32) -> Option<u32> {
+        if limit == 0 {
+            return None;
+        }
+
+        Some(limit.saturating_sub(usage))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Budget {
+    short_limit: u32,
+    short_usage: u32,
+    daily_limit: u32,
+    daily_usage: u32,
+    // Start of the windows the usage above was counted in
+    short_window_start: i64,
+    daily_window_start: i64,
+}
+
+impl Budget {
+    fn roll_windows(&mut self, now: i64) {
+        let short_window_start = now - now % SHORT_WINDOW_SECS;
+        let daily_window_start = now - now % DAILY_WINDOW_SECS;
+
+        if short_window_start != self.short_window_start {
+            self.short_window_start = short_window_start;
+            self.short_usage = 0;
+        }
+
+        if daily_window_start != self.daily_window_start {
+            self.daily_window_start = daily_window_start;
+            self.daily_usage = 0;
+        }
+    }
+
+    // Seconds to wait before sending another request, 0 if one can be sent right away
+    fn wait_for(&self, now: i64, config: &RateLimitConfig) -> u64 {
+        let exhausted = |limit: u32, usage: u32, reserve: u32| -> bool {
+            limit != 0 && usage + reserve >= limit
+        };
+
+        if exhausted(self.daily_limit, self.daily_usage, config.daily_reserve) {
+            return (self.daily_window_start + DAILY_WINDOW_SECS - now) as u64;
+        }
+
+        if exhausted(self.short_limit, self.short_usage, config.short_reserve) {
+            return (self.short_window_start + SHORT_WINDOW_SECS - now) as u64;
+        }
+
+        0
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    budget: Mutex<Budget>,
+    // Requests waiting for the budget to refill are queued behind this
+    queue: tokio::sync::Mutex<()>,
+    // Current unix timestamp, tests move it along with tokio's paused time
+    clock: Box<dyn Fn() -> i64 + Send + Sync>,
+}
+
+impl RateLimiter {
+    const CC: &str = "RateLimiter";
+
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            budget: Mutex::new(Default::default()),
+            queue: tokio::sync::Mutex::new(()),
+            clock: Box::new(|| Utc::now().timestamp()),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_clock(mut self, clock: impl Fn() -> i64 + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    pub fn shared() -> Arc<RateLimiter> {
+        Arc::clone(&SHARED_RATE_LIMITER)
+    }
+
+    // Waits until a request can be sent within the budget and books it
+    pub async fn acquire(&self) -> StravaResult<()> {
+        let _queue_slot = self.queue.lock().await;
+
+        loop {
+            let wait_secs = {
+                let now = (self.clock)();
+                let mut budget = self.budget.lock().unwrap();
+                budget.roll_windows(now);
+
+                let wait_secs = budget.wait_for(now, &self.config);
+
+                if wait_secs == 0 {
+                    budget.short_usage += 1;
+                    budget.daily_usage += 1;
+
+                    return Ok(());
+                }
+
+                wait_secs
+            };
+
+            if Duration::from_secs(wait_secs) > self.config.max_wait {
+                return Err(StravaApiError::RateLimited {
+                    retry_after: Some(wait_secs),
+                });
+            }
+
+            logln!("Rate limit nearly reached, waiting {}s", wait_secs);
+            tokio::time::sleep(Duration::from_secs(wait_secs)).await;
+        }
+    }
+
+    // Strava reports "15min,daily" pairs, e.g. X-RateLimit-Usage: 20,300
+    pub fn update_from_response(&self, response: &HttpResponse) {
+        let parse_pair = |header: &str| -> Option<(u32, u32)> {
+            let value = response.header(header)?;
+            let mut values = value.split(',').map(|v| v.trim().parse::<u32>().ok());
+
+            Some((values.next()??, values.next()??))
+        };
+
+        let now = (self.clock)();
+        let mut budget = self.budget.lock().unwrap();
+        budget.roll_windows(now);
+
+        if let Some((short_limit, daily_limit)) = parse_pair("X-RateLimit-Limit") {
+            budget.short_limit = short_limit;
+            budget.daily_limit = daily_limit;
+        }
+
+        if let Some((short_usage, daily_usage)) = parse_pair("X-RateLimit-Usage") {
+            budget.short_usage = short_usage;
+            budget.daily_usage = daily_usage;
+        }
+
+        // Rejected for quota but no headers to tell which window, assume the short one is used up
+        if response.status == 429 && budget.short_usage < budget.short_limit {
+            budget.short_usage = budget.short_limit;
+        }
+    }
+
+    pub fn quota(&self) -> RateLimitQuota {
+        let now = (self.clock)();
+        let mut budget = self.budget.lock().unwrap();
+        budget.roll_windows(now);
+
+        RateLimitQuota {
+            short_limit: budget.short_limit,
+            short_usage: budget.short_usage,
+            daily_limit: budget.daily_limit,
+            daily_usage: budget.daily_usage,
+            short_reset_in: (budget.short_window_start + SHORT_WINDOW_SECS - now) as u64,
+            daily_reset_in: (budget.daily_window_start + DAILY_WINDOW_SECS - now) as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // Midnight UTC, both windows start here
+    const DAY_START: i64 = 1685577600;
+
+    // Starts a minute into the day and follows tokio's paused time
+    fn paused_limiter(config: RateLimitConfig) -> RateLimiter {
+        let start = tokio::time::Instant::now();
+
+        RateLimiter::new(config)
+            .with_clock(move || DAY_START + 60 + start.elapsed().as_secs() as i64)
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                .collect::<HashMap<String, String>>(),
+            body: Vec::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn usage_resets_when_its_window_rolls_over() {
+        let rate_limiter = paused_limiter(Default::default());
+        rate_limiter.update_from_response(&response(
+            200,
+            &[
+                ("X-RateLimit-Limit", "100,1000"),
+                ("X-RateLimit-Usage", "50,500"),
+            ],
+        ));
+
+        let quota = rate_limiter.quota();
+        assert_eq!((quota.short_usage, quota.daily_usage), (50, 500));
+        assert_eq!(quota.short_reset_in, 840);
+
+        tokio::time::advance(Duration::from_secs(840)).await;
+
+        let quota = rate_limiter.quota();
+        assert_eq!((quota.short_usage, quota.daily_usage), (0, 500));
+        assert_eq!(quota.short_remaining(), Some(100));
+
+        tokio::time::advance(Duration::from_secs(quota.daily_reset_in)).await;
+
+        let quota = rate_limiter.quota();
+        assert_eq!((quota.short_usage, quota.daily_usage), (0, 0));
+        assert_eq!(quota.daily_reset_in, DAILY_WINDOW_SECS as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_or_missing_headers_are_ignored() {
+        let rate_limiter = paused_limiter(Default::default());
+
+        rate_limiter.update_from_response(&response(200, &[]));
+        assert_eq!(
+            rate_limiter.quota(),
+            RateLimitQuota {
+                short_reset_in: 840,
+                daily_reset_in: (DAILY_WINDOW_SECS - 60) as u64,
+                ..Default::default()
+            }
+        );
+        assert_eq!(rate_limiter.quota().short_remaining(), None);
+
+        rate_limiter.update_from_response(&response(
+            200,
+            &[
+                ("X-RateLimit-Limit", "100,1000"),
+                ("X-RateLimit-Usage", "10,20"),
+            ],
+        ));
+
+        for (limit, usage) in [("100", "11"), ("100,abc", ",21"), ("", "-1,22")] {
+            rate_limiter.update_from_response(&response(
+                200,
+                &[("X-RateLimit-Limit", limit), ("X-RateLimit-Usage", usage)],
+            ));
+        }
+
+        let quota = rate_limiter.quota();
+        assert_eq!((quota.short_limit, quota.daily_limit), (100, 1000));
+        assert_eq!((quota.short_usage, quota.daily_usage), (10, 20));
+
+        // Rejected without headers, the short window is taken as used up
+        rate_limiter.update_from_response(&response(429, &[]));
+        assert_eq!(rate_limiter.quota().short_remaining(), Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_waits_for_the_next_window_once_the_budget_is_used() {
+        let rate_limiter = paused_limiter(Default::default());
+        rate_limiter.update_from_response(&response(
+            200,
+            &[
+                ("X-RateLimit-Limit", "10,1000"),
+                ("X-RateLimit-Usage", "7,7"),
+            ],
+        ));
+
+        let start = tokio::time::Instant::now();
+
+        // Third request would eat into the reserve of 2
+        rate_limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        rate_limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(840));
+
+        let quota = rate_limiter.quota();
+        assert_eq!((quota.short_usage, quota.daily_usage), (1, 9));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_fails_when_the_wait_is_too_long() {
+        let rate_limiter = paused_limiter(Default::default());
+        rate_limiter.update_from_response(&response(
+            200,
+            &[
+                ("X-RateLimit-Limit", "100,1000"),
+                ("X-RateLimit-Usage", "0,990"),
+            ],
+        ));
+
+        assert!(matches!(
+            rate_limiter.acquire().await,
+            Err(StravaApiError::RateLimited {
+                retry_after: Some(wait_secs)
+            }) if wait_secs == (DAILY_WINDOW_SECS - 60) as u64
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, time::Duration};
+
+use rand::Rng;
+
+use crate::{
+    config::{Config, EndpointRetryConfig, PipelineConfig},
+    strava::error::StravaApiError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Athlete,
+    Activity,
+    ActivityTelemetry,
+    Segment,
+    SegmentTelemetry,
+    AthleteActivities,
+    OAuthToken,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 7] = [
+        Endpoint::Athlete,
+        Endpoint::Activity,
+        Endpoint::ActivityTelemetry,
+        Endpoint::Segment,
+        Endpoint::SegmentTelemetry,
+        Endpoint::AthleteActivities,
+        Endpoint::OAuthToken,
+    ];
+
+    // Name used in the strava.retry.<endpoint> settings
+    pub fn name(&self) -> &'static str {
+        match self {
+            Endpoint::Athlete => "athlete",
+            Endpoint::Activity => "activity",
+            Endpoint::ActivityTelemetry => "activity_telemetry",
+            Endpoint::Segment => "segment",
+            Endpoint::SegmentTelemetry => "segment_telemetry",
+            Endpoint::AthleteActivities => "athlete_activities",
+            Endpoint::OAuthToken => "oauth_token",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Number of retries after the first attempt, 0 disables retrying
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    // Also the longest Retry-After we are willing to wait for
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl From<&PipelineConfig> for RetryPolicy {
+    fn from(config: &PipelineConfig) -> Self {
+        Self {
+            max_retries: config.max_retries,
+            base_delay: config.retry_base_delay(),
+            max_delay: config.retry_max_delay(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    // Fields set for an endpoint replace the ones of this policy
+    pub fn with_overrides(&self, overrides: &EndpointRetryConfig) -> Self {
+        Self {
+            max_retries: overrides.max_retries.unwrap_or(self.max_retries),
+            base_delay: overrides
+                .base_delay_ms
+                .map_or(self.base_delay, Duration::from_millis),
+            max_delay: overrides
+                .max_delay_secs
+                .map_or(self.max_delay, Duration::from_secs),
+        }
+    }
+
+    // Returns how long to wait before retrying after the failed 'attempt' (0 based)
+    // or None if the error should be reported to the caller
+    pub fn delay_for(&self, attempt: u32, err: &StravaApiError) -> Option<Duration> {
+        if attempt >= self.max_retries || !err.is_transient() {
+            return None;
+        }
+
+        // Full jitter: random delay up to the exponential backoff ceiling
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jittered =
+            Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64));
+
+        if let StravaApiError::RateLimited {
+            retry_after: Some(retry_after),
+        } = err
+        {
+            let retry_after = Duration::from_secs(*retry_after);
+
+            if retry_after > self.max_delay {
+                return None;
+            }
+
+            return Some(retry_after.max(jittered));
+        }
+
+        Some(jittered)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetryPolicies {
+    default: RetryPolicy,
+    per_endpoint: HashMap<Endpoint, RetryPolicy>,
+}
+
+impl RetryPolicies {
+    pub fn new(default: RetryPolicy) -> Self {
+        Self {
+            default,
+            per_endpoint: HashMap::new(),
+        }
+    }
+
+    pub fn with_endpoint(mut self, endpoint: Endpoint, policy: RetryPolicy) -> Self {
+        self.per_endpoint.insert(endpoint, policy);
+        self
+    }
+
+    pub fn for_endpoint(&self, endpoint: Endpoint) -> &RetryPolicy {
+        self.per_endpoint.get(&endpoint).unwrap_or(&self.default)
+    }
+}
+
+// Pipeline settings for every endpoint, the strava.retry.<endpoint> ones on top
+impl From<&Config> for RetryPolicies {
+    fn from(config: &Config) -> Self {
+        let default = RetryPolicy::from(&config.pipeline);
+        let mut policies = RetryPolicies::new(default.clone());
+
+        for endpoint in Endpoint::ALL {
+            if let Some(overrides) = config.strava.retry.get(endpoint.name()) {
+                policies = policies.with_endpoint(endpoint, default.with_overrides(overrides));
+            }
+        }
+
+        policies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigError;
+
+    #[test]
+    fn endpoint_settings_replace_the_pipeline_ones() {
+        let mut config = Config::default();
+        config.set("pipeline.max_retries", "2").unwrap();
+        config
+            .set("strava.retry.activity.max_retries", "5")
+            .unwrap();
+        config
+            .set("strava.retry.activity.base_delay_ms", "0")
+            .unwrap();
+
+        let policies = RetryPolicies::from(&config);
+
+        let activity = policies.for_endpoint(Endpoint::Activity);
+        assert_eq!(activity.max_retries, 5);
+        assert_eq!(activity.base_delay, Duration::ZERO);
+        assert_eq!(activity.max_delay, config.pipeline.retry_max_delay());
+
+        assert_eq!(policies.for_endpoint(Endpoint::Segment).max_retries, 2);
+    }
+
+    #[test]
+    fn endpoint_settings_are_read_from_the_file() {
+        let path = std::env::temp_dir().join(format!("gc_retry_{}.toml", std::process::id()));
+        std::fs::write(&path, "[strava.retry.oauth_token]\nmax_retries = 7\n").unwrap();
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let policies = RetryPolicies::from(&config);
+        assert_eq!(policies.for_endpoint(Endpoint::OAuthToken).max_retries, 7);
+
+        std::fs::write(&path, "[strava.retry.oauth]\nmax_retries = 7\n").unwrap();
+        let result = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(result, Err(ConfigError::UnknownSetting(setting)) if setting == "strava.retry.oauth.max_retries")
+        );
+    }
+}
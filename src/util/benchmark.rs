@@ -46,14 +46,10 @@ impl Display for Benchmark {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let duration = self.time.elapsed();
         
-        if duration.as_secs() <= 0 {
-            write!(f, "{}ms", duration.as_millis())
+        if duration.as_secs() > 60 {
+            write!(f, "{:0>2}:{:0>2}min", duration.as_secs() / 60, duration.as_secs() % 60)
         } else {
-            if duration.as_secs() > 60 {
-                write!(f, "{:0>2}:{:0>2}min", duration.as_secs() / 60, duration.as_secs() % 60)
-            } else {
-                write!(f, "{}ms", duration.as_millis())
-            }
+            write!(f, "{}ms", duration.as_millis())
         }
     }
 }
\ No newline at end of file
@@ -20,7 +20,7 @@ pub struct Facilities {
 
 impl Facilities {
     pub fn strava_db(&self) -> Arc<StravaDB> {
-        Arc::clone(&self.strava_db.as_ref().unwrap())
+        Arc::clone(self.strava_db.as_ref().unwrap())
     }
 
     pub fn gc_db(&self) -> Arc<GCDB> {
@@ -31,11 +31,17 @@ impl Facilities {
         Arc::clone(self.strava_api.as_ref().unwrap())
     }
 
+    // For the work which can be done with or without Strava
+    pub fn optional_strava_api(&self) -> Option<Arc<StravaApi>> {
+        self.strava_api.clone()
+    }
+
+    // Dependencies which were not provided stay missing
     pub fn clone(&self) -> Facilities {
         Self {
-            strava_api: Some(self.strava_api()),
-            strava_db: Some(self.strava_db()),
-            gc_db: Some(self.gc_db()),
+            strava_api: self.strava_api.clone(),
+            strava_db: self.strava_db.clone(),
+            gc_db: self.gc_db.clone(),
         }
     }
 
@@ -70,8 +76,7 @@ impl DependenciesBuilder {
                 strava_api: None,
                 strava_db: None,
                 gc_db: None,
-            }
-            .into(),
+            },
         }
     }
 
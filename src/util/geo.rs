@@ -22,7 +22,7 @@ impl GeoUtils {
         dist = dist.acos();
         dist = GeoUtils::rad2deg(dist);
         dist = dist * 60.0 * 1.1515;
-        dist = dist * 1.609344;
+        dist *= 1.609344;
 
         dist
     }
@@ -35,8 +35,8 @@ impl GeoUtils {
         rad * 180.0 / PI as f64
     }
 
-    pub fn get_bounding_box(polyline: &String) -> (Coord, Coord) {
-        let line_string = polyline::decode_polyline(&polyline, 5).unwrap();
+    pub fn get_bounding_box(polyline: &str) -> (Coord, Coord) {
+        let line_string = polyline::decode_polyline(polyline, 5).unwrap();
 
         let mut min_lat: f64 = 180.0;
         let mut min_long: f64 = 180.0;
@@ -61,14 +61,14 @@ impl GeoUtils {
         Coord::from(((left_b.x + right_top.x) / 2., (left_b.y + right_top.y) / 2.))
     }
 
-    pub fn get_coords_from_poly(polyline: &String) -> Vec<Coord> {
-        let line_string = polyline::decode_polyline(&polyline, 5).unwrap();
+    pub fn get_coords_from_poly(polyline: &str) -> Vec<Coord> {
+        let line_string = polyline::decode_polyline(polyline, 5).unwrap();
 
-        line_string.coords().into_iter().cloned().collect()
+        line_string.coords().cloned().collect()
     }
 
     // Returns a list of indexes j which point to the indexes closest to telemetry[i]
-    pub fn create_polyline_mapping_table(polyline: &String, telemetry_latlngs: &Vec<LatLng>) -> Vec<usize> {
+    pub fn create_polyline_mapping_table(polyline: &str, telemetry_latlngs: &[LatLng]) -> Vec<usize> {
         // Equality operator between a Coord and a Latlng with a slight tolerance
         let coord_eq_latlng = |coord: &Coord, latlng: &LatLng| -> bool {
             if (coord.x - latlng[1] as f64).abs() <= 0.00005
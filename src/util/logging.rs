@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::RwLock};
 
-pub(crate) static mut LOGGER_CONFIG: once_cell::sync::Lazy<LoggingConfig> =
-    once_cell::sync::Lazy::new(|| LoggingConfig::default());
+// Read by every log line from any thread, written only when the settings change
+pub(crate) static LOGGER_CONFIG: once_cell::sync::Lazy<RwLock<LoggingConfig>> =
+    once_cell::sync::Lazy::new(Default::default);
 
 #[derive(Copy, Clone, PartialEq, PartialOrd)]
 pub enum LogLevel {
@@ -12,12 +13,12 @@ pub enum LogLevel {
 #[macro_export]
 macro_rules! logln {
     ($fmt:literal) => {
-        if crate::logging::is_enabled(Self::CC) {
+        if $crate::logging::is_enabled(Self::CC) {
             println!("[{}:{}] {}", file!(), line!(), $fmt);
         }
     };
     ($fmt:literal, $($arg:tt)*) => {
-        if crate::logging::is_enabled(Self::CC) {
+        if $crate::logging::is_enabled(Self::CC) {
             print!("[{}:{}] ", file!(), line!());
             println!($fmt, $($arg)*);
         }
@@ -27,13 +28,13 @@ macro_rules! logln {
 #[macro_export]
 macro_rules! logsl {
     ($fmt:literal) => {
-        if crate::logging::is_enabled(Self::CC) {
+        if $crate::logging::is_enabled(Self::CC) {
             print!("\r[{}:{}] {}", file!(), line!(), $fmt);            
             std::io::Write::flush(&mut std::io::stdout()).unwrap();
         }
     };
     ($fmt:literal, $($arg:tt)*) => {
-        if crate::logging::is_enabled(Self::CC) {                        
+        if $crate::logging::is_enabled(Self::CC) {                        
             print!("\r[{}:{}] ", file!(), line!());
             print!($fmt, $($arg)*);        
             
@@ -45,12 +46,12 @@ macro_rules! logsl {
 #[macro_export]
 macro_rules! logvbln {
     ($fmt:literal) => {
-        if crate::logging::is_enabled(Self::CC) && crate::logging::is_at_level(Self::CC, crate::logging::LogLevel::VERBOSE) {
+        if $crate::logging::is_enabled(Self::CC) && $crate::logging::is_at_level(Self::CC, $crate::logging::LogLevel::VERBOSE) {
             println!("[{}:{}] {}", file!(), line!(), $fmt);
         }
     };
     ($fmt:literal, $($arg:tt)*) => {
-        if crate::logging::is_enabled(Self::CC) && crate::logging::is_at_level(Self::CC, crate::logging::LogLevel::VERBOSE) {
+        if $crate::logging::is_enabled(Self::CC) && $crate::logging::is_at_level(Self::CC, $crate::logging::LogLevel::VERBOSE) {
             print!("[{}:{}] ", file!(), line!());
             println!($fmt, $($arg)*);
         }
@@ -60,13 +61,13 @@ macro_rules! logvbln {
 #[macro_export]
 macro_rules! logvbsl {
     ($fmt:literal) => {
-        if crate::logging::is_enabled(Self::CC) && crate::logging::is_at_level(Self::CC, crate::logging::LogLevel::VERBOSE) {
+        if $crate::logging::is_enabled(Self::CC) && $crate::logging::is_at_level(Self::CC, $crate::logging::LogLevel::VERBOSE) {
             print!("[{}:{}] {}", file!(), line!(), $fmt);
             std::io::Write::flush(&mut std::io::stdout()).unwrap();
         }
     };
     ($fmt:literal, $($arg:tt)*) => {
-        if crate::logging::is_enabled(Self::CC) && crate::logging::is_at_level(Self::CC, crate::logging::LogLevel::VERBOSE) {
+        if $crate::logging::is_enabled(Self::CC) && $crate::logging::is_at_level(Self::CC, $crate::logging::LogLevel::VERBOSE) {
             print!("\r[{}:{}] ", file!(), line!());
             print!($fmt, $($arg)*);        
             std::io::Write::flush(&mut std::io::stdout()).unwrap();
@@ -75,33 +76,31 @@ macro_rules! logvbsl {
 }
 
 pub fn is_enabled(cc: &'static str) -> bool {
-    unsafe { LOGGER_CONFIG.cc_enabled(cc) }
+    LOGGER_CONFIG.read().unwrap().cc_enabled(cc)
 }
 
 pub fn is_at_level(cc: &'static str, level: LogLevel) -> bool {
-    unsafe { LOGGER_CONFIG.cc_at_level(cc, level) }
+    LOGGER_CONFIG.read().unwrap().cc_at_level(cc, level)
 }
 
 pub fn disable_cc(cc: &'static str) {
-    unsafe { LOGGER_CONFIG.disable_cc(cc) };
+    LOGGER_CONFIG.write().unwrap().disable_cc(cc);
 }
 
 pub fn enable_cc(cc: &'static str, level: LogLevel) {
-    unsafe { LOGGER_CONFIG.enable_cc(cc, level) };
+    LOGGER_CONFIG.write().unwrap().enable_cc(cc, level);
 }
 
 pub fn set_global_logging(enabled: bool) {
     if enabled {
-        unsafe { LOGGER_CONFIG.enable_global_tracing() };
+        LOGGER_CONFIG.write().unwrap().enable_global_tracing();
     } else {
-        unsafe { LOGGER_CONFIG.disable_global_tracing() };
+        LOGGER_CONFIG.write().unwrap().disable_global_tracing();
     }
 }
 
 pub fn set_global_level(level: LogLevel) {
-    unsafe {
-        LOGGER_CONFIG.set_global_level(level);
-    }
+    LOGGER_CONFIG.write().unwrap().set_global_level(level);
 }
 
 pub trait SettingsChangedSubscriber {